@@ -0,0 +1,40 @@
+// Measures how long building a graph with many small operations takes.
+//
+// Compare the default thread safe contexts with the `single_threaded` feature:
+//
+//     cargo bench --bench graph_build -- --save-baseline threaded
+//     cargo bench --bench graph_build --features single_threaded -- --baseline threaded
+
+use anyhow::Result;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rusty_ggml::prelude::*;
+
+const ELEMENTS: usize = 8;
+// Each step creates 3 tensors, this stays below GGML's graph node limit.
+const STEPS: usize = 1000;
+
+fn build_graph() -> Result<GGraph> {
+    let ctx = GContextBuilder::new()
+        .mem_size(4 * 1024 * 1024)
+        .no_alloc(true)
+        .build()?;
+    let x = ctx.tensor(GType::F32, [ELEMENTS])?;
+    let w = ctx.tensor(GType::F32, [ELEMENTS])?;
+    let mut t = x.clone();
+    for _ in 0..STEPS {
+        t = (&t * &w).sqr() + &x;
+    }
+    let mut graph = GGraph::new(1);
+    graph.build_forward_expand(&t)?;
+    Ok(graph)
+}
+
+fn bench_graph_build(c: &mut Criterion) {
+    c.bench_function("build_graph", |b| {
+        b.iter(|| black_box(build_graph().expect("Building graph failed")))
+    });
+}
+
+criterion_group!(benches, bench_graph_build);
+criterion_main!(benches);
@@ -1,30 +1,50 @@
 use std::{
+    backtrace::Backtrace,
+    collections::{HashMap, HashSet},
     ffi::c_void,
-    ops,
+    fmt, ops,
     ptr::NonNull,
-    sync::{
-        atomic::{self, AtomicBool},
-        Arc, Mutex, MutexGuard,
-    },
+    sync::{Arc, OnceLock},
 };
 
 use anyhow::{anyhow, bail, ensure, Result};
+use num_traits::FromPrimitive;
 use thiserror::Error;
 
 use ggml_sys_bleedingedge as gg;
 
-use crate::{dims::*, gtensor::GTensor, util::GType, validation::*};
+use crate::{
+    dims::*,
+    galloc::*,
+    gtensor::{GAnyTensor, GTensor, GTensorError, GTensorMetadata},
+    sync::{Flag, Lock, LockGuard, Shared},
+    util::{GOp, GType, GUnaryOp},
+    validation::*,
+};
 
 #[derive(Debug, Error, Clone)]
 pub enum GContextError {
     #[error("Attempt to set invalid scratch buffer id {0}")]
     InvalidScratchBufferId(usize),
 
+    #[error("Scratch buffer {0} is still in use by a live tensor")]
+    ScratchBufferInUse(usize),
+
+    #[error("Attempt to use invalid mapped region id {0}")]
+    InvalidMappedRegionId(usize),
+
+    #[error("Mapped region {region} is too small: need {needed} bytes, have {len}")]
+    MappedRegionTooSmall {
+        region: usize,
+        needed: usize,
+        len: usize,
+    },
+
     #[error("Failed to lock context mutex")]
     MutexFailure,
 
     #[error("Context is deceased: {0}")]
-    DeadContext(Arc<anyhow::Error>),
+    DeadContext(Arc<GFailure>),
 
     #[error("Unknown error (likely mutex acquisition failure)")]
     Unknown,
@@ -40,31 +60,120 @@ pub enum GContextError {
     #[error("Attempt to access data in or compute with a no_alloc context")]
     NoAlloc,
 
+    #[error("Memory buffer must be aligned to {0} bytes")]
+    MisalignedMemBuffer(usize),
+
+    #[error("Memory buffer too small: got {got} bytes, need {need}")]
+    MemBufferTooSmall { got: usize, need: usize },
+
+    #[error("Context does not have a graph allocator")]
+    NoGraphAllocator,
+
+    #[error("No tensor named {0:?} in context")]
+    TensorNotFound(String),
+
+    #[error("Tensor was invalidated by rewinding the context")]
+    InvalidatedTensor,
+
+    #[error("Invalid context mark: {0}")]
+    InvalidMark(&'static str),
+
+    #[error("Context is frozen and can't be modified")]
+    Frozen,
+
+    #[error("Only tensors that aren't the result of an operation can be imported, got {0}")]
+    ImportNotLeaf(GOp),
+
+    #[error("Context imports tensors from this one, importing from it would leak both")]
+    CyclicImport,
+
+    #[error("Graph would have {nodes} nodes and {leafs} leafs, more than the maximum of {max}")]
+    GraphOverflow {
+        max: usize,
+        nodes: usize,
+        leafs: usize,
+    },
+
     #[error("General error: {0}")]
     General(Arc<anyhow::Error>),
 }
 
 pub(crate) struct IContext {
-    // Pointer to the GGML context.
+    // Pointer to the GGML context. For auto-growing contexts this is the
+    // newest arena, which new tensors are created in.
     pub(crate) gctx: NonNull<gg::ggml_context>,
 
+    // Full arenas of an auto-growing context, oldest first.
+    pub(crate) old_arenas: Vec<GArena>,
+
+    // Arena offset the current arena starts at. Offsets in the arenas are
+    // numbered as if they were one buffer, oldest first.
+    pub(crate) arena_base: usize,
+
+    // Set if a new arena is added when the current one runs out of memory.
+    pub(crate) auto_grow: bool,
+
+    // Total context memory of all arenas. When an arena is replaced, its
+    // remaining memory is counted as used.
     pub(crate) context_memory: usize,
     // Amount of context memory currently used.
     pub(crate) context_used: usize,
 
-    // List of scratch buffers. Only dropped when the `IContext` is
-    // finally freed.
-    pub(crate) scratch_buffers: Vec<ScratchBuffer>,
+    // List of scratch buffers, indexed by id. Removed buffers leave
+    // a `None` behind so the other ids stay valid.
+    pub(crate) scratch_buffers: Vec<Option<ScratchBuffer>>,
 
     // The current scratch buffer if set.
     pub(crate) current_scratch_buffer: Option<usize>,
 
+    // List of mapped regions that tensor data may point into. Only dropped
+    // when the `IContext` is finally freed.
+    pub(crate) mapped_regions: Vec<MappedRegion>,
+
+    // Data of tensors from other contexts that tensors in this one are views of.
+    // Holding these keeps the other contexts alive.
+    pub(crate) imports: Vec<ImportedData>,
+
+    // Every memory request applied with `update_used_memory`, in order.
+    pub(crate) allocation_log: Vec<GAllocationRecord>,
+
+    // Each rewind starts a new generation. For every earlier generation this holds
+    // the lowest arena offset rewound to since then: Tensors created in that
+    // generation at or past the offset no longer exist.
+    pub(crate) rewind_limits: Vec<usize>,
+
+    // End of the last GGML object that belongs to a successfully created tensor.
+    // Objects past this were left behind by a failed operation.
+    pub(crate) arena_committed: usize,
+
+    // Graph allocator that places tensor data if set.
+    pub(crate) graph_allocator: Option<GGraphAllocator>,
+
+    // Set when the context is only being used to measure memory requirements.
+    // See `GContext::measure`.
+    pub(crate) measuring: bool,
+
+    // Scratch buffer sizes from a previous measurement. Buffers registered
+    // with a smaller size are enlarged to match.
+    pub(crate) scratch_size_hints: Vec<usize>,
+
+    // Caller-supplied memory used by the GGML context if set. Since fields
+    // are dropped after `Drop::drop` runs, this is freed after the GGML context.
+    #[allow(dead_code)]
+    pub(crate) mem_buffer: Option<Box<dyn ops::DerefMut<Target = [u8]> + Send>>,
+
     // Populated if an error occurred during some previous
     // operation.
-    pub(crate) failed: Option<Arc<anyhow::Error>>,
+    pub(crate) failed: Option<Arc<GFailure>>,
+
+    // The most recent failure of a fallible operation. The context is still
+    // usable, but this will become the `failed` value if the failure ends
+    // up killing it.
+    pub(crate) last_failure: Option<Arc<GFailure>>,
 }
 
-// FIXME: YOLO? It's an internal struct and only lives in an Arc.
+// It's an internal struct that only lives in an `Arc<Mutex>`. It's only accessed with
+// the mutex held, or without modifying it once the context is frozen.
 unsafe impl Send for IContext {}
 
 impl Drop for IContext {
@@ -72,9 +181,21 @@ impl Drop for IContext {
     // when the very last instance of the `Arc` is dropped.
     fn drop(&mut self) {
         unsafe { gg::ggml_free(self.gctx.as_ptr()) }
+        self.old_arenas
+            .iter()
+            .for_each(|arena| unsafe { gg::ggml_free(arena.gctx.as_ptr()) })
     }
 }
 
+// An arena of an auto-growing context that was replaced by a larger one.
+pub(crate) struct GArena {
+    gctx: NonNull<gg::ggml_context>,
+    // Arena offset the arena starts at.
+    base: usize,
+    // Context memory added when the arena replaced the previous one.
+    memory: usize,
+}
+
 impl ops::Deref for IContext {
     type Target = NonNull<gg::ggml_context>;
 
@@ -94,7 +215,16 @@ impl IContext {
         self.gctx.as_ptr()
     }
 
-    pub(crate) fn update_used_memory(&mut self, mr: &GMemoryRequest) -> Result<()> {
+    /// Applies a memory request for a newly created tensor and records it
+    /// in the allocation log.
+    ///
+    /// # Safety
+    /// The tensor pointer must be valid.
+    pub(crate) unsafe fn update_used_memory(
+        &mut self,
+        mr: &GMemoryRequest,
+        tptr: *const gg::ggml_tensor,
+    ) -> Result<()> {
         let mut mr = *mr;
         ensure!(
             mr.required_scratch == 0 || self.current_scratch_buffer == mr.current_scratch_buffer,
@@ -103,7 +233,9 @@ impl IContext {
             mr.current_scratch_buffer,
         );
         match mr.reqtype {
-            GMemoryRequestType::Tensor { .. } => (),
+            GMemoryRequestType::Tensor { .. }
+            | GMemoryRequestType::MappedTensor { .. }
+            | GMemoryRequestType::View { .. } => (),
             wut => bail!("Request type {wut:?} currently not implemented in IContext::use_memory"),
         }
         let new_ctx_used = self.context_used + mr.required_ctx;
@@ -112,21 +244,335 @@ impl IContext {
             bail!(GContextError::InsufficientMemory(mr));
         }
         if let Some(bufid) = &mr.current_scratch_buffer {
-            let buf = &mut self.scratch_buffers[*bufid];
+            let measuring = self.measuring;
+            let buf = self.scratch_buffer_mut(*bufid)?;
             let new_scratch_used = buf.used + mr.required_scratch;
-            if new_scratch_used > buf.buf.len() {
-                println!(
-                    "MEM(scratch): {new_scratch_used} > {} -- {mr:?}",
-                    buf.buf.len()
-                );
+            if !measuring && new_scratch_used > buf.buf.len() {
                 mr.fits = false;
                 bail!(GContextError::InsufficientMemory(mr));
             }
             buf.used = new_scratch_used;
+            buf.peak = buf.peak.max(new_scratch_used);
         }
         self.context_used = new_ctx_used;
+
+        let tref = &*tptr;
+        self.allocation_log.push(GAllocationRecord {
+            op: GOp::from_u32(tref.op).ok_or_else(|| anyhow!("Bad tensor op"))?,
+            typ: GType::from_u32(tref.type_).ok_or_else(|| anyhow!("Bad tensor type"))?,
+            ggml_ne: tref.ne.map(|ne| ne as usize),
+            mapped: matches!(mr.reqtype, GMemoryRequestType::MappedTensor { .. }),
+            ctx_bytes: mr.required_ctx,
+            scratch_bytes: mr.required_scratch,
+            scratch_buffer: mr
+                .current_scratch_buffer
+                .filter(|_| mr.required_scratch > 0),
+            graph_bytes: mr.required_graph,
+        });
+        self.arena_committed = self.arena_used();
+        Ok(())
+    }
+
+    /// Returns the arena offset where the next GGML object will be placed.
+    pub(crate) fn arena_used(&self) -> usize {
+        self.arena_base + unsafe { gg::ggml_used_mem(self.gptr()) }
+    }
+
+    /// Returns the GGML contexts of all arenas, oldest first.
+    pub(crate) fn arenas(&self) -> impl Iterator<Item = NonNull<gg::ggml_context>> + '_ {
+        self.old_arenas
+            .iter()
+            .map(|arena| arena.gctx)
+            .chain(std::iter::once(self.gctx))
+    }
+
+    /// Returns the memory GGML is using in all arenas.
+    pub(crate) fn ggml_used(&self) -> usize {
+        self.arenas()
+            .map(|gctx| unsafe { gg::ggml_used_mem(gctx.as_ptr()) })
+            .sum()
+    }
+
+    /// Makes a new arena with at least the specified size current.
+    /// It will be at least twice as large as the previous arena.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held.
+    pub(crate) unsafe fn grow(&mut self, min_size: usize) -> Result<()> {
+        let old_size = gg::ggml_get_mem_size(self.gptr());
+        let mem_size = (old_size * 2).max(min_size + gg::GGML_MEM_ALIGN as usize);
+        let ptr = gg::ggml_init(gg::ggml_init_params {
+            mem_size,
+            mem_buffer: std::ptr::null_mut(),
+            no_alloc: gg::ggml_get_no_alloc(self.gptr()),
+        });
+        let gctx = NonNull::new(ptr).ok_or_else(|| anyhow!("GGML init failed"))?;
+        self.old_arenas.push(GArena {
+            gctx: self.gctx,
+            base: self.arena_base,
+            memory: self.context_memory,
+        });
+        self.gctx = gctx;
+        self.arena_base += old_size;
+        self.context_used = self.context_memory;
+        self.context_memory += mem_size;
+        self.sync_scratch();
+        Ok(())
+    }
+
+    /// Returns the amount of context memory available.
+    pub(crate) fn available_ctx(&self) -> usize {
+        if !self.measuring {
+            return self.context_memory - self.context_used;
+        }
+        // When measuring, GGML only allocates objects so the only real limit is
+        // having space for another one in the GGML arena.
+        let (used, size) = unsafe {
+            (
+                gg::ggml_used_mem(self.gptr()),
+                gg::ggml_get_mem_size(self.gptr()),
+            )
+        };
+        let needed = gg::GGML_OBJECT_SIZE + gg::GGML_TENSOR_SIZE + gg::GGML_MEM_ALIGN as usize;
+        if used + needed > size {
+            0
+        } else {
+            usize::MAX - self.context_used
+        }
+    }
+
+    /// Returns the amount of memory available in the specified scratch buffer.
+    pub(crate) fn available_scratch(&self, bufid: usize) -> usize {
+        match self.scratch_buffer(bufid) {
+            Ok(sbuf) if self.measuring => usize::MAX - sbuf.used,
+            Ok(sbuf) => sbuf.buf.len().saturating_sub(sbuf.used),
+            Err(_) => 0,
+        }
+    }
+
+    pub(crate) fn scratch_buffer(&self, bufid: usize) -> Result<&ScratchBuffer> {
+        Ok(self
+            .scratch_buffers
+            .get(bufid)
+            .and_then(|sbuf| sbuf.as_ref())
+            .ok_or(GContextError::InvalidScratchBufferId(bufid))?)
+    }
+
+    pub(crate) fn scratch_buffer_mut(&mut self, bufid: usize) -> Result<&mut ScratchBuffer> {
+        Ok(self
+            .scratch_buffers
+            .get_mut(bufid)
+            .and_then(|sbuf| sbuf.as_mut())
+            .ok_or(GContextError::InvalidScratchBufferId(bufid))?)
+    }
+
+    /// Fails if any live tensor has data in the specified scratch buffer.
+    pub(crate) fn ensure_scratch_unused(&self, bufid: usize) -> Result<()> {
+        let sbuf = self.scratch_buffer(bufid)?;
+        ensure!(
+            Arc::strong_count(&sbuf.live) == 1,
+            GContextError::ScratchBufferInUse(bufid)
+        );
+        Ok(())
+    }
+
+    /// Returns a reference that tracks the tensor as live if its data
+    /// points into one of the scratch buffers.
+    ///
+    /// # Safety
+    /// The tensor pointer must be valid.
+    pub(crate) unsafe fn scratch_ref(&self, tptr: *const gg::ggml_tensor) -> Option<Arc<()>> {
+        let data = (*tptr).data as *const u8;
+        self.scratch_buffers
+            .iter()
+            .flatten()
+            .find(|sbuf| sbuf.buf.as_ptr_range().contains(&data))
+            .map(|sbuf| sbuf.live.clone())
+    }
+
+    /// Point GGML at the current scratch buffer (if any). New tensors are
+    /// placed after the memory already used in the buffer.
+    pub(crate) fn sync_scratch(&mut self) {
+        let scratch = match self.current_scratch_buffer {
+            Some(bufid) => {
+                let sbuf = self.scratch_buffers[bufid]
+                    .as_mut()
+                    .expect("Impossible: Current scratch buffer was removed");
+                gg::ggml_scratch {
+                    // When measuring `used` can exceed the buffer size.
+                    offs: sbuf.used.min(sbuf.buf.len()),
+                    size: sbuf.buf.len(),
+                    data: sbuf.buf.as_mut_ptr() as *mut c_void,
+                }
+            }
+            None => gg::ggml_scratch {
+                offs: 0,
+                size: 0,
+                data: std::ptr::null_mut(),
+            },
+        };
+        unsafe {
+            gg::ggml_set_scratch(self.gptr(), scratch);
+        }
+    }
+
+    /// Returns the current generation. See [GContext::rewind].
+    pub(crate) fn generation(&self) -> usize {
+        self.rewind_limits.len()
+    }
+
+    /// Returns the offset of a GGML object in the arena, or `usize::MAX` if
+    /// it isn't in any arena.
+    fn arena_offset<T>(&self, ptr: *const T) -> usize {
+        let bases = self
+            .old_arenas
+            .iter()
+            .map(|arena| (arena.gctx, arena.base))
+            .chain(std::iter::once((self.gctx, self.arena_base)));
+        for (gctx, base) in bases {
+            let (buf, size) = unsafe {
+                (
+                    gg::ggml_get_mem_buffer(gctx.as_ptr()) as usize,
+                    gg::ggml_get_mem_size(gctx.as_ptr()),
+                )
+            };
+            if (buf..buf + size).contains(&(ptr as usize)) {
+                return base + (ptr as usize - buf);
+            }
+        }
+        usize::MAX
+    }
+
+    /// Marks the context as failed. This starts a new generation, so any
+    /// tensors handed out while the context is dead can be told apart.
+    pub(crate) fn set_failed(&mut self, failure: Arc<GFailure>) {
+        self.failed = Some(failure);
+        self.rewind_limits.push(self.arena_committed);
+    }
+
+    /// Drops every GGML object past the specified arena offset, which must be
+    /// the end of an object or `0`.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held. The dropped objects must not be used again.
+    unsafe fn truncate_arena(&mut self, arena_used: usize) -> Result<()> {
+        // Arenas added after the offset are freed entirely.
+        while arena_used <= self.arena_base {
+            let Some(arena) = self.old_arenas.pop() else {
+                break;
+            };
+            gg::ggml_free(self.gptr());
+            self.gctx = arena.gctx;
+            self.arena_base = arena.base;
+            self.context_memory = arena.memory;
+        }
+        let arena_used = arena_used - self.arena_base;
+        let layout = &mut *(self.gptr() as *mut GgmlContextLayout);
+        ensure!(
+            layout.mem_buffer == gg::ggml_get_mem_buffer(self.gptr())
+                && layout.mem_size == gg::ggml_get_mem_size(self.gptr()),
+            "Unexpected GGML context layout"
+        );
+        let (mut obj, mut n_objects) = (std::ptr::null_mut(), 0);
+        if arena_used > 0 {
+            obj = layout.objects_begin;
+            n_objects = 1;
+            while (*obj).offs + (*obj).size < arena_used {
+                obj = (*obj).next;
+                n_objects += 1;
+            }
+            (*obj).next = std::ptr::null_mut();
+        } else {
+            layout.objects_begin = std::ptr::null_mut();
+        }
+        layout.objects_end = obj;
+        layout.n_objects = n_objects;
+        Ok(())
+    }
+
+    // Stores the failure of an operation as the last failure and returns the
+    // error the operation should report.
+    pub(crate) fn record_failure(&mut self, trace: GOpTrace, err: anyhow::Error) -> GTensorError {
+        let (err, failure) = match err.downcast::<GTensorError>() {
+            Ok(err) => (err.clone(), Arc::new(GFailure::new(trace, err.into()))),
+            Err(e) => {
+                let failure = Arc::new(GFailure::new(trace, e));
+                (GTensorError::Failed(failure.clone()), failure)
+            }
+        };
+        self.last_failure = Some(failure);
+        err
+    }
+
+    /// Frees any GGML objects left behind by a failed operation.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held.
+    pub(crate) unsafe fn rollback_uncommitted(&mut self) -> Result<()> {
+        if self.arena_used() > self.arena_committed {
+            self.truncate_arena(self.arena_committed)?;
+            self.sync_scratch();
+        }
         Ok(())
     }
+
+    /// Fails if the tensor from the specified generation was invalidated by rewinding.
+    pub(crate) fn ensure_live(
+        &self,
+        tptr: *const gg::ggml_tensor,
+        generation: usize,
+    ) -> Result<()> {
+        if let Some(limit) = self.rewind_limits.get(generation) {
+            ensure!(
+                self.arena_offset(tptr) < *limit,
+                GContextError::InvalidatedTensor
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns every tensor object in the GGML arena in the order they were created.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held.
+    pub(crate) unsafe fn tensor_ptrs(&self) -> Vec<NonNull<gg::ggml_tensor>> {
+        let mut result = vec![];
+        for gctx in self.arenas() {
+            // When no objects exist, the start of the arena is uninitialized.
+            if gg::ggml_used_mem(gctx.as_ptr()) == 0 {
+                continue;
+            }
+            // Objects are a linked list starting at the beginning of the arena
+            // and the object data follows the object header.
+            let base = gg::ggml_get_mem_buffer(gctx.as_ptr()) as *mut u8;
+            let mut obj = base as *const gg::ggml_object;
+            while let Some(objref) = obj.as_ref() {
+                if objref.type_ == gg::ggml_object_type_GGML_OBJECT_TENSOR {
+                    result.push(NonNull::new_unchecked(
+                        base.add(objref.offs) as *mut gg::ggml_tensor
+                    ));
+                }
+                obj = objref.next;
+            }
+        }
+        result
+    }
+
+    /// Returns `true` if the tensor's data points into a mapped region
+    /// or another context.
+    ///
+    /// # Safety
+    /// The tensor pointer must be valid.
+    pub(crate) unsafe fn is_mapped(&self, tptr: *const gg::ggml_tensor) -> bool {
+        let data = (*tptr).data as *const u8;
+        self.mapped_regions
+            .iter()
+            .any(|mreg| mreg.as_slice().as_ptr_range().contains(&data))
+            || self
+                .imports
+                .iter()
+                .any(|imp| imp.data.contains(&(data as usize)))
+    }
 }
 
 #[derive(Clone)]
@@ -143,21 +589,52 @@ pub struct GContext {
     #[allow(dead_code)]
     pub(crate) no_alloc: bool,
 
-    // This atomic is used to mark the context as dead. Ideally we could
+    // This flag is used to mark the context as dead. Ideally we could
     // mark it in the `ictx` field, but one failure condition is failing to
     // acquire the mutex: in that case all we can do is mark the context as
     // dead using this field.
-    pub(crate) dead: Arc<AtomicBool>,
+    pub(crate) dead: Shared<Flag>,
 
     // The real context structure which contains a pointer to the actual
     // GGML context.
-    pub(crate) ictx: Arc<Mutex<IContext>>,
+    pub(crate) ictx: Shared<Lock<IContext>>,
+
+    // Set when the context is frozen. See [GFrozenContext].
+    pub(crate) frozen: Shared<OnceLock<FrozenState>>,
 }
 
+// State of a frozen context. Once a context is frozen its `IContext` is never
+// modified again, so it can be read without holding the mutex.
+pub(crate) struct FrozenState {
+    ictx: FrozenIContext,
+    // Only set for the handles `fail_with_last_failure` returns when an operation
+    // tried to modify the frozen context. Using them reports the failure.
+    failure: OnceLock<Arc<GFailure>>,
+}
+
+// Pointer to the `IContext` of a frozen context.
+struct FrozenIContext(*const IContext);
+
+// Sharing this hands out `&IContext` to several threads without the mutex. That's
+// sound because nothing reachable from it is modified once the context is frozen:
+// 1. `freeze` sets `GContext::frozen` with the mutex held, and every function that
+//    takes the mutex to modify the `IContext` checks it after acquiring the mutex.
+// 2. The GGML context, its memory buffer and the tensor objects in it are only reached
+//    through raw pointers, which are only read. Tensors from frozen contexts are used
+//    in other contexts through views that don't refer to the frozen tensor objects.
+// 3. The `IContext` lives as long as the `GContext` holding the `FrozenState`.
+unsafe impl Send for FrozenIContext {}
+unsafe impl Sync for FrozenIContext {}
+
 /// GGML scratch buffer structure used for temporary data storage.
 pub struct ScratchBuffer {
     pub(crate) buf: Box<[u8]>,
     pub(crate) used: usize,
+    // Highest value `used` reached since the buffer was registered.
+    pub(crate) peak: usize,
+    // Tensors with data in the buffer hold a clone of this, so the buffer
+    // is only in use when the count is greater than one.
+    pub(crate) live: Arc<()>,
 }
 
 impl ScratchBuffer {
@@ -171,16 +648,44 @@ impl ScratchBuffer {
         Self {
             buf: data.into_boxed_slice(),
             used: 0,
+            peak: 0,
+            live: Arc::new(()),
         }
     }
 }
 
+/// Read-only memory that tensor data can point into without being
+/// copied into the context.
+pub(crate) struct MappedRegion {
+    data: Box<dyn AsRef<[u8]> + Send + Sync>,
+}
+
+impl MappedRegion {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        (*self.data).as_ref()
+    }
+}
+
+/// Tensor data owned by another context.
+///
+/// The source context is kept alive by this, so two contexts importing from
+/// each other would never be freed. `import_view` refuses to create such a cycle
+/// between two contexts, but not longer ones.
+pub(crate) struct ImportedData {
+    source: Shared<Lock<IContext>>,
+    data: ops::Range<usize>,
+}
+
 #[derive(Default)]
 /// GGML context builder structure used to build a
 /// [GContext].
 pub struct GContextBuilder {
     mem_size: usize,
     no_alloc: bool,
+    mem_buffer: Option<Box<dyn ops::DerefMut<Target = [u8]> + Send>>,
+    graph_allocator: Option<GGraphAllocator>,
+    scratch_size_hints: Vec<usize>,
+    auto_grow: bool,
 }
 
 // FIXME: We probably should use the typestate pattern in here to make sure
@@ -213,116 +718,668 @@ impl GContextBuilder {
         self
     }
 
+    /// Supply the memory GGML will use for the context instead
+    /// of having GGML allocate it. This can be any owned buffer,
+    /// for example `Box<[u8]>`, `Pin<Box<[u8]>>` or a huge page
+    /// allocation. The buffer is freed when the last clone of the
+    /// [GContext] is dropped.
+    ///
+    /// If the memory size isn't set, the length of the buffer will be used.
+    ///
+    /// **Invariants**
+    /// 1. The buffer must be aligned to `GGML_MEM_ALIGN` (16) bytes.
+    /// 2. The buffer must be at least as large as the memory size.
+    pub fn mem_buffer<B>(mut self, buf: B) -> Self
+    where
+        B: ops::DerefMut<Target = [u8]> + Send + 'static,
+    {
+        self.mem_buffer = Some(Box::new(buf));
+        self
+    }
+
+    /// Place tensor data using the supplied [GGraphAllocator]. The context memory
+    /// will then only be used for GGML objects, so the memory size only needs
+    /// to be large enough for those.
+    ///
+    /// **Note**: Scratch buffers have no effect on contexts with a graph allocator.
+    pub fn graph_allocator(mut self, galloc: GGraphAllocator) -> Self {
+        self.graph_allocator = Some(galloc);
+        self
+    }
+
+    /// When a tensor doesn't fit in the context memory, add another GGML context
+    /// at least twice as large instead of failing with [GContextError::InsufficientMemory].
+    /// The memory size then only sets the size of the first one.
+    ///
+    /// **Note**: Memory left over at the end of a GGML context is counted as used
+    /// once the next one is added.
+    pub fn auto_grow(mut self, auto_grow: bool) -> Self {
+        self.auto_grow = auto_grow;
+        self
+    }
+
     /// Build a GGML context ([GContext]) based on the
     /// builder's configuration.
-    pub fn build(self) -> Result<GContext> {
+    pub fn build(mut self) -> Result<GContext> {
+        let mem_buffer = match self.mem_buffer.as_mut() {
+            Some(buf) => {
+                let align = gg::GGML_MEM_ALIGN as usize;
+                ensure!(
+                    buf.as_ptr().align_offset(align) == 0,
+                    GContextError::MisalignedMemBuffer(align)
+                );
+                if self.mem_size == 0 {
+                    self.mem_size = buf.len();
+                }
+                ensure!(
+                    self.mem_size <= buf.len(),
+                    GContextError::MemBufferTooSmall {
+                        got: buf.len(),
+                        need: self.mem_size
+                    }
+                );
+                buf.as_mut_ptr() as *mut c_void
+            }
+            None => std::ptr::null_mut(),
+        };
+        // GGML itself never allocates data when the graph allocator is used, but
+        // data is only actually accessible if the allocator isn't measuring.
+        let measuring_alloc = self.graph_allocator.as_ref().map(|ga| ga.is_measure());
         let ptr = unsafe {
             gg::ggml_init(gg::ggml_init_params {
                 mem_size: self.mem_size,
-                mem_buffer: std::ptr::null_mut(),
-                no_alloc: self.no_alloc,
+                mem_buffer,
+                no_alloc: self.no_alloc || measuring_alloc.is_some(),
             })
         };
         ensure!(!ptr.is_null(), "GGML init failed");
         Ok(GContext {
             context_size: self.mem_size,
-            no_alloc: self.no_alloc,
+            no_alloc: self.no_alloc || measuring_alloc == Some(true),
             ptrval: ptr as usize,
-            ictx: Arc::new(Mutex::new(IContext {
+            ictx: Shared::new(Lock::new(IContext {
                 gctx: NonNull::new(ptr).unwrap(),
+                old_arenas: vec![],
+                arena_base: 0,
+                auto_grow: self.auto_grow,
                 context_used: 0,
                 context_memory: self.mem_size,
                 scratch_buffers: vec![],
                 current_scratch_buffer: None,
+                mapped_regions: vec![],
+                imports: vec![],
+                allocation_log: vec![],
+                rewind_limits: vec![],
+                arena_committed: 0,
+                graph_allocator: self.graph_allocator,
+                measuring: false,
+                scratch_size_hints: self.scratch_size_hints,
+                mem_buffer: self.mem_buffer,
                 failed: None,
+                last_failure: None,
             })),
-            dead: Arc::new(AtomicBool::new(false)),
+            dead: Shared::new(Flag::new(false)),
+            frozen: Shared::new(OnceLock::new()),
         })
     }
 }
 
-impl GContext {
-    pub(crate) fn with_icontext<OUT, F>(&self, fun: F) -> Result<OUT>
-    where
-        F: FnOnce(&GContext, MutexGuard<IContext>) -> Result<OUT>,
-    {
-        let failed = self.dead.load(atomic::Ordering::SeqCst);
-        let ictx = self
-            .ictx
-            .lock()
-            .map_err(|_e| anyhow!(GContextError::MutexFailure))?;
-        if let Some(e) = ictx.failed.clone() {
-            bail!(GContextError::DeadContext(e));
-        }
-        if failed {
-            bail!(GContextError::Unknown)
-        } else {
-            fun(self, ictx)
+// Mirror of GGML's private `struct ggml_context` in the pinned GGML version.
+// GGML has no API to remove objects, so `GContext::rewind` needs to
+// manipulate the object list directly.
+#[repr(C)]
+struct GgmlContextLayout {
+    mem_size: usize,
+    mem_buffer: *mut c_void,
+    mem_buffer_owned: bool,
+    no_alloc: bool,
+    no_alloc_save: bool,
+    n_objects: std::os::raw::c_int,
+    objects_begin: *mut gg::ggml_object,
+    objects_end: *mut gg::ggml_object,
+    scratch: gg::ggml_scratch,
+    scratch_save: gg::ggml_scratch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A saved point in a [GContext]'s allocations. See [GContext::mark].
+pub struct GContextMark {
+    ptrval: usize,
+    generation: usize,
+    arena_used: usize,
+    context_used: usize,
+    scratch_used: Vec<usize>,
+    galloc_used: usize,
+    log_len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A memory request applied to a [GContext] when a tensor was created.
+pub struct GAllocationRecord {
+    /// The operation that created the tensor.
+    pub op: GOp,
+
+    /// The tensor's type.
+    pub typ: GType,
+
+    /// The tensor's shape in GGML order. See [GTensor::get_ne].
+    pub ggml_ne: [usize; gg::GGML_MAX_DIMS as usize],
+
+    /// `true` if the tensor's data is in a mapped region.
+    pub mapped: bool,
+
+    /// Bytes of context memory used.
+    pub ctx_bytes: usize,
+
+    /// Bytes of scratch buffer memory used.
+    pub scratch_bytes: usize,
+
+    /// The scratch buffer id if scratch memory was used.
+    pub scratch_buffer: Option<usize>,
+
+    /// Bytes the graph allocator is expected to place.
+    pub graph_bytes: usize,
+}
+
+impl GAllocationRecord {
+    /// Returns the context and scratch bytes used.
+    pub fn total_bytes(&self) -> usize {
+        self.ctx_bytes + self.scratch_bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An input to the operation recorded in a [GFailure].
+pub struct GFailureInput {
+    /// The tensor's type.
+    pub typ: GType,
+
+    /// The tensor's shape in GGML order. See [GTensor::get_ne].
+    pub ggml_ne: [usize; gg::GGML_MAX_DIMS as usize],
+}
+
+#[derive(Debug)]
+/// Details about the operation that killed a [GContext]. This is the
+/// payload of [GContextError::DeadContext].
+pub struct GFailure {
+    /// The name of the operation, for example `"add"` or `"mul_mat"`.
+    pub op: &'static str,
+
+    /// The operation's input tensors in argument order, starting with `self`.
+    pub inputs: Vec<GFailureInput>,
+
+    /// The memory request for the result if one was made before the failure.
+    pub memory_request: Option<GMemoryRequest>,
+
+    /// Where the operation was called from.
+    ///
+    /// **Note**: This is only captured when enabled with the `RUST_BACKTRACE`
+    /// or `RUST_LIB_BACKTRACE` environment variables.
+    pub backtrace: Backtrace,
+
+    /// The error the operation failed with.
+    pub error: anyhow::Error,
+}
+
+impl GFailure {
+    pub(crate) fn new(trace: GOpTrace, error: anyhow::Error) -> Self {
+        let memory_request =
+            trace
+                .memory_request
+                .or_else(|| match error.downcast_ref::<GContextError>() {
+                    Some(GContextError::InsufficientMemory(mr)) => Some(*mr),
+                    _ => None,
+                });
+        Self {
+            op: trace.op,
+            inputs: trace.inputs,
+            memory_request,
+            backtrace: Backtrace::capture(),
+            error,
         }
     }
+}
 
-    // FIXME: This logic seems kind of weird. Same problem in `Tensor::with_tensor_infallible`.
-    pub(crate) fn with_icontext_infallible<OUT, F>(&self, fun: F) -> Result<OUT>
-    where
-        F: FnOnce(MutexGuard<IContext>) -> OUT,
-    {
-        let failed = self.dead.load(atomic::Ordering::SeqCst);
-        let mut ctx = self.ictx.lock().map_err(|_e| {
-            self.dead.store(true, atomic::Ordering::SeqCst);
-            GContextError::MutexFailure
-        })?;
-        if let Some(e) = ctx.failed.clone() {
-            bail!(GContextError::DeadContext(e));
+impl fmt::Display for GFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.op)?;
+        for (idx, input) in self.inputs.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:?} {:?}", input.typ, input.ggml_ne)?;
         }
-        // This might look weird but the idea is that we might have failed previously
-        // due to being unable to acquire the mutex. Since we didn't have the mutex,
-        // naturally it was impossible to set the `failed` field inside the `IContext`
-        // structure.
-        // There probably still is a race condition here but it should be very unlikely.
-        if failed {
-            let e = GContextError::Unknown;
-            ctx.failed = Some(Arc::new(anyhow::Error::new(e.clone())));
-            Err(e)?;
+        write!(f, ") failed: {}", self.error)
+    }
+}
+
+// Describes an operation while it runs so a [GFailure] can be built if it fails.
+pub(crate) struct GOpTrace {
+    pub(crate) op: &'static str,
+    pub(crate) inputs: Vec<GFailureInput>,
+    pub(crate) memory_request: Option<GMemoryRequest>,
+}
+
+impl GOpTrace {
+    pub(crate) fn new(op: &'static str, inputs: Vec<GFailureInput>) -> Self {
+        Self {
+            op,
+            inputs,
+            memory_request: None,
         }
-        Ok(fun(ctx))
     }
+}
+
+#[derive(Clone)]
+/// A tensor owned by a [GContext]. See [GContext::tensors].
+///
+/// The tensor's metadata is available through the [GAnyTensor] methods or
+/// by converting it to a [GTensor].
+pub struct GContextTensor {
+    /// The type-erased tensor.
+    pub tensor: GAnyTensor,
 
-    pub(crate) fn delay_failure_with_icontext<OUT, DF, F>(&self, dfun: DF, fun: F) -> OUT
+    /// The tensor's name. Empty if it was never set.
+    pub name: String,
+
+    /// The operation that created the tensor.
+    pub op: GOp,
+
+    /// `true` if the tensor is a view of another tensor's data.
+    pub is_view: bool,
+}
+
+#[derive(Clone)]
+/// A tensor in a [GGraph]. See [GGraph::nodes] and [GGraph::leafs].
+///
+/// Like [GContextTensor], the tensor's metadata is available through the
+/// [GAnyTensor] methods or [Self::metadata].
+pub struct GGraphTensor {
+    /// The type-erased tensor.
+    pub tensor: GAnyTensor,
+
+    /// The tensor's name. GGML names unnamed tensors when they're added to a graph.
+    pub name: String,
+
+    /// The operation that created the tensor.
+    pub op: GOp,
+
+    /// The specific operation when `op` is [GOp::Unary].
+    pub unary_op: Option<GUnaryOp>,
+
+    /// The tensors the operation used as inputs, in order.
+    pub sources: Vec<GAnyTensor>,
+
+    /// `true` if the tensor is a parameter GGML can compute gradients for.
+    pub is_param: bool,
+}
+
+impl GGraphTensor {
+    /// Returns a copy of the metadata associated with the tensor.
+    ///
+    /// **Invariants**
+    /// 1. `DIMS` must match the number of dimensions of the tensor.
+    pub fn metadata<const DIMS: usize>(&self) -> Result<GTensorMetadata<DIMS>>
     where
-        DF: Fn() -> OUT,
-        F: FnOnce(&mut IContext) -> Result<OUT>,
+        Dim<DIMS>: DimValid,
     {
-        self.with_icontext_infallible(|mut ictx| {
-            fun(&mut ictx).unwrap_or_else(|e| {
-                // We have the context mutex but the handler function returned
-                // an error condition. So store the error in the context and mark it as dead.
-                self.dead.store(true, atomic::Ordering::SeqCst);
-                ictx.failed = Some(Arc::new(e));
-                dfun()
-            })
-        })
-        .unwrap_or_else(|_e| {
-            // We couldn't get the context mutex, but we can still mark the context as dead.
-            self.dead.store(true, atomic::Ordering::SeqCst);
-            dfun()
-        })
+        Ok(self.tensor.clone().into_typed::<DIMS>()?.metadata())
     }
+}
 
-    pub fn estimate_tensor_size<const DIMS: usize>(
-        &self,
-        typ: GType,
-        shape: [usize; DIMS],
-    ) -> Result<GMemoryRequest> {
-        self.with_icontext(|ctx, ictx| {
-            Ok(GMemoryRequest::estimate_tensor_request_ictx(
-                ctx, &ictx, typ, shape,
-            ))
-        })
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Usage of a scratch buffer in a [GMemoryReport].
+pub struct GScratchUsage {
+    /// The scratch buffer id.
+    pub id: usize,
 
-    /// Create a new tensor with the specified [type](GType) and shape.
-    ///
-    /// This uses const generics to determine the new tensor's dimensions. The tensor dimensions
+    /// The buffer size in bytes.
+    pub size: usize,
+
+    /// Bytes currently used.
+    pub used: usize,
+
+    /// Highest number of bytes used at any point.
+    pub peak: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Summary of memory use in a [GContext]. See [GContext::memory_report].
+pub struct GMemoryReport {
+    /// The context memory size in bytes.
+    pub context_size: usize,
+
+    /// Bytes of context memory used according to the requests applied.
+    pub context_used: usize,
+
+    /// Bytes of context memory GGML reports as used.
+    pub ggml_used: usize,
+
+    /// Usage of each scratch buffer that hasn't been removed.
+    pub scratch: Vec<GScratchUsage>,
+
+    /// Number of tensors created.
+    pub tensors: usize,
+
+    /// Total bytes the graph allocator is expected to place.
+    pub graph_bytes: usize,
+
+    /// The tensors that used the most context and scratch memory,
+    /// largest first.
+    pub top_consumers: Vec<GAllocationRecord>,
+}
+
+impl fmt::Display for GMemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "context: {} / {} bytes used (GGML: {}), {} tensors",
+            self.context_used, self.context_size, self.ggml_used, self.tensors
+        )?;
+        if self.graph_bytes > 0 {
+            writeln!(f, "graph allocator: {} bytes", self.graph_bytes)?;
+        }
+        for sb in &self.scratch {
+            writeln!(
+                f,
+                "scratch {}: {} / {} bytes used, peak {}",
+                sb.id, sb.used, sb.size, sb.peak
+            )?;
+        }
+        for rec in &self.top_consumers {
+            writeln!(
+                f,
+                "{:>12} bytes: {} {:?} {:?}{}",
+                rec.total_bytes(),
+                rec.op,
+                rec.typ,
+                rec.ggml_ne,
+                rec.scratch_buffer
+                    .map_or(String::new(), |id| format!(" (scratch {id})"))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Memory requirements captured by [GContext::measure].
+pub struct GMeasurement {
+    /// The required context size in bytes.
+    pub context_size: usize,
+
+    /// The required size in bytes of each scratch buffer, indexed
+    /// by scratch buffer id.
+    pub scratch_sizes: Vec<usize>,
+}
+
+impl GMeasurement {
+    /// Returns a [GContextBuilder] configured with the measured context size.
+    /// Scratch buffers registered with contexts it builds will be enlarged to
+    /// the measured sizes if necessary.
+    pub fn context_builder(&self) -> GContextBuilder {
+        GContextBuilder {
+            scratch_size_hints: self.scratch_sizes.clone(),
+            ..GContextBuilder::new().mem_size(self.context_size)
+        }
+    }
+
+    /// Build a [GContext] with the measured size.
+    pub fn build(&self) -> Result<GContext> {
+        self.context_builder().build()
+    }
+}
+
+impl GContext {
+    /// Size of the GGML arena used by the measuring context in [Self::measure].
+    /// Since the context is `no_alloc`, only GGML objects use this memory.
+    pub const MEASURE_MEM_SIZE: usize = 16 * 1024 * 1024;
+
+    /// Run the supplied function with a `no_alloc` context and measure
+    /// how much context and scratch memory the tensors it creates would
+    /// require. See [GContextBuilder::no_alloc].
+    ///
+    /// Scratch buffers registered by the function don't need to be large
+    /// enough: The amount of each one that would be used is measured instead.
+    /// This is the peak usage, so resetting a buffer partway through doesn't
+    /// make it look smaller than it needs to be.
+    ///
+    /// Returns the measurement along with the output of the function.
+    pub fn measure<OUT, F>(fun: F) -> Result<(GMeasurement, OUT)>
+    where
+        F: FnOnce(&mut GContext) -> Result<OUT>,
+    {
+        let mut ctx = GContextBuilder::new()
+            .mem_size(Self::MEASURE_MEM_SIZE)
+            .no_alloc(true)
+            .build()?;
+        ctx.with_icontext_infallible(|mut ictx| {
+            ictx.measuring = true;
+            ictx.context_memory = usize::MAX;
+        })?;
+        let out = fun(&mut ctx)?;
+        let measurement = ctx.with_icontext(|_ctx, ictx| {
+            let ggml_used = unsafe { gg::ggml_used_mem(ictx.gptr()) };
+            Ok(GMeasurement {
+                context_size: ictx.context_used.max(ggml_used),
+                scratch_sizes: ictx
+                    .scratch_buffers
+                    .iter()
+                    .map(|sb| sb.as_ref().map_or(0, |sb| sb.peak))
+                    .collect(),
+            })
+        })?;
+        Ok((measurement, out))
+    }
+
+    /// Measure the memory the supplied function requires as with [Self::measure],
+    /// then build a context with that size and run the function again with it.
+    ///
+    /// Returns the new context along with the output of the second run.
+    pub fn measure_and_build<OUT, F>(mut fun: F) -> Result<(GContext, OUT)>
+    where
+        F: FnMut(&mut GContext) -> Result<OUT>,
+    {
+        let (measurement, _) = Self::measure(&mut fun)?;
+        let mut ctx = measurement.build()?;
+        let out = fun(&mut ctx)?;
+        Ok((ctx, out))
+    }
+
+    pub(crate) fn with_icontext<OUT, F>(&self, fun: F) -> Result<OUT>
+    where
+        F: FnOnce(&GContext, LockGuard<IContext>) -> Result<OUT>,
+    {
+        let failed = self.dead.get();
+        let ictx = self
+            .ictx
+            .lock()
+            .map_err(|_e| anyhow!(GContextError::MutexFailure))?;
+        // `freeze` holds the mutex, so this has to be checked after acquiring it.
+        self.ensure_not_frozen()?;
+        if let Some(e) = ictx.failed.clone() {
+            bail!(GContextError::DeadContext(e));
+        }
+        if failed {
+            bail!(GContextError::Unknown)
+        } else {
+            fun(self, ictx)
+        }
+    }
+
+    // FIXME: This logic seems kind of weird. Same problem in `Tensor::with_tensor_infallible`.
+    pub(crate) fn with_icontext_infallible<OUT, F>(&self, fun: F) -> Result<OUT>
+    where
+        F: FnOnce(LockGuard<IContext>) -> OUT,
+    {
+        let failed = self.dead.get();
+        let mut ctx = self.ictx.lock().map_err(|_e| {
+            self.dead.set(true);
+            GContextError::MutexFailure
+        })?;
+        self.ensure_not_frozen()?;
+        if let Some(e) = ctx.failed.clone() {
+            bail!(GContextError::DeadContext(e));
+        }
+        // This might look weird but the idea is that we might have failed previously
+        // due to being unable to acquire the mutex. Since we didn't have the mutex,
+        // naturally it was impossible to set the `failed` field inside the `IContext`
+        // structure.
+        // There probably still is a race condition here but it should be very unlikely.
+        if failed {
+            let e = GContextError::Unknown;
+            let trace = GOpTrace::new("unknown", vec![]);
+            ctx.set_failed(Arc::new(GFailure::new(trace, e.clone().into())));
+            Err(e)?;
+        }
+        Ok(fun(ctx))
+    }
+
+    // Fails if the context is frozen. Handles returned by `fail_with_last_failure`
+    // report the failure of the operation instead.
+    fn ensure_not_frozen(&self) -> Result<()> {
+        if let Some(frozen) = self.frozen.get() {
+            if let Some(failure) = frozen.failure.get() {
+                bail!(GContextError::DeadContext(failure.clone()));
+            }
+            bail!(GContextError::Frozen);
+        }
+        Ok(())
+    }
+
+    // Like `with_icontext` for functions that only read the context. Frozen
+    // contexts are read without taking the mutex.
+    pub(crate) fn with_icontext_read<OUT, F>(&self, fun: F) -> Result<OUT>
+    where
+        F: FnOnce(&GContext, &IContext) -> Result<OUT>,
+    {
+        let Some(frozen) = self.frozen.get() else {
+            return self.with_icontext(|ctx, ictx| fun(ctx, &ictx));
+        };
+        if let Some(failure) = frozen.failure.get() {
+            bail!(GContextError::DeadContext(failure.clone()));
+        }
+        fun(self, unsafe { &*frozen.ictx.0 })
+    }
+
+    // Runs a fallible operation with the context mutex held. A failure leaves
+    // the context usable, it is only saved so `fail_with_last_failure` can
+    // report it if it ends up killing the context.
+    pub(crate) fn with_icontext_traced<OUT, F>(
+        &self,
+        mut trace: GOpTrace,
+        fun: F,
+    ) -> Result<OUT, GTensorError>
+    where
+        F: FnOnce(&mut IContext, &mut GOpTrace) -> Result<OUT>,
+    {
+        let mut ictx = self
+            .ictx
+            .lock()
+            .map_err(|_e| GTensorError::General(Arc::new(anyhow!(GContextError::MutexFailure))))?;
+        if let Some(frozen) = self.frozen.get() {
+            if let Some(failure) = frozen.failure.get() {
+                Err(GTensorError::General(Arc::new(anyhow!(
+                    GContextError::DeadContext(failure.clone())
+                ))))?
+            }
+            let failure = GFailure::new(trace, anyhow!(GContextError::Frozen));
+            return Err(GTensorError::Failed(Arc::new(failure)));
+        }
+        if let Some(failure) = ictx.failed.clone() {
+            Err(GTensorError::General(Arc::new(anyhow!(
+                GContextError::DeadContext(failure)
+            ))))?
+        }
+        if self.dead.get() {
+            ictx.last_failure = None;
+            Err(GTensorError::General(Arc::new(anyhow!(
+                GContextError::Unknown
+            ))))?
+        }
+        fun(&mut ictx, &mut trace).map_err(|e| {
+            // If this fails, the objects are freed when the context is.
+            let _ = unsafe { ictx.rollback_uncommitted() };
+            ictx.record_failure(trace, e)
+        })
+    }
+
+    // Records the failure of an operation that couldn't be run with
+    // `with_icontext_traced` so a following `fail_with_last_failure` uses it.
+    pub(crate) fn record_failure(&self, trace: GOpTrace, err: anyhow::Error) -> GTensorError {
+        match self.ictx.lock() {
+            Ok(mut ictx) if self.frozen.get().is_none() => ictx.record_failure(trace, err),
+            _ => match err.downcast::<GTensorError>() {
+                Ok(err) => err,
+                Err(e) => GTensorError::Failed(Arc::new(GFailure::new(trace, e))),
+            },
+        }
+    }
+
+    // Kills the context after an operation run with `with_icontext_traced`
+    // failed with the specified error. Returns the context dead tensors should
+    // belong to and the generation they should use if the context mutex could
+    // be acquired.
+    //
+    // Frozen contexts are shared between threads, so they are never killed. For
+    // those this returns a separate handle that reports the failure when used.
+    pub(crate) fn fail_with_last_failure(&self, err: &GTensorError) -> (GContext, Option<usize>) {
+        let Ok(mut ictx) = self.ictx.lock() else {
+            self.dead.set(true);
+            return (self.clone(), None);
+        };
+        if let Some(frozen) = self.frozen.get() {
+            // Already a handle for an earlier failure.
+            if frozen.failure.get().is_some() {
+                return (self.clone(), None);
+            }
+            let failure = match err {
+                GTensorError::Failed(failure) => failure.clone(),
+                err => {
+                    let trace = GOpTrace::new("unknown", vec![]);
+                    Arc::new(GFailure::new(trace, err.clone().into()))
+                }
+            };
+            let state = FrozenState {
+                ictx: FrozenIContext(frozen.ictx.0),
+                failure: OnceLock::from(failure),
+            };
+            let ctx = GContext {
+                dead: Shared::new(Flag::new(true)),
+                frozen: Shared::new(OnceLock::from(state)),
+                ..self.clone()
+            };
+            return (ctx, None);
+        }
+        self.dead.set(true);
+        if ictx.failed.is_none() {
+            let failure = ictx.last_failure.take().unwrap_or_else(|| {
+                let trace = GOpTrace::new("unknown", vec![]);
+                Arc::new(GFailure::new(trace, err.clone().into()))
+            });
+            ictx.set_failed(failure);
+        }
+        (self.clone(), Some(ictx.generation()))
+    }
+
+    pub fn estimate_tensor_size<const DIMS: usize>(
+        &self,
+        typ: GType,
+        shape: [usize; DIMS],
+    ) -> Result<GMemoryRequest> {
+        self.with_icontext(|ctx, ictx| {
+            Ok(GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                &ictx,
+                typ,
+                Self::ggml_ne(shape),
+            ))
+        })
+    }
+
+    /// Create a new tensor with the specified [type](GType) and shape.
+    ///
+    /// This uses const generics to determine the new tensor's dimensions. The tensor dimensions
     /// will be equal to the number of items in the `shape` array.
     pub fn tensor<const DIMS: usize>(
         &self,
@@ -334,42 +1391,310 @@ impl GContext {
         DimPair<DIMS, 4>: DimLt,
     {
         self.with_icontext(|ctx, mut ictx| {
-            let mr = GMemoryRequest::estimate_tensor_request_ictx(self, &ictx, typ, shape);
-            mr.fit_or_die()?;
+            let ne = Self::ggml_ne(shape);
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(self, &ictx, typ, ne);
+            let mr = mr.fit_or_grow(&mut ictx)?;
+            if let Some(galloc) = &ictx.graph_allocator {
+                galloc.ensure_fits(GMemoryRequest::tensor_data_bytes(typ, &ne))?;
+            }
 
             unsafe {
-                let p = match DIMS {
-                    1 => gg::ggml_new_tensor_1d(ictx.gptr(), typ as u32, shape[0] as i64),
-                    2 => gg::ggml_new_tensor_2d(
-                        ictx.gptr(),
-                        typ as u32,
-                        shape[1] as i64,
-                        shape[0] as i64,
-                    ),
-                    3 => gg::ggml_new_tensor_3d(
-                        ictx.gptr(),
-                        typ as u32,
-                        shape[1] as i64,
-                        shape[0] as i64,
-                        shape[2] as i64,
-                    ),
-                    _ => unreachable!(),
-                };
-
-                if p.is_null() {
-                    Err(GContextError::TensorCreationFailed)?;
+                let p = Self::new_tensor_ptr(&ictx, typ, shape)?;
+                if let Some(galloc) = &mut ictx.graph_allocator {
+                    galloc.alloc_tensor(p);
+                }
+                GTensor::new_from_ptr(ctx, &mut ictx, (mr, p))
+            }
+        })
+    }
+
+    /// # Safety
+    /// Must be called with context mutex held.
+    unsafe fn new_tensor_ptr<const DIMS: usize>(
+        ictx: &IContext,
+        typ: GType,
+        shape: [usize; DIMS],
+    ) -> Result<*mut gg::ggml_tensor> {
+        let p = match DIMS {
+            1 => gg::ggml_new_tensor_1d(ictx.gptr(), typ as u32, shape[0] as i64),
+            2 => gg::ggml_new_tensor_2d(ictx.gptr(), typ as u32, shape[1] as i64, shape[0] as i64),
+            3 => gg::ggml_new_tensor_3d(
+                ictx.gptr(),
+                typ as u32,
+                shape[1] as i64,
+                shape[0] as i64,
+                shape[2] as i64,
+            ),
+            _ => unreachable!(),
+        };
+
+        if p.is_null() {
+            Err(GContextError::TensorCreationFailed)?;
+        }
+        Ok(p)
+    }
+
+    /// Converts a shape as passed to [Self::tensor] to GGML order, where the
+    /// number of columns comes first.
+    fn ggml_ne<const DIMS: usize>(mut shape: [usize; DIMS]) -> [usize; DIMS] {
+        if DIMS > 1 {
+            shape.swap(0, 1);
+        }
+        shape
+    }
+
+    /// Find a tensor in this context by name. See [GTensor::set_name].
+    ///
+    /// **Invariants**
+    /// 1. A tensor with the name must exist in the context.
+    /// 2. `DIMS` must match the number of dimensions of the tensor.
+    pub fn get_tensor<const DIMS: usize>(&self, name: &str) -> Result<GTensor<DIMS>>
+    where
+        Dim<DIMS>: DimValid,
+    {
+        let cname = std::ffi::CString::new(name)
+            .map_err(|_e| GContextError::TensorNotFound(name.to_string()))?;
+        let t = self.with_icontext_read(|ctx, ictx| unsafe {
+            let tptr = ictx
+                .arenas()
+                .find_map(|gctx| NonNull::new(gg::ggml_get_tensor(gctx.as_ptr(), cname.as_ptr())))
+                .ok_or_else(|| GContextError::TensorNotFound(name.to_string()))?;
+            GAnyTensor::from_existing_ptr(ctx, ictx, tptr)
+        })?;
+        t.into_typed()
+    }
+
+    /// Returns an iterator over every tensor in this context in the order
+    /// they were created, including intermediate results and tensors
+    /// no longer referenced elsewhere.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// // Count the parameters of a model: tensors that aren't the result of an operation.
+    /// let params = ctx
+    ///     .tensors()?
+    ///     .filter(|ct| ct.op == GOp::None)
+    ///     .map(|ct| ct.tensor.elements())
+    ///     .sum::<usize>();
+    /// ```
+    pub fn tensors(&self) -> Result<impl Iterator<Item = GContextTensor>> {
+        self.with_icontext_read(|ctx, ictx| unsafe {
+            ictx.tensor_ptrs()
+                .into_iter()
+                .map(|tptr| {
+                    let tref = tptr.as_ref();
+                    Ok(GContextTensor {
+                        tensor: GAnyTensor::from_existing_ptr(ctx, ictx, tptr)?,
+                        name: std::ffi::CStr::from_ptr(tref.name.as_ptr())
+                            .to_string_lossy()
+                            .into_owned(),
+                        op: GOp::from_u32(tref.op).ok_or(GTensorError::UnknownOp(tref.op))?,
+                        is_view: !tref.view_src.is_null(),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .map(|tensors| tensors.into_iter())
+    }
+
+    /// Register a read-only region of memory that tensor data can point into
+    /// without being copied, for example a memory mapped model file. Any type
+    /// that can be viewed as `[u8]` works, including `memmap2::Mmap`.
+    ///
+    /// The return value is the region id which can be used with [Self::mapped_tensor].
+    ///
+    /// **Note**: Mapped regions cannot be removed directly and are only freed
+    /// when the [GContext] structure is dropped.
+    pub fn register_mapped_region<M>(&self, region: M) -> Result<usize>
+    where
+        M: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        self.with_icontext_infallible(|mut ictx| {
+            let regionid = ictx.mapped_regions.len();
+            ictx.mapped_regions.push(MappedRegion {
+                data: Box::new(region),
+            });
+            regionid
+        })
+    }
+
+    /// Create a new tensor with the specified [type](GType) and shape with its data
+    /// pointing into a region registered with [Self::register_mapped_region], starting
+    /// at `offset` bytes into the region. Only the GGML object for the tensor uses context
+    /// memory.
+    ///
+    /// **Invariants**
+    /// 1. `region` must be a valid id as returned by [Self::register_mapped_region].
+    /// 2. The tensor's data must fit in the region starting at `offset`.
+    /// 3. The tensor is read-only: Attempting to change its data will fail.
+    pub fn mapped_tensor<const DIMS: usize>(
+        &self,
+        typ: GType,
+        shape: [usize; DIMS],
+        region: usize,
+        offset: usize,
+    ) -> Result<GTensor<DIMS>>
+    where
+        Dim<DIMS>: DimValid,
+        DimPair<DIMS, 4>: DimLt,
+    {
+        self.with_icontext(|ctx, mut ictx| {
+            let ne = Self::ggml_ne(shape);
+            let mr = GMemoryRequest::estimate_mapped_tensor_request_ictx(self, &ictx, typ, ne);
+            let mr = mr.fit_or_grow(&mut ictx)?;
+
+            let data = ictx
+                .mapped_regions
+                .get(region)
+                .ok_or(GContextError::InvalidMappedRegionId(region))?
+                .as_slice();
+            // An offset that overflows can't fit in any region.
+            let needed = offset.saturating_add(GMemoryRequest::tensor_data_bytes(typ, &ne));
+            ensure!(
+                needed <= data.len(),
+                GContextError::MappedRegionTooSmall {
+                    region,
+                    needed,
+                    len: data.len(),
                 }
+            );
+            let data = data[offset..].as_ptr() as *mut c_void;
+
+            unsafe {
+                // Tensors created while no_alloc is set only get the GGML object.
+                let no_alloc = gg::ggml_get_no_alloc(ictx.gptr());
+                gg::ggml_set_no_alloc(ictx.gptr(), true);
+                let p = Self::new_tensor_ptr(&ictx, typ, shape);
+                gg::ggml_set_no_alloc(ictx.gptr(), no_alloc);
+                let p = p?;
+                (*p).data = data;
                 GTensor::new_from_ptr(ctx, &mut ictx, (mr, p))
             }
         })
     }
 
+    /// Create a tensor in this context that's a zero-copy view of a tensor from
+    /// another context. Like a [mapped tensor](Self::mapped_tensor), only the GGML object
+    /// uses context memory and the tensor is read-only.
+    ///
+    /// The other context is kept alive for as long as this one exists. This makes it
+    /// possible to build graphs in short-lived contexts against tensors in a long-lived one.
+    ///
+    /// **Invariants**
+    /// 1. The tensor's data must be allocated.
+    /// 2. The tensor must not be the result of an operation ([GOp::None]). The view is
+    ///    a leaf of graphs built in this context, so the operation would never run.
+    ///    Import the inputs of the operation instead, or copy the computed result
+    ///    with [Self::import_copy].
+    /// 3. The other context must not have views of tensors from this one.
+    ///
+    /// **Note**: Rewinding the other context past the tensor invalidates the view
+    /// without it being detected.
+    ///
+    /// **Note**: Longer chains of contexts importing from each other, for example
+    /// `A` from `B`, `B` from `C` and `C` from `A`, are not detected. None of the
+    /// contexts in such a cycle are ever freed.
+    pub fn import_view<const DIMS: usize>(&self, tensor: &GTensor<DIMS>) -> Result<GTensor<DIMS>>
+    where
+        Dim<DIMS>: DimValid,
+    {
+        // Only one context is locked at a time, so this can't deadlock.
+        let (ne, nb, data) = tensor.with_tensor_read(|ctx, ictx, tptr| {
+            ensure!(!ctx.no_alloc, GContextError::NoAlloc);
+            ensure!(
+                tensor.md.op == GOp::None,
+                GContextError::ImportNotLeaf(tensor.md.op)
+            );
+            ensure!(
+                !ictx
+                    .imports
+                    .iter()
+                    .any(|imp| Shared::ptr_eq(&imp.source, &self.ictx)),
+                GContextError::CyclicImport
+            );
+            GTensor::<DIMS>::ensure_allocated(ictx, tptr)?;
+            let tref = unsafe { &*tptr };
+            Ok((tref.ne, tref.nb, tref.data))
+        })?;
+        let mut result = self.with_icontext(|ctx, mut ictx| {
+            let typ = tensor.md.typ;
+            let mr = GMemoryRequest::estimate_mapped_tensor_request_ictx(
+                self,
+                &ictx,
+                typ,
+                ne.map(|n| n as usize),
+            );
+            let mr = mr.fit_or_grow(&mut ictx)?;
+            if tensor.ctx.ptrval != ctx.ptrval {
+                let start = data as usize;
+                ictx.imports.push(ImportedData {
+                    source: tensor.ctx.ictx.clone(),
+                    data: start..start + tensor.md.len_bytes,
+                });
+            }
+
+            unsafe {
+                let no_alloc = gg::ggml_get_no_alloc(ictx.gptr());
+                gg::ggml_set_no_alloc(ictx.gptr(), true);
+                let p = gg::ggml_new_tensor(ictx.gptr(), typ as u32, DIMS as i32, ne.as_ptr());
+                gg::ggml_set_no_alloc(ictx.gptr(), no_alloc);
+                ensure!(!p.is_null(), GContextError::TensorCreationFailed);
+                (*p).nb = nb;
+                (*p).data = data;
+                GTensor::new_from_ptr(ctx, &mut ictx, (mr, p))
+            }
+        })?;
+        // Data in the other context's scratch buffer stays in use while the view exists.
+        result.scratch_ref = tensor.scratch_ref.clone();
+        Ok(result)
+    }
+
+    /// Create a tensor in this context with a copy of the data of a tensor
+    /// from another context.
+    ///
+    /// **Invariants**
+    /// 1. The tensor's data must be allocated and contiguous.
+    /// 2. The tensor must have fewer than 4 dimensions.
+    pub fn import_copy<const DIMS: usize>(&self, tensor: &GTensor<DIMS>) -> Result<GTensor<DIMS>>
+    where
+        Dim<DIMS>: DimValid,
+        DimPair<DIMS, 4>: DimLt,
+    {
+        ensure!(tensor.md.is_contiguous(), GTensorError::InvalidOperation);
+        // Shapes are specified with rows and columns swapped compared to GGML.
+        let mut shape = tensor.md.shape;
+        if DIMS > 1 {
+            shape.swap(0, 1);
+        }
+        let result = self.tensor(tensor.md.typ, shape)?;
+        if self.no_alloc {
+            return Ok(result);
+        }
+        let dst = result.with_tensor_infallible(|_ctx, _ictx, tptr| unsafe { (*tptr).data })?;
+        // The new tensor isn't visible to anything else yet, so it's safe to write
+        // its data without holding this context's lock.
+        unsafe {
+            tensor.with_data(|src| {
+                (dst as *mut u8).copy_from_nonoverlapping(src.as_ptr(), src.len())
+            })?;
+        }
+        Ok(result)
+    }
+
     /// Register a scratch buffer. The return value is the scratch buffer id
     /// which can be used with [Self::set_scratch_buffer].
+    ///
+    /// **Note**: If the context was built from a [GMeasurement], the buffer
+    /// will be replaced with a larger one if it is smaller than the
+    /// measured size for its id.
     pub fn register_scratch_buffer(&mut self, buf: ScratchBuffer) -> Result<usize> {
         self.with_icontext_infallible(|mut ictx| {
             let bufid = ictx.scratch_buffers.len();
-            ictx.scratch_buffers.push(buf);
+            let buf = match ictx.scratch_size_hints.get(bufid) {
+                Some(size) if *size > buf.buf.len() => ScratchBuffer::new(*size),
+                _ => buf,
+            };
+            ictx.scratch_buffers.push(Some(buf));
             bufid
         })
     }
@@ -377,62 +1702,381 @@ impl GContext {
     /// Set or clear the current scratch buffer. A valid id as returned by
     /// [Self::register_scratch_buffer] must be supplied.
     ///
-    /// **Note**: Scratch buffers cannot be removed directly and are only freed
-    /// when the [GContext] structure is dropped.
+    /// New tensors are placed after any memory already used in the buffer.
+    /// See [Self::reset_scratch_buffer].
     pub fn set_scratch_buffer(&self, maybebufid: Option<usize>) -> Result<()> {
         self.with_icontext(|_ctx, mut ictx| {
-            let (size, data) = if let Some(bufid) = maybebufid {
-                if bufid >= ictx.scratch_buffers.len() {
-                    Err(GContextError::InvalidScratchBufferId(bufid))?;
-                }
-                ictx.current_scratch_buffer = maybebufid;
-                let buf = &mut ictx.scratch_buffers[bufid].buf;
-                (buf.len(), buf.as_mut_ptr() as *mut c_void)
-            } else {
-                (0, std::ptr::null_mut())
-            };
-            unsafe {
-                gg::ggml_set_scratch(
-                    ictx.gptr(),
-                    gg::ggml_scratch {
-                        offs: 0,
-                        size,
-                        data,
-                    },
-                );
+            if let Some(bufid) = maybebufid {
+                ictx.scratch_buffer(bufid)?;
             }
+            ictx.current_scratch_buffer = maybebufid;
+            ictx.sync_scratch();
+            Ok(())
+        })
+    }
+
+    /// Reset the used memory of a scratch buffer to zero so it can be reused,
+    /// for example when rebuilding the graph for each step of generation.
+    ///
+    /// **Invariants**
+    /// 1. `bufid` must be a valid id as returned by [Self::register_scratch_buffer].
+    /// 2. No live tensor may have data in the buffer.
+    ///
+    /// **Note**: Tensors in graphs built before the reset may still refer to the
+    /// buffer, so those graphs must not be computed afterward.
+    pub fn reset_scratch_buffer(&mut self, bufid: usize) -> Result<()> {
+        self.with_icontext(|_ctx, mut ictx| {
+            ictx.ensure_scratch_unused(bufid)?;
+            ictx.scratch_buffer_mut(bufid)?.used = 0;
+            ictx.sync_scratch();
             Ok(())
         })
     }
 
+    /// Replace a scratch buffer, for example with a larger one. The id stays the
+    /// same and the old buffer is freed. If it was the current scratch buffer,
+    /// the new one becomes current.
+    ///
+    /// **Invariants**
+    /// 1. `bufid` must be a valid id as returned by [Self::register_scratch_buffer].
+    /// 2. No live tensor may have data in the old buffer.
+    ///
+    /// **Note**: See [Self::reset_scratch_buffer].
+    pub fn replace_scratch_buffer(&mut self, bufid: usize, buf: ScratchBuffer) -> Result<()> {
+        self.with_icontext(|_ctx, mut ictx| {
+            ictx.ensure_scratch_unused(bufid)?;
+            ictx.scratch_buffers[bufid] = Some(buf);
+            ictx.sync_scratch();
+            Ok(())
+        })
+    }
+
+    /// Remove a scratch buffer and free it. If it was the current scratch buffer,
+    /// no scratch buffer will be current afterward. The id won't be reused.
+    ///
+    /// **Invariants**
+    /// 1. `bufid` must be a valid id as returned by [Self::register_scratch_buffer].
+    /// 2. No live tensor may have data in the buffer.
+    ///
+    /// **Note**: See [Self::reset_scratch_buffer].
+    pub fn remove_scratch_buffer(&mut self, bufid: usize) -> Result<()> {
+        self.with_icontext(|_ctx, mut ictx| {
+            ictx.ensure_scratch_unused(bufid)?;
+            ictx.scratch_buffers[bufid] = None;
+            if ictx.current_scratch_buffer == Some(bufid) {
+                ictx.current_scratch_buffer = None;
+            }
+            ictx.sync_scratch();
+            Ok(())
+        })
+    }
+
+    /// Use the context's [GGraphAllocator] to allocate the data for the tensors
+    /// in the graph. The return value is the amount of allocator memory
+    /// used so far.
+    ///
+    /// This happens automatically in [Self::compute] if it hasn't been done already.
+    ///
+    /// **Invariants**
+    /// 1. The context must have a graph allocator.
+    /// 2. The graph must not already have been allocated.
+    /// 3. The allocator's buffer must have room for the graph. Use
+    ///    [GGraphAllocator::measure] to find the required size.
+    pub fn alloc_graph(&self, graph: &mut GGraph) -> Result<usize> {
+        ensure!(!graph.allocated, GAllocatorError::AlreadyAllocated);
+        let size = self.with_icontext(|_ctx, mut ictx| {
+            let galloc = ictx
+                .graph_allocator
+                .as_mut()
+                .ok_or(GContextError::NoGraphAllocator)?;
+            unsafe { galloc.alloc_graph(&mut graph.graph) }
+        })?;
+        graph.allocated = true;
+        Ok(size)
+    }
+
     /// Runs the supplied graph using this context.
     pub fn compute(&self, graph: &mut GGraph) -> Result<()> {
         ensure!(!self.no_alloc, GContextError::NoAlloc);
+        if !graph.allocated
+            && self.with_icontext(|_ctx, ictx| Ok(ictx.graph_allocator.is_some()))?
+        {
+            self.alloc_graph(graph)?;
+        }
         let n_threads = graph.n_threads;
-        self.with_icontext_infallible(|ictx| unsafe {
-            gg::ggml_graph_compute_with_ctx(ictx.gptr(), &mut graph.graph, n_threads as i32)
+        self.with_icontext_infallible(|_ictx| unsafe {
+            // The work buffer lives outside the context so computing doesn't use
+            // context memory that no request accounted for.
+            let mut cplan = gg::ggml_graph_plan(&mut *graph.graph, n_threads as i32);
+            let mut work = vec![0u8; cplan.work_size];
+            cplan.work_data = work.as_mut_ptr();
+            gg::ggml_graph_compute(&mut *graph.graph, &mut cplan);
+        })
+    }
+
+    /// Returns every memory request applied to the context so far, in order.
+    /// Like [Self::memory_report], this also works with a dead context.
+    pub fn allocation_log(&self) -> Result<Vec<GAllocationRecord>> {
+        let ictx = self.ictx.lock().map_err(|_e| GContextError::MutexFailure)?;
+        Ok(ictx.allocation_log.clone())
+    }
+
+    /// Returns a summary of the context's memory use including the `top_n`
+    /// tensors that used the most memory. This also works with a dead
+    /// context, which is useful after running out of memory.
+    pub fn memory_report(&self, top_n: usize) -> Result<GMemoryReport> {
+        let ictx = self.ictx.lock().map_err(|_e| GContextError::MutexFailure)?;
+        let mut top_consumers = ictx.allocation_log.clone();
+        top_consumers.sort_by_key(|rec| std::cmp::Reverse(rec.total_bytes()));
+        top_consumers.truncate(top_n);
+        Ok(GMemoryReport {
+            context_size: ictx.context_memory,
+            context_used: ictx.context_used,
+            ggml_used: ictx.ggml_used(),
+            scratch: ictx
+                .scratch_buffers
+                .iter()
+                .enumerate()
+                .filter_map(|(id, sb)| {
+                    sb.as_ref().map(|sb| GScratchUsage {
+                        id,
+                        size: sb.buf.len(),
+                        used: sb.used,
+                        peak: sb.peak,
+                    })
+                })
+                .collect(),
+            tensors: ictx.allocation_log.len(),
+            graph_bytes: ictx.allocation_log.iter().map(|rec| rec.graph_bytes).sum(),
+            top_consumers,
         })
     }
 
+    /// Save the current allocation state of the context so it can be restored
+    /// with [Self::rewind].
+    pub fn mark(&self) -> Result<GContextMark> {
+        self.with_icontext(|ctx, ictx| {
+            Ok(GContextMark {
+                ptrval: ctx.ptrval,
+                generation: ictx.generation(),
+                arena_used: ictx.arena_used(),
+                context_used: ictx.context_used,
+                scratch_used: ictx
+                    .scratch_buffers
+                    .iter()
+                    .map(|sb| sb.as_ref().map_or(0, |sb| sb.used))
+                    .collect(),
+                galloc_used: ictx.graph_allocator.as_ref().map_or(0, |ga| ga.used()),
+                log_len: ictx.allocation_log.len(),
+            })
+        })
+    }
+
+    /// Roll the context back to a mark saved with [Self::mark], freeing the context,
+    /// scratch and graph allocator memory used by every tensor created since. Those
+    /// tensors become invalid: Using them will fail with [GContextError::InvalidatedTensor].
+    ///
+    /// A mark can be used any number of times but rewinding to an earlier mark
+    /// invalidates the marks saved after it.
+    ///
+    /// **Invariants**
+    /// 1. The mark must come from this context and still be valid.
+    ///
+    /// **Note**: Graphs containing tensors created after the mark must not be computed.
+    pub fn rewind(&mut self, mark: &GContextMark) -> Result<()> {
+        self.with_icontext(|ctx, mut ictx| {
+            ensure!(
+                mark.ptrval == ctx.ptrval,
+                GContextError::InvalidMark("mark is from a different context")
+            );
+            ensure!(
+                ictx.rewind_limits
+                    .get(mark.generation)
+                    .is_none_or(|limit| mark.arena_used <= *limit),
+                GContextError::InvalidMark("context was rewound to an earlier point")
+            );
+
+            unsafe { ictx.truncate_arena(mark.arena_used)? };
+            if let Some(galloc) = &mut ictx.graph_allocator {
+                // Tensors created before the mark only have data below `galloc_used`.
+                unsafe { galloc.truncate(mark.galloc_used) };
+            }
+            ictx.arena_committed = mark.arena_used;
+            ictx.context_used = mark.context_used;
+            for (bufid, sbuf) in ictx.scratch_buffers.iter_mut().enumerate() {
+                if let Some(sbuf) = sbuf {
+                    sbuf.used = sbuf
+                        .used
+                        .min(mark.scratch_used.get(bufid).copied().unwrap_or(0));
+                }
+            }
+            ictx.sync_scratch();
+            ictx.allocation_log.truncate(mark.log_len);
+            ictx.rewind_limits
+                .iter_mut()
+                .for_each(|limit| *limit = (*limit).min(mark.arena_used));
+            ictx.rewind_limits.push(mark.arena_used);
+            Ok(())
+        })
+    }
+
+    /// Bring a dead context back to life after an operation failed, returning the
+    /// failure if there was one. GGML objects left behind by the failed operation
+    /// are freed.
+    ///
+    /// Tensors created before the failure remain usable. Tensors returned by
+    /// operations while the context was dead are invalid: Using them will fail
+    /// with [GContextError::InvalidatedTensor].
+    ///
+    /// **Note**: Graph allocator memory used by the failed operation isn't freed.
+    pub fn recover(&mut self) -> Result<Option<Arc<GFailure>>> {
+        let mut ictx = self.ictx.lock().map_err(|_e| GContextError::MutexFailure)?;
+        ensure!(self.frozen.get().is_none(), GContextError::Frozen);
+        ictx.last_failure = None;
+        let Some(failure) = ictx.failed.take() else {
+            ensure!(!self.dead.get(), GContextError::Unknown);
+            return Ok(None);
+        };
+        unsafe { ictx.rollback_uncommitted()? };
+        // Only tensors handed out while dead belong to the current generation.
+        ictx.rewind_limits.push(0);
+        self.dead.set(false);
+        Ok(Some(failure))
+    }
+
     /// Returns the amount of memory GGML is currently using.
     pub fn used_mem(&self) -> Result<usize> {
-        self.with_icontext_infallible(|ictx| unsafe { gg::ggml_used_mem(ictx.gptr()) })
+        self.with_icontext_read(|_ctx, ictx| Ok(ictx.ggml_used()))
+    }
+
+    /// Freeze the context, for example after loading model weights into it.
+    /// See [GFrozenContext].
+    ///
+    /// **Invariants**
+    /// 1. The context must not be dead.
+    pub fn freeze(self) -> Result<GFrozenContext> {
+        self.with_icontext(|ctx, ictx| {
+            let state = FrozenState {
+                ictx: FrozenIContext(&*ictx as *const IContext),
+                failure: OnceLock::new(),
+            };
+            // Only possible if another thread froze a clone of the context first.
+            ensure!(ctx.frozen.set(state).is_ok(), GContextError::Frozen);
+            Ok(())
+        })?;
+        Ok(GFrozenContext { ctx: self })
+    }
+}
+
+#[derive(Clone)]
+/// An immutable [GContext], usually holding model weights. Frozen contexts can
+/// be shared between threads and reading them doesn't require acquiring a lock,
+/// so several threads can build and compute graphs using the same weights at the
+/// same time, each in its own mutable context.
+///
+/// Tensors from the frozen context can't be modified and operations can't create
+/// new tensors in it. Binary operations between a frozen tensor and a tensor from
+/// another context put the result in the other context, see [GContext::import_view].
+///
+/// **Note**: Trying to modify the context doesn't kill it, since other threads may
+/// be using it. Instead the tensor the operation returns is dead, or for operations
+/// like [GTensor::fill_f32] that don't return one, the tensor it was called on.
+/// Using a dead tensor reports the failure. The `try_` version of operations
+/// returns the error right away.
+///
+/// **Note**: With the `single_threaded` feature, frozen contexts can't be sent
+/// to other threads.
+///
+/// Example:
+/// ```rust,ignore
+/// let weights = load_weights()?.freeze()?;
+/// let workers = (0..4).map(|_| {
+///     let weights = weights.clone();
+///     std::thread::spawn(move || -> Result<()> {
+///         let ctx = GContextBuilder::new().mem_size(ctx_size).build()?;
+///         let w = weights.get_tensor::<2>("output.weight")?;
+///         let x = ctx.tensor(GType::F32, [1, 4096])?;
+///         let result = w.mul_mat(&x);
+///         // ...
+///         Ok(())
+///     })
+/// });
+/// ```
+pub struct GFrozenContext {
+    ctx: GContext,
+}
+
+impl GFrozenContext {
+    /// Find a tensor in this context by name. See [GContext::get_tensor].
+    pub fn get_tensor<const DIMS: usize>(&self, name: &str) -> Result<GTensor<DIMS>>
+    where
+        Dim<DIMS>: DimValid,
+    {
+        self.ctx.get_tensor(name)
+    }
+
+    /// Returns an iterator over every tensor in this context. See [GContext::tensors].
+    pub fn tensors(&self) -> Result<impl Iterator<Item = GContextTensor>> {
+        self.ctx.tensors()
+    }
+
+    /// Returns a summary of the context's memory use. See [GContext::memory_report].
+    pub fn memory_report(&self, top_n: usize) -> Result<GMemoryReport> {
+        self.ctx.memory_report(top_n)
+    }
+
+    /// Returns the amount of memory GGML is using.
+    pub fn used_mem(&self) -> Result<usize> {
+        self.ctx.used_mem()
     }
 }
 
 pub struct GGraph {
     n_threads: usize,
-    graph: gg::ggml_cgraph,
+    // The GGML graph structure is large, so it lives on the heap.
+    graph: Box<gg::ggml_cgraph>,
+    // Contexts the graph's tensors were expanded from, used to find the
+    // context a tensor belongs to.
+    contexts: Vec<GContext>,
+    // Tensors the graph was expanded with, in order.
+    outputs: Vec<*mut gg::ggml_tensor>,
+    // Set once a graph allocator has placed the graph's tensors.
+    allocated: bool,
 }
 
 impl GGraph {
     /// Create a new computation graph with the specified number of threads.
+    /// The graph can hold up to `GGML_MAX_NODES` (4096) nodes and as many leafs.
     pub fn new(n_threads: usize) -> Self {
-        let graph = unsafe { std::mem::zeroed::<gg::ggml_cgraph>() };
-        Self { n_threads, graph }
+        let graph = unsafe { Box::<gg::ggml_cgraph>::new_zeroed().assume_init() };
+        Self {
+            n_threads,
+            graph,
+            contexts: vec![],
+            outputs: vec![],
+            allocated: false,
+        }
+    }
+
+    /// Returns the number of threads used when computing the graph.
+    pub fn n_threads(&self) -> usize {
+        self.n_threads
+    }
+
+    /// Returns the number of nodes in the graph.
+    pub fn n_nodes(&self) -> usize {
+        self.graph.n_nodes as usize
+    }
+
+    /// Returns the number of leafs in the graph.
+    pub fn n_leafs(&self) -> usize {
+        self.graph.n_leafs as usize
     }
 
     /// Register a tensor to be processed when the graph is computed.
+    ///
+    /// **Invariants**
+    /// 1. The graph must not end up with more than `GGML_MAX_NODES` (4096) nodes or leafs.
     pub fn build_forward_expand<const DIMS: usize, T: AsRef<GTensor<DIMS>>>(
         &mut self,
         tensor: T,
@@ -443,8 +2087,810 @@ impl GGraph {
         // FIXME: Should we bail out here if no_alloc?
         tensor
             .as_ref()
-            .with_tensor_infallible(|_ctx, _ictx, tptr| unsafe {
-                gg::ggml_build_forward_expand(&mut self.graph, tptr)
+            .with_tensor_infallible(|ctx, _ictx, tptr| unsafe {
+                let (nodes, leafs) = self.count_new(tptr);
+                let (nodes, leafs) = (self.n_nodes() + nodes, self.n_leafs() + leafs);
+                let max = gg::GGML_MAX_NODES as usize;
+                ensure!(
+                    nodes <= max && leafs <= max,
+                    GContextError::GraphOverflow { max, nodes, leafs }
+                );
+                gg::ggml_build_forward_expand(&mut *self.graph, tptr);
+                if !self.contexts.iter().any(|c| c.ptrval == ctx.ptrval) {
+                    self.contexts.push(ctx.clone());
+                }
+                if !self.outputs.contains(&tptr) {
+                    self.outputs.push(tptr);
+                }
+                Ok(())
+            })?
+    }
+
+    /// Returns an iterator over the nodes of the graph: the tensors that are the
+    /// result of an operation, in the order they will be computed.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let mut graph = GGraph::new(1);
+    /// graph.build_forward_expand(&(&a * &b))?;
+    /// let ops = graph.nodes()?.map(|gt| gt.op).collect::<Vec<_>>();
+    /// assert_eq!(ops, [GOp::Mul]);
+    /// ```
+    pub fn nodes(&self) -> Result<impl Iterator<Item = GGraphTensor>> {
+        self.graph_tensors(&self.graph.nodes[..self.n_nodes()])
+    }
+
+    /// Returns an iterator over the leafs of the graph: the tensors that are inputs
+    /// and don't depend on any other tensor, in the order they were added.
+    pub fn leafs(&self) -> Result<impl Iterator<Item = GGraphTensor>> {
+        self.graph_tensors(&self.graph.leafs[..self.n_leafs()])
+    }
+
+    pub(crate) fn cgraph(&self) -> &gg::ggml_cgraph {
+        &self.graph
+    }
+
+    pub(crate) fn outputs(&self) -> &[*mut gg::ggml_tensor] {
+        &self.outputs
+    }
+
+    fn graph_tensors(
+        &self,
+        tptrs: &[*mut gg::ggml_tensor],
+    ) -> Result<impl Iterator<Item = GGraphTensor>> {
+        // Every source of a tensor in the graph is also a node or leaf.
+        let all = self.graph.nodes[..self.n_nodes()]
+            .iter()
+            .chain(&self.graph.leafs[..self.n_leafs()]);
+        let mut tensors = HashMap::new();
+        for ctx in &self.contexts {
+            ctx.with_icontext_read(|ctx, ictx| unsafe {
+                for tptr in all.clone().filter_map(|tptr| NonNull::new(*tptr)) {
+                    if ictx.arena_offset(tptr.as_ptr()) != usize::MAX {
+                        tensors.insert(
+                            tptr.as_ptr(),
+                            GAnyTensor::from_existing_ptr(ctx, ictx, tptr)?,
+                        );
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        let get = |tptr: *mut gg::ggml_tensor| {
+            tensors
+                .get(&tptr)
+                .cloned()
+                .ok_or_else(|| anyhow!("Graph tensor does not belong to a known context"))
+        };
+        tptrs
+            .iter()
+            .map(|tptr| unsafe {
+                let tref = &**tptr;
+                let tensor = get(*tptr)?;
+                Ok(GGraphTensor {
+                    op: tensor.op(),
+                    unary_op: tensor.unary_op(),
+                    tensor,
+                    name: std::ffi::CStr::from_ptr(tref.name.as_ptr())
+                        .to_string_lossy()
+                        .into_owned(),
+                    sources: tref
+                        .src
+                        .iter()
+                        .filter(|src| !src.is_null())
+                        .map(|src| get(*src))
+                        .collect::<Result<_>>()?,
+                    is_param: tref.is_param,
+                })
             })
+            .collect::<Result<Vec<_>>>()
+            .map(|tensors| tensors.into_iter())
+    }
+
+    /// Returns a [Graphviz](https://graphviz.org/) DOT document describing the graph.
+    ///
+    /// Every node and leaf is labelled with its name, operation, type and shape.
+    /// Shapes use the same dimension order as [GContext::tensor], not GGML's.
+    /// Edges point from a tensor's sources to the tensor and are labelled with the
+    /// source index. Leafs are filled pink and parameters yellow.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// std::fs::write("graph.dot", graph.to_dot()?)?;
+    /// // Then render it with: dot -Tsvg graph.dot -o graph.svg
+    /// ```
+    pub fn to_dot(&self) -> Result<String> {
+        use std::fmt::Write;
+
+        let nodes = &self.graph.nodes[..self.n_nodes()];
+        let leafs = &self.graph.leafs[..self.n_leafs()];
+        let ids = nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, tptr)| (*tptr, format!("n{idx}")))
+            .chain(
+                leafs
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, tptr)| (*tptr, format!("l{idx}"))),
+            )
+            .collect::<HashMap<_, _>>();
+        let tensors = self.nodes()?.chain(self.leafs()?);
+
+        let mut dot = String::from("digraph G {\n  rankdir = TB;\n  node [shape = box];\n");
+        let mut edges = String::new();
+        for (tptr, gt) in nodes.iter().chain(leafs).zip(tensors) {
+            let id = &ids[tptr];
+            let op = match gt.unary_op {
+                Some(uop) => format!("{}({uop})", gt.op),
+                None => gt.op.to_string(),
+            };
+            // GGML keeps the number of columns first.
+            let mut shape = gt.tensor.shape();
+            if shape.len() > 1 {
+                shape.swap(0, 1);
+            }
+            let style = if gt.is_param {
+                ", style = filled, fillcolor = yellow"
+            } else if id.starts_with('l') {
+                ", style = filled, fillcolor = pink"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "  {id} [label = \"{}\\n{op} {:?} {shape:?}\"{style}];",
+                gt.name.replace('\\', "\\\\").replace('"', "\\\""),
+                gt.tensor.element_type(),
+            )?;
+            let srcs = unsafe { (**tptr).src };
+            for (idx, src) in srcs.iter().enumerate().filter(|(_, src)| !src.is_null()) {
+                let src_id = ids
+                    .get(src)
+                    .ok_or_else(|| anyhow!("Graph tensor source is not part of the graph"))?;
+                writeln!(edges, "  {src_id} -> {id} [label = \"{idx}\"];")?;
+            }
+        }
+        dot.push_str(&edges);
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// Returns the number of `(nodes, leafs)` expanding the graph with the tensor
+    /// would add. This visits the sources the same way GGML does.
+    ///
+    /// # Safety
+    /// The tensor and its sources must be valid.
+    unsafe fn count_new(&self, tptr: *mut gg::ggml_tensor) -> (usize, usize) {
+        let g = &*self.graph;
+        let mut visited = g.nodes[..self.n_nodes()]
+            .iter()
+            .chain(&g.leafs[..self.n_leafs()])
+            .copied()
+            .collect::<HashSet<_>>();
+        let (mut nodes, mut leafs) = (0, 0);
+        let mut pending = vec![tptr];
+        while let Some(tptr) = pending.pop() {
+            if !visited.insert(tptr) {
+                continue;
+            }
+            let tref = &*tptr;
+            pending.extend(tref.src.iter().filter(|src| !src.is_null()));
+            if tref.op == gg::ggml_op_GGML_OP_NONE && tref.grad.is_null() {
+                leafs += 1;
+            } else {
+                nodes += 1;
+            }
+        }
+        (nodes, leafs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops;
+
+    use anyhow::Result;
+
+    use super::*;
+
+    // A buffer that starts `skip` bytes after a `GGML_MEM_ALIGN` boundary. A `Vec<u8>`
+    // is only guaranteed to be aligned to one byte.
+    struct OffsetBuffer {
+        buf: Vec<u8>,
+        start: usize,
+        len: usize,
+    }
+
+    impl OffsetBuffer {
+        fn new(len: usize, skip: usize) -> Self {
+            let align = gg::GGML_MEM_ALIGN as usize;
+            let buf = vec![0u8; len + align + skip];
+            let start = buf.as_ptr().align_offset(align) + skip;
+            Self { buf, start, len }
+        }
+    }
+
+    impl ops::Deref for OffsetBuffer {
+        type Target = [u8];
+
+        fn deref(&self) -> &Self::Target {
+            &self.buf[self.start..self.start + self.len]
+        }
+    }
+
+    impl ops::DerefMut for OffsetBuffer {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.buf[self.start..self.start + self.len]
+        }
+    }
+
+    #[test]
+    fn test_mem_buffer() -> Result<()> {
+        let buf = OffsetBuffer::new(1024 * 1024, 0);
+        let bufptr = buf.as_ptr();
+        let ctx = GContextBuilder::new().mem_buffer(buf).build()?;
+        let mut g = GGraph::new(1);
+        let mut t = ctx.tensor(GType::F32, [3])?;
+        t.populate_f32([2.0, 2.0, 2.0]);
+        let t2 = t.sqr();
+        g.build_forward_expand(&t2)?;
+        ctx.compute(&mut g)?;
+        let mut output = [0.0; 3];
+        t2.copy_to_slice_f32(&mut output)?;
+        assert_eq!(output, [4.0, 4.0, 4.0]);
+        ctx.with_icontext_infallible(|ictx| unsafe {
+            assert_eq!(gg::ggml_get_mem_buffer(ictx.gptr()) as *const u8, bufptr);
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_buffer_misaligned() {
+        let result = GContextBuilder::new()
+            .mem_buffer(OffsetBuffer::new(1024, 1))
+            .build();
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GContextError>()),
+            Err(Ok(GContextError::MisalignedMemBuffer(_)))
+        ));
+    }
+
+    #[test]
+    fn test_scratch_buffer_reuse() -> Result<()> {
+        let mut ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let mut a = ctx.tensor(GType::F32, [256])?;
+        a.fill_f32(2.0);
+        let bufid = ctx.register_scratch_buffer(ScratchBuffer::new(4096))?;
+        ctx.set_scratch_buffer(Some(bufid))?;
+        let b = a.sqr();
+        assert!(matches!(
+            ctx.reset_scratch_buffer(bufid)
+                .map_err(|e| e.downcast::<GContextError>()),
+            Err(Ok(GContextError::ScratchBufferInUse(0)))
+        ));
+        let b2 = b.clone();
+        drop(b);
+        assert!(ctx
+            .replace_scratch_buffer(bufid, ScratchBuffer::new(8192))
+            .is_err());
+        drop(b2);
+
+        ctx.reset_scratch_buffer(bufid)?;
+        ctx.replace_scratch_buffer(bufid, ScratchBuffer::new(8192))?;
+        let b = a.sqr();
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&b)?;
+        ctx.compute(&mut g)?;
+        assert_eq!(b.get_f32_1d(0)?, 4.0);
+        drop(b);
+
+        ctx.remove_scratch_buffer(bufid)?;
+        assert!(ctx.set_scratch_buffer(Some(bufid)).is_err());
+        assert!(ctx.remove_scratch_buffer(bufid).is_err());
+        // Without a scratch buffer the result goes in the context memory.
+        let c = a.sqr();
+        assert_eq!(ctx.register_scratch_buffer(ScratchBuffer::new(16))?, 1);
+        assert!(ctx
+            .with_icontext(|_ctx, ictx| Ok(
+                unsafe { ictx.scratch_ref(c.tptr.as_ptr()) }.is_none()
+            ))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_report() -> Result<()> {
+        let mut ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let small = ctx.tensor(GType::F32, [16])?;
+        let big = ctx.tensor(GType::F32, [4096])?;
+        let bufid = ctx.register_scratch_buffer(ScratchBuffer::new(32 * 1024))?;
+        ctx.set_scratch_buffer(Some(bufid))?;
+        let _sq = big.sqr();
+        ctx.set_scratch_buffer(None)?;
+        let _ = small.sqr();
+        assert!(ctx.tensor(GType::F32, [16 * 1024]).is_err());
+
+        let report = ctx.memory_report(2)?;
+        assert_eq!(report.tensors, 4);
+        assert_eq!(report.context_size, 64 * 1024);
+        assert_eq!(report.top_consumers.len(), 2);
+        let biggest = &report.top_consumers[..];
+        assert!(biggest.iter().all(|rec| rec.ggml_ne == [4096, 1, 1, 1]));
+        assert_eq!(biggest[0].scratch_buffer, None);
+        assert_eq!(biggest[1].scratch_buffer, Some(bufid));
+        assert_eq!(biggest[1].op, GOp::Sqr);
+        assert_eq!(report.scratch[0].peak, biggest[1].scratch_bytes);
+        assert!(report.to_string().contains("SQR"));
+
+        let log = ctx.allocation_log()?;
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[0].ggml_ne, [16, 1, 1, 1]);
+        assert_eq!(
+            log.iter().map(|rec| rec.ctx_bytes).sum::<usize>(),
+            report.context_used
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tensor_names() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let mut w = ctx.tensor(GType::F32, [2, 3])?;
+        assert_eq!(w.name()?, "");
+        w.set_name("layer.0.weight")?;
+        w.fill_f32(1.5);
+        let mut out = w.sqr();
+        out.set_name("out")?;
+        assert!(w.set_name(&"x".repeat(64)).is_err());
+        assert!(w.set_name("a\0b").is_err());
+
+        let found = ctx.get_tensor::<2>("layer.0.weight")?;
+        assert!(found == w);
+        assert_eq!(found.shape(), w.shape());
+        assert_eq!(found.get_f32_1d(0)?, 1.5);
+        assert!(ctx.get_tensor::<2>("out")? == out);
+        assert!(matches!(
+            ctx.get_tensor::<3>("out")
+                .map_err(|e| e.downcast::<crate::gtensor::GTensorError>()),
+            Err(Ok(crate::gtensor::GTensorError::DimensionMismatch {
+                got: 2,
+                expected: 3
+            }))
+        ));
+        assert!(matches!(
+            ctx.get_tensor::<2>("missing")
+                .map_err(|e| e.downcast::<GContextError>()),
+            Err(Ok(GContextError::TensorNotFound(_)))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tensor_iteration() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        assert_eq!(ctx.tensors()?.count(), 0);
+        let mut w = ctx.tensor(GType::F32, [2, 3])?;
+        w.set_name("w")?;
+        let b = ctx.tensor(GType::Q8_0, [4, 32])?;
+        let r = w.reshape([3, 2]);
+        let _sum = r.sqr();
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&b)?;
+
+        let tensors = ctx.tensors()?.collect::<Vec<_>>();
+        assert_eq!(tensors.len(), 4);
+        assert_eq!(tensors[0].name, "w");
+        assert_eq!(tensors[0].tensor.shape(), w.shape().to_vec());
+        assert_eq!(tensors[1].tensor.element_type(), GType::Q8_0);
+        assert_eq!(tensors[2].op, GOp::Reshape);
+        assert!(tensors[2].is_view);
+        assert_eq!(tensors[2].tensor.get_ne(), [2, 3, 1, 1]);
+        assert_eq!(tensors[3].op, GOp::Sqr);
+        assert!(!tensors[3].is_view);
+        let params = tensors
+            .iter()
+            .filter(|ct| ct.op == GOp::None)
+            .map(|ct| ct.tensor.elements())
+            .sum::<usize>();
+        assert_eq!(params, 6 + 128);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_rewind() -> Result<()> {
+        let mut ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let mut w = ctx.tensor(GType::F32, [1024])?;
+        w.fill_f32(3.0);
+        let bufid = ctx.register_scratch_buffer(ScratchBuffer::new(16 * 1024))?;
+        let mark = ctx.mark()?;
+        let used = ctx.used_mem()?;
+
+        // Without rewinding, the scratch buffer would run out of memory on the fourth step.
+        for _ in 0..16 {
+            ctx.set_scratch_buffer(Some(bufid))?;
+            let sq = w.sqr();
+            ctx.set_scratch_buffer(None)?;
+            let out = &sq + &w;
+            let mut g = GGraph::new(1);
+            g.build_forward_expand(&out)?;
+            ctx.compute(&mut g)?;
+            assert_eq!(out.get_f32_1d(0)?, 12.0);
+            let inner = ctx.mark()?;
+
+            ctx.rewind(&mark)?;
+            assert_eq!(ctx.used_mem()?, used);
+            assert_eq!(ctx.tensors()?.count(), 1);
+            assert!(matches!(
+                out.get_f32_1d(0).map_err(|e| e.downcast::<GContextError>()),
+                Err(Ok(GContextError::InvalidatedTensor))
+            ));
+            assert!(ctx.rewind(&inner).is_err());
+        }
+        assert_eq!(w.get_f32_1d(0)?, 3.0);
+        assert_eq!(ctx.memory_report(0)?.tensors, 1);
+
+        let other = GContextBuilder::new().mem_size(1024).build()?;
+        assert!(ctx.rewind(&other.mark()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_context_failure() -> Result<()> {
+        // Room for the inputs but not the result.
+        let ctx = GContextBuilder::new().mem_size(160 * 1024).build()?;
+        let a = ctx.tensor(GType::F32, [4, 4096])?;
+        let b = ctx.tensor(GType::F32, [4, 4096])?;
+        let _ = &a + &b;
+
+        let mut g = GGraph::new(1);
+        let err = g.build_forward_expand(&a).unwrap_err();
+        let failure = match err.downcast::<GContextError>()? {
+            GContextError::DeadContext(failure) => failure,
+            e => panic!("Unexpected error {e:?}"),
+        };
+        assert_eq!(failure.op, "add");
+        assert_eq!(failure.inputs.len(), 2);
+        assert_eq!(failure.inputs[1].typ, GType::F32);
+        assert_eq!(failure.inputs[1].ggml_ne, [4096, 4, 1, 1]);
+        let mr = failure.memory_request.expect("Missing memory request");
+        assert!(!mr.fits);
+        assert!(matches!(
+            failure.error.downcast_ref::<GContextError>(),
+            Some(GContextError::InsufficientMemory(_))
+        ));
+        assert!(failure.to_string().starts_with("add(F32 [4096, 4, 1, 1]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover() -> Result<()> {
+        // Room for the inputs but not the result.
+        let mut ctx = GContextBuilder::new().mem_size(160 * 1024).build()?;
+        let mut a = ctx.tensor(GType::F32, [4, 4096])?;
+        a.populate_f32(vec![2.0; 4 * 4096]);
+        let b = ctx.tensor(GType::F32, [4, 4096])?;
+        let used = ctx.used_mem()?;
+        assert!(ctx.recover()?.is_none());
+
+        let dead = &a + &b;
+        {
+            // Pretend the failed operation left an object behind.
+            let ictx = ctx.ictx.lock().unwrap();
+            unsafe { gg::ggml_new_tensor_1d(ictx.gptr(), GType::F32 as u32, 16) };
+        }
+        let _ = dead.sqr();
+        assert!(ctx.tensor(GType::F32, [1]).is_err());
+
+        let failure = ctx.recover()?.expect("Missing failure");
+        assert_eq!(failure.op, "add");
+        assert_eq!(ctx.used_mem()?, used);
+        assert!(matches!(
+            dead.get_f32_1d(0)
+                .map_err(|e| e.downcast::<GContextError>()),
+            Err(Ok(GContextError::InvalidatedTensor))
+        ));
+
+        let t = a.view([4], [0]).sqr();
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+        ctx.compute(&mut g)?;
+        assert_eq!(t.get_f32_1d(3)?, 4.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Result<()> {
+        let weights = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let mut w = weights.tensor(GType::F32, [2, 3])?;
+        w.populate_f32([1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let mut view = ctx.import_view(&w)?;
+        assert_eq!(view.shape(), w.shape());
+        assert!(view.try_fill_f32(0.0).is_err());
+        let mut copy = ctx.import_copy(&w)?;
+        copy.fill_f32(0.0);
+
+        // The view shares data with the original, the copy doesn't.
+        w.set_f32_1d(0, 10.0);
+        drop(weights);
+        assert_eq!(view.get_f32_1d(0)?, 10.0);
+        assert_eq!(copy.get_f32_1d(0)?, 0.0);
+
+        let copy = ctx.import_copy(&w)?;
+        let mut output = [0.0; 6];
+        copy.copy_to_slice_f32(&mut output)?;
+        assert_eq!(output, [10.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        // Too small for the tensor object. The request has the shape in GGML order.
+        let tiny = GContextBuilder::new().mem_size(64).build()?;
+        let err = tiny.import_view(&w).err().unwrap();
+        match err.downcast_ref::<GContextError>() {
+            Some(GContextError::InsufficientMemory(mr)) => assert!(matches!(
+                mr.reqtype,
+                GMemoryRequestType::MappedTensor {
+                    shape: [3, 2, 1, 1],
+                    ..
+                }
+            )),
+            e => panic!("Unexpected error {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_errors() -> Result<()> {
+        let weights = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let w = weights.tensor(GType::F32, [3])?;
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let x = ctx.tensor(GType::F32, [3])?;
+
+        // The result of an operation would be imported before it's computed.
+        let sqr = w.try_sqr()?;
+        let err = ctx
+            .import_view(&sqr)
+            .err()
+            .expect("Expected import to fail");
+        assert!(matches!(
+            err.downcast_ref::<GContextError>(),
+            Some(GContextError::ImportNotLeaf(GOp::Sqr))
+        ));
+        match x.try_add(&sqr) {
+            Err(GTensorError::Failed(failure)) => assert!(matches!(
+                failure.error.downcast_ref::<GContextError>(),
+                Some(GContextError::ImportNotLeaf(GOp::Sqr))
+            )),
+            _ => panic!("Expected add to fail"),
+        }
+
+        // Importing in both directions would keep both contexts alive forever.
+        let _view = ctx.import_view(&w)?;
+        let err = weights
+            .import_view(&x)
+            .err()
+            .expect("Expected import to fail");
+        assert!(matches!(
+            err.downcast_ref::<GContextError>(),
+            Some(GContextError::CyclicImport)
+        ));
+        assert!(w.try_add(&x).is_err());
+        assert!(x.try_add(&w).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "single_threaded"))]
+    fn test_frozen_context() -> Result<()> {
+        use crate::gtensor::GMulMat;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<GFrozenContext>();
+
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let mut w = ctx.tensor(GType::F32, [2, 3])?;
+        w.populate_f32([1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        w.set_name("weight")?;
+        let mut w2 = w.clone();
+        let weights = ctx.freeze()?;
+
+        let workers = (1..=4)
+            .map(|i| {
+                let weights = weights.clone();
+                std::thread::spawn(move || -> Result<[f32; 2]> {
+                    let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+                    let w = weights.get_tensor::<2>("weight")?;
+                    let mut x = ctx.tensor(GType::F32, [1, 3])?;
+                    x.fill_f32(i as f32);
+                    let t = w.mul_mat(&x);
+                    let mut g = GGraph::new(1);
+                    g.build_forward_expand(&t)?;
+                    ctx.compute(&mut g)?;
+                    let mut output = [0.0; 2];
+                    t.copy_to_slice_f32(&mut output)?;
+                    Ok(output)
+                })
+            })
+            .collect::<Vec<_>>();
+        for (i, worker) in (1..=4).zip(workers) {
+            let i = i as f32;
+            assert_eq!(worker.join().unwrap()?, [6.0 * i, 15.0 * i]);
+        }
+
+        assert_eq!(w2.get_f32_1d(1)?, 2.0);
+        assert!(w2.try_fill_f32(0.0).is_err());
+        match w2.try_sqr() {
+            Err(GTensorError::Failed(failure)) => assert!(matches!(
+                failure.error.downcast_ref::<GContextError>(),
+                Some(GContextError::Frozen)
+            )),
+            _ => panic!("Expected frozen context failure"),
+        }
+        // Failed operations don't affect the frozen context.
+        assert_eq!(weights.tensors()?.count(), 1);
+
+        // Only the tensors the operations returned or modified report the failure.
+        let frozen_failure =
+            |result: Result<f32>| match result.map_err(|e| e.downcast::<GContextError>()) {
+                Err(Ok(GContextError::DeadContext(failure))) => failure.op,
+                _ => panic!("Expected dead tensor"),
+            };
+        let w3 = weights.get_tensor::<2>("weight")?;
+        let sqr = w2.sqr();
+        assert_eq!(frozen_failure(sqr.get_f32_1d(0)), "sqr");
+        assert_eq!(frozen_failure(sqr.transpose().get_f32_1d(0)), "sqr");
+        assert_eq!(frozen_failure((&w2 + &w3).get_f32_1d(0)), "add");
+        w2.fill_f32(0.0);
+        assert_eq!(frozen_failure(w2.get_f32_1d(0)), "fill_f32");
+        assert_eq!(w3.get_f32_1d(1)?, 2.0);
+        assert_eq!(weights.get_tensor::<2>("weight")?.get_f32_1d(1)?, 2.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_grow() -> Result<()> {
+        let mut ctx = GContextBuilder::new()
+            .mem_size(4 * 1024)
+            .auto_grow(true)
+            .build()?;
+        let mut a = ctx.tensor(GType::F32, [512])?;
+        a.populate_f32(vec![2.0; 512]);
+        a.set_name("a")?;
+        let mark = ctx.mark()?;
+        let mut b = ctx.tensor(GType::F32, [2048])?;
+        b.fill_f32(3.0);
+        let c = a.view([512], [0]) * b.view([512], [0]);
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&c)?;
+        ctx.compute(&mut g)?;
+        let mut output = vec![0.0; 512];
+        c.copy_to_slice_f32(&mut output)?;
+        assert!(output.iter().all(|v| *v == 6.0));
+
+        let arenas = ctx.with_icontext(|_ctx, ictx| Ok(ictx.old_arenas.len() + 1))?;
+        assert!(arenas > 1);
+        let report = ctx.memory_report(0)?;
+        assert!(report.context_size > 4 * 1024);
+        assert_eq!(ctx.used_mem()?, report.ggml_used);
+        assert_eq!(ctx.tensors()?.count(), 5);
+        assert_eq!(ctx.get_tensor::<1>("a")?.get_f32_1d(0)?, 2.0);
+
+        // Rewinding past the start of an arena frees it.
+        ctx.rewind(&mark)?;
+        assert_eq!(ctx.memory_report(0)?.context_size, 4 * 1024);
+        assert!(b.get_f32_1d(0).is_err());
+        assert_eq!(a.get_f32_1d(0)?, 2.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_measure_and_build() -> Result<()> {
+        let build = |ctx: &mut GContext| -> Result<GTensor<1>> {
+            let mut a = ctx.tensor(GType::F32, [1024])?;
+            a.populate_f32(vec![3.0; 1024]);
+            let bufid = ctx.register_scratch_buffer(ScratchBuffer::new(0))?;
+            ctx.set_scratch_buffer(Some(bufid))?;
+            drop(a.sqr().sqrt().sqr());
+            ctx.reset_scratch_buffer(bufid)?;
+            let b = a.sqr().sqrt();
+            ctx.set_scratch_buffer(None)?;
+            Ok(a + b)
+        };
+
+        let (measurement, _) = GContext::measure(build)?;
+        assert!(measurement.context_size > 2 * 1024 * 4);
+        assert_eq!(measurement.scratch_sizes[0], 3 * 1024 * 4);
+
+        let (ctx, t) = GContext::measure_and_build(build)?;
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+        ctx.compute(&mut g)?;
+        let mut output = vec![0.0; 1024];
+        t.copy_to_slice_f32(&mut output)?;
+        assert!(output.iter().all(|v| *v == 6.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_overflow() -> Result<()> {
+        let max = gg::GGML_MAX_NODES as usize;
+        let ctx = GContextBuilder::new().mem_size(4 * 1024 * 1024).build()?;
+        let x = ctx.tensor(GType::F32, [4])?;
+        let mut t = x.sqr();
+        for _ in 1..max {
+            t = t.neg();
+        }
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+        assert_eq!((g.n_nodes(), g.n_leafs()), (max, 1));
+
+        let result = g.build_forward_expand(t.neg());
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GContextError>()),
+            Err(Ok(GContextError::GraphOverflow { nodes, leafs: 1, .. })) if nodes == max + 1
+        ));
+        assert_eq!((g.n_nodes(), g.n_leafs()), (max, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_tensors() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let mut a = ctx.tensor(GType::F32, [3])?;
+        a.set_name("a")?;
+        let b = ctx.tensor(GType::F32, [3])?;
+        let mut t = (&a * &b).sqr().relu();
+        t.set_name("result")?;
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+
+        let leafs = g.leafs()?.map(|gt| gt.name).collect::<Vec<_>>();
+        assert_eq!(leafs, ["a", "leaf_1"]);
+
+        let nodes = g.nodes()?.collect::<Vec<_>>();
+        let ops = nodes.iter().map(|gt| gt.op).collect::<Vec<_>>();
+        assert_eq!(ops, [GOp::Mul, GOp::Sqr, GOp::Unary]);
+        assert_eq!(nodes[2].unary_op, Some(GUnaryOp::Relu));
+        let sources = |gt: &GGraphTensor| {
+            gt.sources
+                .iter()
+                .map(|src| src.name())
+                .collect::<Result<Vec<_>>>()
+        };
+        assert_eq!(sources(&nodes[0])?, ["a", "leaf_1"]);
+        assert_eq!(sources(&nodes[2])?, ["node_1"]);
+        assert_eq!(nodes[2].name, "result");
+        assert_eq!(nodes[1].metadata::<1>()?.shape, [3]);
+        assert!(nodes[1].metadata::<2>().is_err());
+        Ok(())
+    }
+    #[test]
+    fn test_graph_to_dot() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let mut a = ctx.tensor(GType::F32, [2, 3])?;
+        a.set_name("a")?;
+        let mut w = ctx.tensor(GType::F32, [2, 3])?;
+        w.set_name("w \"weights\"")?;
+        w.with_tensor_infallible(|_ctx, _ictx, tptr| unsafe { (*tptr).is_param = true })?;
+        let mut t = (&a * &w).relu().transpose();
+        t.set_name("out")?;
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+
+        let expected = r#"digraph G {
+  rankdir = TB;
+  node [shape = box];
+  n0 [label = "node_0\nMUL F32 [2, 3]"];
+  n1 [label = "node_1\nUNARY(RELU) F32 [2, 3]"];
+  n2 [label = "out\nTRANSPOSE F32 [3, 2]"];
+  l0 [label = "a\nNONE F32 [2, 3]", style = filled, fillcolor = pink];
+  l1 [label = "w \"weights\"\nNONE F32 [2, 3]", style = filled, fillcolor = yellow];
+  l0 -> n0 [label = "0"];
+  l1 -> n0 [label = "1"];
+  n0 -> n1 [label = "0"];
+  n1 -> n2 [label = "0"];
+}
+"#;
+        assert_eq!(g.to_dot()?, expected);
+        Ok(())
     }
 }
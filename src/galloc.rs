@@ -0,0 +1,376 @@
+use std::{ffi::c_void, ptr::NonNull};
+
+use anyhow::{ensure, Result};
+use thiserror::Error;
+
+use ggml_sys_bleedingedge as gg;
+
+use crate::context::{GContext, GContextBuilder, GGraph};
+
+// The graph allocator is part of the GGML library but the sys crate
+// doesn't generate bindings for `ggml-alloc.h`.
+#[repr(C)]
+struct ggml_allocr {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn ggml_allocr_new(data: *mut c_void, size: usize, alignment: usize) -> *mut ggml_allocr;
+    fn ggml_allocr_new_measure(alignment: usize) -> *mut ggml_allocr;
+    fn ggml_allocr_free(alloc: *mut ggml_allocr);
+    fn ggml_allocr_reset(alloc: *mut ggml_allocr);
+    fn ggml_allocr_alloc(alloc: *mut ggml_allocr, tensor: *mut gg::ggml_tensor);
+    fn ggml_allocr_alloc_graph(alloc: *mut ggml_allocr, graph: *mut gg::ggml_cgraph) -> usize;
+}
+
+#[derive(Debug, Error, Clone)]
+pub enum GAllocatorError {
+    #[error("Graph allocator alignment must be a power of two, got {0}")]
+    BadAlignment(usize),
+
+    #[error("Not enough graph allocator memory: need {need} bytes, have {have}")]
+    InsufficientMemory { need: usize, have: usize },
+
+    #[error("Graph has already been allocated")]
+    AlreadyAllocated,
+
+    #[error("Failed to create GGML graph allocator")]
+    CreationFailed,
+}
+
+/// GGML graph allocator. Contexts built with one only use their own memory
+/// for GGML objects: tensor data is placed in a single buffer owned by the
+/// allocator. When a [GGraph] is allocated with [GContext::alloc_graph],
+/// memory for intermediate results is reused as soon as nothing else in
+/// the graph needs them.
+///
+/// Tensors created directly with [GContext::tensor] (for example inputs) are
+/// allocated immediately so they can be populated before the graph is built.
+///
+/// The allocator's memory is only freed by rewinding the context with
+/// [GContext::rewind], which makes it possible to run a sequence of graphs
+/// in the same buffer: Save a mark before building each graph and rewind
+/// to it once the graph's results are no longer needed.
+///
+/// **Note**: Only the graph outputs (nodes nothing else depends on) are
+/// guaranteed to still contain their results after the graph is computed.
+///
+/// Example:
+/// ```rust,ignore
+/// // Pass 1: Find out how much memory the graph needs.
+/// let size = GGraphAllocator::measure(ctx_size, |ctx, graph| build_graph(ctx, graph))?;
+/// // Pass 2: Actually build and run it.
+/// let ctx = GContextBuilder::new()
+///     .mem_size(ctx_size)
+///     .graph_allocator(GGraphAllocator::new(size)?)
+///     .build()?;
+/// let mut graph = GGraph::new(1);
+/// build_graph(&ctx, &mut graph)?;
+/// ctx.compute(&mut graph)?;
+/// ```
+pub struct GGraphAllocator {
+    aptr: NonNull<ggml_allocr>,
+    // Buffer the allocator places tensor data in. `None` when measuring.
+    buf: Option<Box<[u8]>>,
+    alignment: usize,
+    // Usable bytes in the buffer after aligning its start.
+    capacity: usize,
+    // Upper bound on the highest offset the allocator has handed out.
+    high_water: usize,
+}
+
+// The allocator is only ever accessed with the context mutex held.
+unsafe impl Send for GGraphAllocator {}
+
+impl Drop for GGraphAllocator {
+    fn drop(&mut self) {
+        unsafe { ggml_allocr_free(self.aptr.as_ptr()) }
+    }
+}
+
+impl GGraphAllocator {
+    /// Default tensor data alignment, the same value `llama.cpp` uses.
+    pub const DEFAULT_ALIGNMENT: usize = 32;
+
+    /// Create a graph allocator with a buffer of the specified size in bytes.
+    pub fn new(size: usize) -> Result<Self> {
+        Self::new_with_alignment(size, Self::DEFAULT_ALIGNMENT)
+    }
+
+    /// Create a graph allocator with a buffer of the specified size in bytes
+    /// and tensor data alignment.
+    ///
+    /// **Invariants**
+    /// 1. `alignment` must be a power of two.
+    pub fn new_with_alignment(size: usize, alignment: usize) -> Result<Self> {
+        ensure!(
+            alignment.is_power_of_two(),
+            GAllocatorError::BadAlignment(alignment)
+        );
+        // Extra space so the usable part can start at an aligned address.
+        let mut buf = vec![0u8; size + alignment].into_boxed_slice();
+        let capacity = buf.len() - buf.as_ptr().align_offset(alignment);
+        let aptr =
+            unsafe { ggml_allocr_new(buf.as_mut_ptr() as *mut c_void, buf.len(), alignment) };
+        Ok(Self {
+            aptr: NonNull::new(aptr).ok_or(GAllocatorError::CreationFailed)?,
+            buf: Some(buf),
+            alignment,
+            capacity,
+            high_water: 0,
+        })
+    }
+
+    /// Create a graph allocator that only measures how much memory would be
+    /// required. Contexts built with a measuring allocator behave as if
+    /// they were `no_alloc`: see [GContextBuilder::no_alloc].
+    pub fn new_measure() -> Result<Self> {
+        let alignment = Self::DEFAULT_ALIGNMENT;
+        let aptr = unsafe { ggml_allocr_new_measure(alignment) };
+        Ok(Self {
+            aptr: NonNull::new(aptr).ok_or(GAllocatorError::CreationFailed)?,
+            buf: None,
+            alignment,
+            capacity: usize::MAX,
+            high_water: 0,
+        })
+    }
+
+    /// Returns the buffer size required to allocate the graph built by the
+    /// supplied function, which is called with a context using a
+    /// measuring graph allocator and an empty graph. `mem_size` is the context size and only
+    /// needs to be large enough for the GGML objects.
+    ///
+    /// The result can be passed to [Self::new].
+    pub fn measure<F>(mem_size: usize, fun: F) -> Result<usize>
+    where
+        F: FnOnce(&GContext, &mut GGraph) -> Result<()>,
+    {
+        let ctx = GContextBuilder::new()
+            .mem_size(mem_size)
+            .graph_allocator(Self::new_measure()?)
+            .build()?;
+        let mut graph = GGraph::new(1);
+        fun(&ctx, &mut graph)?;
+        ctx.alloc_graph(&mut graph)
+    }
+
+    /// Returns `true` if this allocator only measures memory requirements.
+    pub fn is_measure(&self) -> bool {
+        self.buf.is_none()
+    }
+
+    /// Returns the tensor data alignment.
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    /// Returns the size of the allocator's buffer in bytes, or `0` when measuring.
+    pub fn size(&self) -> usize {
+        self.buf.as_ref().map_or(0, |buf| buf.len())
+    }
+
+    /// Returns the amount of buffer memory in use.
+    pub fn used(&self) -> usize {
+        self.high_water
+    }
+
+    /// Fails if allocating `size` bytes of tensor data could exhaust the buffer.
+    pub(crate) fn ensure_fits(&self, size: usize) -> Result<()> {
+        // Everything allocated so far is below `high_water` so the final
+        // free block always has room for the data if this passes.
+        let need = self.high_water + size.next_multiple_of(self.alignment);
+        ensure!(
+            need <= self.capacity,
+            GAllocatorError::InsufficientMemory {
+                need,
+                have: self.capacity
+            }
+        );
+        Ok(())
+    }
+
+    /// # Safety
+    /// Must be called with context mutex held after checking the tensor fits
+    /// with [Self::ensure_fits]. The tensor must not be a view and must not
+    /// already have data.
+    pub(crate) unsafe fn alloc_tensor(&mut self, tptr: *mut gg::ggml_tensor) {
+        ggml_allocr_alloc(self.aptr.as_ptr(), tptr);
+        self.high_water += gg::ggml_nbytes(tptr).next_multiple_of(self.alignment);
+    }
+
+    /// Frees all buffer memory past the first `used` bytes.
+    ///
+    /// Nothing placed by the allocator is ever freed otherwise, so its memory is
+    /// always one used block followed by one free block. Resetting and reserving
+    /// `used` bytes again restores the state it was in when that much was in use.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held. `used` must be a value previously
+    /// returned by [Self::used] and no live tensor may have data past it.
+    pub(crate) unsafe fn truncate(&mut self, used: usize) {
+        ggml_allocr_reset(self.aptr.as_ptr());
+        self.high_water = 0;
+        if used > 0 {
+            let mut block = Self::placeholder_tensor(used);
+            self.alloc_tensor(&mut block);
+        }
+    }
+
+    /// Places the data of the graph's tensors, returning the amount of buffer
+    /// memory used so far.
+    ///
+    /// GGML aborts when the buffer is too small, so the graph is first placed by
+    /// a separate measuring allocator. If the result fits, a single block of that
+    /// size is reserved and the tensors are moved into it. Tensors allocated with
+    /// [Self::alloc_tensor] are outside the measuring allocator's buffer, so their
+    /// memory is never reused for the graph.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held.
+    pub(crate) unsafe fn alloc_graph(&mut self, graph: &mut gg::ggml_cgraph) -> Result<usize> {
+        let measure = Self::new_measure()?;
+        // Marks where the measuring allocator's buffer starts.
+        let mut start = Self::placeholder_tensor(1);
+        ggml_allocr_alloc(measure.aptr.as_ptr(), &mut start);
+        let start = start.data as usize + measure.alignment;
+        let size = ggml_allocr_alloc_graph(measure.aptr.as_ptr(), graph) - measure.alignment;
+        self.ensure_fits(size)?;
+        if size == 0 {
+            return Ok(self.high_water);
+        }
+
+        let mut block = Self::placeholder_tensor(size);
+        self.alloc_tensor(&mut block);
+        let (n_nodes, n_leafs) = (graph.n_nodes as usize, graph.n_leafs as usize);
+        let tensors = graph.nodes[..n_nodes]
+            .iter()
+            .chain(graph.leafs[..n_leafs].iter());
+        for tref in tensors.filter_map(|tptr| tptr.as_mut()) {
+            let offset = (tref.data as usize).wrapping_sub(start);
+            if offset < size {
+                tref.data = (block.data as *mut u8).add(offset) as *mut c_void;
+            }
+        }
+        Ok(self.high_water)
+    }
+
+    // A tensor object that's only used to reserve `size` bytes of allocator memory.
+    fn placeholder_tensor(size: usize) -> gg::ggml_tensor {
+        let mut tensor = unsafe { std::mem::zeroed::<gg::ggml_tensor>() };
+        tensor.type_ = gg::ggml_type_GGML_TYPE_I8;
+        tensor.ne = [size as i64, 1, 1, 1];
+        tensor.nb = [1, size, size, size];
+        tensor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{gtensor::GTensor, util::GType};
+
+    const CTX_SIZE: usize = 256 * 1024;
+    const ELEMENTS: usize = 4096;
+
+    fn build_graph(ctx: &GContext, graph: &mut GGraph) -> Result<GTensor<1>> {
+        let mut a = ctx.tensor(GType::F32, [ELEMENTS])?;
+        a.populate_f32(vec![2.0; ELEMENTS]);
+        let mut t = a.clone();
+        for _ in 0..16 {
+            t = t.sqr().sqrt() + &a;
+        }
+        graph.build_forward_expand(&t)?;
+        Ok(t)
+    }
+
+    #[test]
+    fn test_graph_allocator() -> Result<()> {
+        let size = GGraphAllocator::measure(CTX_SIZE, |ctx, g| build_graph(ctx, g).map(|_| ()))?;
+        // 49 tensors were created, but only a few need to be live at once.
+        assert!(size < 8 * ELEMENTS * 4);
+
+        let ctx = GContextBuilder::new()
+            .mem_size(CTX_SIZE)
+            .graph_allocator(GGraphAllocator::new(size)?)
+            .build()?;
+        let mut g = GGraph::new(1);
+        let t = build_graph(&ctx, &mut g)?;
+        assert!(matches!(
+            t.get_f32_1d(0)
+                .map_err(|e| e.downcast::<crate::gtensor::GTensorError>()),
+            Err(Ok(crate::gtensor::GTensorError::Unallocated))
+        ));
+        ctx.compute(&mut g)?;
+        let mut output = vec![0.0; ELEMENTS];
+        t.copy_to_slice_f32(&mut output)?;
+        assert!(output.iter().all(|v| *v == 34.0));
+        assert!(ctx.alloc_graph(&mut g).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_allocator_sequence() -> Result<()> {
+        let size = GGraphAllocator::measure(CTX_SIZE, |ctx, g| build_graph(ctx, g).map(|_| ()))?;
+        let mut ctx = GContextBuilder::new()
+            .mem_size(CTX_SIZE)
+            .graph_allocator(GGraphAllocator::new(size)?)
+            .build()?;
+        let mark = ctx.mark()?;
+
+        // Each graph needs the whole buffer, so this only works if rewinding frees it.
+        for _ in 0..4 {
+            let mut g = GGraph::new(1);
+            let t = build_graph(&ctx, &mut g)?;
+            assert_eq!(ctx.alloc_graph(&mut g)?, size);
+            ctx.compute(&mut g)?;
+            assert_eq!(t.get_f32_1d(0)?, 34.0);
+            ctx.rewind(&mark)?;
+        }
+
+        // Without rewinding there's no room left for another input.
+        let mut g = GGraph::new(1);
+        build_graph(&ctx, &mut g)?;
+        ctx.compute(&mut g)?;
+        let result = ctx.tensor(GType::F32, [ELEMENTS]);
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GAllocatorError>()),
+            Err(Ok(GAllocatorError::InsufficientMemory { .. }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_allocator_too_small() -> Result<()> {
+        let ctx = GContextBuilder::new()
+            .mem_size(CTX_SIZE)
+            .graph_allocator(GGraphAllocator::new(ELEMENTS)?)
+            .build()?;
+        let result = ctx.tensor(GType::F32, [ELEMENTS]);
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GAllocatorError>()),
+            Err(Ok(GAllocatorError::InsufficientMemory { .. }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_allocator_graph_too_big() -> Result<()> {
+        let size = GGraphAllocator::measure(CTX_SIZE, |ctx, g| build_graph(ctx, g).map(|_| ()))?;
+        // Room for the input but not the intermediate results.
+        let ctx = GContextBuilder::new()
+            .mem_size(CTX_SIZE)
+            .graph_allocator(GGraphAllocator::new(ELEMENTS * 4)?)
+            .build()?;
+        let mut g = GGraph::new(1);
+        build_graph(&ctx, &mut g)?;
+        let result = ctx.compute(&mut g);
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GAllocatorError>()),
+            Err(Ok(GAllocatorError::InsufficientMemory { need, have })) if need == size && have >= ELEMENTS * 4
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+mod reader;
+mod value;
+mod writer;
+
+pub use reader::*;
+pub use value::*;
+pub use writer::*;
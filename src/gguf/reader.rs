@@ -0,0 +1,659 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Result};
+use num_traits::FromPrimitive;
+use thiserror::Error;
+
+use ggml_sys_bleedingedge as gg;
+
+use super::value::*;
+use crate::{
+    context::{GContext, GContextBuilder},
+    gtensor::GAnyTensor,
+    util::GType,
+    validation::GMemoryRequest,
+};
+
+/// Metadata key used to override the default tensor data alignment.
+pub const GGUF_KEY_ALIGNMENT: &str = "general.alignment";
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum GgufError {
+    #[error("Bad magic {0:#010x}, not a GGUF file")]
+    BadMagic(u32),
+
+    #[error("Unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Unknown metadata value type {0}")]
+    UnknownValueType(u32),
+
+    #[error("Metadata key {0} is not valid UTF-8")]
+    BadString(String),
+
+    #[error("Duplicate metadata key {0}")]
+    DuplicateKey(String),
+
+    #[error("Metadata key {0}: array items must all have the same type")]
+    BadArray(String),
+
+    #[error("Metadata arrays can't be nested more than {0} deep")]
+    ArrayTooDeep(usize),
+
+    #[error("Bad data alignment {0}")]
+    BadAlignment(u64),
+
+    #[error("Data alignment must have type U32, got {0:?}")]
+    BadAlignmentType(GgufValueType),
+
+    #[error("Tensor {name}: unknown or unsupported type {typ}")]
+    UnknownTensorType { name: String, typ: u32 },
+
+    #[error("Tensor {name}: unsupported number of dimensions {n_dims}")]
+    UnsupportedDims { name: String, n_dims: usize },
+
+    #[error("Tensor {name}: bad shape {ne:?} for type {typ:?}")]
+    BadShape {
+        name: String,
+        typ: GType,
+        ne: Vec<u64>,
+    },
+
+    #[error("Tensor {name}: data offset {offset} is not aligned to {alignment}")]
+    BadOffset {
+        name: String,
+        offset: u64,
+        alignment: usize,
+    },
+
+    #[error("Duplicate tensor {0}")]
+    DuplicateTensor(String),
+
+    #[error("Tensor {0}: data must be contiguous to be saved")]
+    NonContiguousData(String),
+
+    #[error("Tensor {name}: data offset {offset} is out of range")]
+    OffsetOutOfRange { name: String, offset: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Information about a tensor stored in a GGUF file.
+pub struct GgufTensorInfo {
+    /// The tensor's name.
+    pub name: String,
+
+    /// The type of tensor.
+    pub typ: GType,
+
+    /// GGML's conception of the tensor's shape. The length is the
+    /// number of dimensions.
+    ///
+    /// **Note**: Be aware that GGML shapes have the first two
+    /// dimensions swapped. See [GgufTensorInfo::shape].
+    pub ggml_ne: Vec<usize>,
+
+    /// Offset of the tensor data relative to the start of the
+    /// data section.
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    /// Return the number of dimensions for this tensor.
+    pub fn dims(&self) -> usize {
+        self.ggml_ne.len()
+    }
+
+    /// Return the shape of this tensor in the same order used by
+    /// [GContext::tensor].
+    pub fn shape(&self) -> Vec<usize> {
+        let mut shape = self.ggml_ne.clone();
+        if shape.len() > 1 {
+            shape.swap(0, 1);
+        }
+        shape
+    }
+
+    /// Returns the number of elements in this tensor.
+    pub fn elements(&self) -> usize {
+        self.ggml_ne.iter().product()
+    }
+
+    /// Returns the tensor data length in bytes.
+    pub fn len_bytes(&self) -> usize {
+        let Some((ne0, rest)) = self.ggml_ne.split_first() else {
+            return 0;
+        };
+        self.typ.element_size() * (ne0 / self.typ.block_size()) * rest.iter().product::<usize>()
+    }
+
+    /// Creates a tensor with this tensor's type and shape in the specified context.
+    /// The tensor's data is not populated.
+    pub fn new_tensor(&self, ctx: &GContext) -> Result<GAnyTensor> {
+        let shp = self.shape();
+        Ok(match shp.len() {
+            1 => ctx.tensor(self.typ, [shp[0]])?.into(),
+            2 => ctx.tensor(self.typ, [shp[0], shp[1]])?.into(),
+            3 => ctx.tensor(self.typ, [shp[0], shp[1], shp[2]])?.into(),
+            n_dims => bail!(GgufError::UnsupportedDims {
+                name: self.name.clone(),
+                n_dims
+            }),
+        })
+    }
+
+    /// Creates a tensor with this tensor's type and shape in the specified context
+    /// with its data pointing into a mapped region. See [GContext::mapped_tensor].
+    pub fn new_mapped_tensor(
+        &self,
+        ctx: &GContext,
+        region: usize,
+        offset: usize,
+    ) -> Result<GAnyTensor> {
+        let shp = self.shape();
+        Ok(match shp.len() {
+            1 => ctx
+                .mapped_tensor(self.typ, [shp[0]], region, offset)?
+                .into(),
+            2 => ctx
+                .mapped_tensor(self.typ, [shp[0], shp[1]], region, offset)?
+                .into(),
+            3 => ctx
+                .mapped_tensor(self.typ, [shp[0], shp[1], shp[2]], region, offset)?
+                .into(),
+            n_dims => bail!(GgufError::UnsupportedDims {
+                name: self.name.clone(),
+                n_dims
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The header, metadata and tensor information from a GGUF file.
+pub struct GgufFile {
+    /// GGUF format version.
+    pub version: u32,
+
+    /// Tensor data alignment in bytes.
+    pub alignment: usize,
+
+    /// Metadata key/value pairs in file order.
+    pub metadata: Vec<(String, GgufValue)>,
+
+    /// Tensor information in file order.
+    pub tensors: Vec<GgufTensorInfo>,
+
+    /// Absolute offset of the tensor data section.
+    pub data_offset: u64,
+}
+
+impl GgufFile {
+    /// Oldest GGUF version this crate can read.
+    pub const MIN_VERSION: u32 = 2;
+
+    /// Newest GGUF version this crate can read.
+    pub const MAX_VERSION: u32 = 3;
+
+    /// Most tensor dimensions this crate can load. GGUF allows up to
+    /// [gg::GGML_MAX_DIMS] but [GContext::tensor] only creates tensors
+    /// with fewer than 4.
+    pub const MAX_DIMS: usize = 3;
+
+    /// How deeply metadata arrays may be nested. This keeps a corrupt file from
+    /// recursing until the stack overflows.
+    pub const MAX_ARRAY_DEPTH: usize = 8;
+
+    /// Parse the header, metadata and tensor information. The reader
+    /// must be positioned at the start of the GGUF data.
+    pub fn read<R: Read>(rdr: R) -> Result<Self> {
+        let mut rdr = GgufReader { rdr, pos: 0 };
+
+        let magic = rdr.read_u32()?;
+        ensure!(magic == gg::GGUF_MAGIC, GgufError::BadMagic(magic));
+        let version = rdr.read_u32()?;
+        ensure!(
+            (Self::MIN_VERSION..=Self::MAX_VERSION).contains(&version),
+            GgufError::UnsupportedVersion(version)
+        );
+        let n_tensors = rdr.read_u64()?;
+        let n_kv = rdr.read_u64()?;
+
+        // Counts come from the file so we don't trust them for preallocation.
+        let mut metadata = Vec::with_capacity(n_kv.min(1024) as usize);
+        let mut keys = HashSet::new();
+        for _ in 0..n_kv {
+            let key = rdr.read_string()?;
+            ensure!(keys.insert(key.clone()), GgufError::DuplicateKey(key));
+            let typ = rdr.read_value_type()?;
+            metadata.push((key, rdr.read_value(typ, 0)?));
+        }
+
+        let alignment = match metadata.iter().find(|(k, _)| k == GGUF_KEY_ALIGNMENT) {
+            Some((_, val)) => {
+                let alignment = val.as_u64().unwrap_or(0);
+                ensure!(
+                    alignment.is_power_of_two(),
+                    GgufError::BadAlignment(alignment)
+                );
+                alignment as usize
+            }
+            None => gg::GGUF_DEFAULT_ALIGNMENT as usize,
+        };
+
+        let mut tensors = Vec::with_capacity(n_tensors.min(1024) as usize);
+        let mut names = HashSet::new();
+        for _ in 0..n_tensors {
+            let ti = rdr.read_tensor_info(alignment)?;
+            ensure!(
+                names.insert(ti.name.clone()),
+                GgufError::DuplicateTensor(ti.name)
+            );
+            tensors.push(ti);
+        }
+
+        let data_offset = rdr.pos.next_multiple_of(alignment as u64);
+        Ok(Self {
+            version,
+            alignment,
+            metadata,
+            tensors,
+            data_offset,
+        })
+    }
+
+    /// Look up a metadata value by key.
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata
+            .iter()
+            .find_map(|(k, v)| (k == key).then_some(v))
+    }
+
+    /// Look up tensor information by name.
+    pub fn tensor_info(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|ti| ti.name == name)
+    }
+
+    /// Returns a [GContextBuilder] with enough memory to hold every
+    /// tensor in the file.
+    pub fn context_builder(&self) -> GContextBuilder {
+        let mem_size = self
+            .tensors
+            .iter()
+            .map(|ti| {
+                GMemoryRequest::object_bytes(
+                    gg::GGML_TENSOR_SIZE + GMemoryRequest::tensor_data_bytes(ti.typ, &ti.ggml_ne),
+                )
+            })
+            .sum();
+        GContextBuilder::new().mem_size(mem_size)
+    }
+
+    /// Returns a [GContextBuilder] with enough memory to hold the GGML objects
+    /// for every tensor in the file, but not their data. For use with
+    /// [GgufFile::map_tensors].
+    pub fn mapped_context_builder(&self) -> GContextBuilder {
+        let mem_size = self.tensors.len() * GMemoryRequest::object_bytes(gg::GGML_TENSOR_SIZE);
+        GContextBuilder::new().mem_size(mem_size)
+    }
+
+    /// Create every tensor in the file in the specified context with the data
+    /// pointing directly into `data` rather than being copied. `data` must contain
+    /// the entire GGUF file, for example a memory mapped file.
+    ///
+    /// `data` is registered as a mapped region of the context and kept alive until
+    /// the context is dropped. See [GContext::register_mapped_region].
+    pub fn map_tensors<M>(&self, ctx: &GContext, data: M) -> Result<HashMap<String, GAnyTensor>>
+    where
+        M: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let region = ctx.register_mapped_region(data)?;
+        self.tensors
+            .iter()
+            .map(|ti| {
+                // Out of range offsets are reported as the region being too small.
+                let offset = self
+                    .data_offset
+                    .checked_add(ti.offset)
+                    .and_then(|offset| usize::try_from(offset).ok())
+                    .unwrap_or(usize::MAX);
+                Ok((ti.name.clone(), ti.new_mapped_tensor(ctx, region, offset)?))
+            })
+            .collect()
+    }
+
+    /// Create every tensor in the file in the specified context and populate
+    /// it from the reader. Data is not read when the context is `no_alloc`.
+    ///
+    /// The reader must be positioned the same way as when [GgufFile::read]
+    /// was called.
+    pub fn load_tensors<R: Read + Seek>(
+        &self,
+        ctx: &GContext,
+        mut rdr: R,
+    ) -> Result<HashMap<String, GAnyTensor>> {
+        let base = rdr.stream_position()?;
+        let mut tensors = HashMap::with_capacity(self.tensors.len());
+        for ti in &self.tensors {
+            let mut t = ti.new_tensor(ctx)?;
+            if !ctx.no_alloc {
+                let pos = base
+                    .checked_add(self.data_offset)
+                    .and_then(|pos| pos.checked_add(ti.offset))
+                    .ok_or_else(|| GgufError::OffsetOutOfRange {
+                        name: ti.name.clone(),
+                        offset: ti.offset,
+                    })?;
+                rdr.seek(SeekFrom::Start(pos))?;
+                unsafe { t.with_data_mut(|buf| rdr.read_exact(buf))?? };
+            }
+            tensors.insert(ti.name.clone(), t);
+        }
+        Ok(tensors)
+    }
+
+    /// Load a GGUF file from the specified path. A [GContext] sized to hold
+    /// all the tensors is created and returned along with the tensors.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(Self, GContext, HashMap<String, GAnyTensor>)> {
+        let mut rdr = BufReader::new(File::open(path)?);
+        let gf = Self::read(&mut rdr)?;
+        let ctx = gf.context_builder().build()?;
+        rdr.rewind()?;
+        let tensors = gf.load_tensors(&ctx, &mut rdr)?;
+        Ok((gf, ctx, tensors))
+    }
+}
+
+struct GgufReader<R> {
+    rdr: R,
+    pos: u64,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.rdr.read_exact(&mut buf)?;
+        self.pos += N as u64;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        self.read_array().map(u32::from_le_bytes)
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        self.read_array().map(u64::from_le_bytes)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_u64()?;
+        let mut buf = Vec::new();
+        // Using take means a corrupt length can't make us allocate a huge buffer up front.
+        let got = (&mut self.rdr).take(len).read_to_end(&mut buf)? as u64;
+        self.pos += got;
+        if got != len {
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+        }
+        Ok(String::from_utf8(buf)
+            .map_err(|e| GgufError::BadString(String::from_utf8_lossy(e.as_bytes()).into()))?)
+    }
+
+    fn read_value_type(&mut self) -> Result<GgufValueType> {
+        let typ = self.read_u32()?;
+        Ok(GgufValueType::from_u32(typ).ok_or(GgufError::UnknownValueType(typ))?)
+    }
+
+    // `depth` is the number of arrays the value is nested in.
+    fn read_value(&mut self, typ: GgufValueType, depth: usize) -> Result<GgufValue> {
+        Ok(match typ {
+            GgufValueType::U8 => GgufValue::U8(u8::from_le_bytes(self.read_array()?)),
+            GgufValueType::I8 => GgufValue::I8(i8::from_le_bytes(self.read_array()?)),
+            GgufValueType::U16 => GgufValue::U16(u16::from_le_bytes(self.read_array()?)),
+            GgufValueType::I16 => GgufValue::I16(i16::from_le_bytes(self.read_array()?)),
+            GgufValueType::U32 => GgufValue::U32(self.read_u32()?),
+            GgufValueType::I32 => GgufValue::I32(i32::from_le_bytes(self.read_array()?)),
+            GgufValueType::F32 => GgufValue::F32(f32::from_le_bytes(self.read_array()?)),
+            GgufValueType::Bool => GgufValue::Bool(self.read_array::<1>()?[0] != 0),
+            GgufValueType::String => GgufValue::String(self.read_string()?),
+            GgufValueType::U64 => GgufValue::U64(self.read_u64()?),
+            GgufValueType::I64 => GgufValue::I64(i64::from_le_bytes(self.read_array()?)),
+            GgufValueType::F64 => GgufValue::F64(f64::from_le_bytes(self.read_array()?)),
+            GgufValueType::Array => {
+                ensure!(
+                    depth < GgufFile::MAX_ARRAY_DEPTH,
+                    GgufError::ArrayTooDeep(GgufFile::MAX_ARRAY_DEPTH)
+                );
+                let eltyp = self.read_value_type()?;
+                let len = self.read_u64()?;
+                let items = (0..len)
+                    .map(|_| self.read_value(eltyp, depth + 1))
+                    .collect::<Result<Vec<_>>>()?;
+                GgufValue::Array(eltyp, items)
+            }
+        })
+    }
+
+    fn read_tensor_info(&mut self, alignment: usize) -> Result<GgufTensorInfo> {
+        let name = self.read_string()?;
+        let n_dims = self.read_u32()? as usize;
+        if n_dims == 0 || n_dims > GgufFile::MAX_DIMS {
+            bail!(GgufError::UnsupportedDims { name, n_dims });
+        }
+        let ne = (0..n_dims)
+            .map(|_| self.read_u64())
+            .collect::<io::Result<Vec<_>>>()?;
+        let rawtyp = self.read_u32()?;
+        let offset = self.read_u64()?;
+
+        let Some(typ) = GType::from_u32(rawtyp) else {
+            bail!(GgufError::UnknownTensorType { name, typ: rawtyp });
+        };
+        let blck = typ.block_size() as u64;
+        if ne.iter().any(|v| *v == 0 || *v > i64::MAX as u64) || ne[0] % blck != 0 {
+            bail!(GgufError::BadShape { name, typ, ne });
+        }
+        if offset % alignment as u64 != 0 {
+            bail!(GgufError::BadOffset {
+                name,
+                offset,
+                alignment
+            });
+        }
+        Ok(GgufTensorInfo {
+            name,
+            typ,
+            ggml_ne: ne.into_iter().map(|v| v as usize).collect(),
+            offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{context::GContextError, gtensor::GTensor};
+
+    fn push_string(buf: &mut Vec<u8>, s: &str) {
+        buf.extend((s.len() as u64).to_le_bytes());
+        buf.extend(s.as_bytes());
+    }
+
+    // Builds a GGUF file containing a single F32 tensor named `weight`
+    // with GGML shape `[3, 2]`.
+    fn mk_gguf(typ: u32) -> Vec<u8> {
+        mk_gguf_shaped(typ, &[3, 2])
+    }
+
+    // Like `mk_gguf` but with the specified GGML shape.
+    fn mk_gguf_shaped(typ: u32, ne: &[u64]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend(gg::GGUF_MAGIC.to_le_bytes());
+        buf.extend(2u32.to_le_bytes());
+        buf.extend(1u64.to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+
+        push_string(&mut buf, "general.name");
+        buf.extend((GgufValueType::String as u32).to_le_bytes());
+        push_string(&mut buf, "test");
+        push_string(&mut buf, "test.values");
+        buf.extend((GgufValueType::Array as u32).to_le_bytes());
+        buf.extend((GgufValueType::I32 as u32).to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+        buf.extend(1i32.to_le_bytes());
+        buf.extend((-1i32).to_le_bytes());
+
+        push_string(&mut buf, "weight");
+        buf.extend((ne.len() as u32).to_le_bytes());
+        ne.iter().for_each(|v| buf.extend(v.to_le_bytes()));
+        buf.extend(typ.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+
+        buf.resize(buf.len().next_multiple_of(32), 0);
+        (1..=ne.iter().product::<u64>()).for_each(|v| buf.extend((v as f32).to_le_bytes()));
+        buf
+    }
+
+    #[test]
+    fn test_gguf_load() -> Result<()> {
+        let data = mk_gguf(GType::F32 as u32);
+        let gf = GgufFile::read(Cursor::new(&data))?;
+        assert_eq!(gf.get("general.name"), Some(&GgufValue::from("test")));
+        assert_eq!(
+            gf.get("test.values"),
+            Some(&GgufValue::Array(
+                GgufValueType::I32,
+                vec![GgufValue::I32(1), GgufValue::I32(-1)]
+            ))
+        );
+        assert_eq!(gf.data_offset as usize, data.len() - 6 * 4);
+        assert_eq!(gf.tensors[0].shape(), vec![2, 3]);
+
+        let ctx = gf.context_builder().build()?;
+        let mut tensors = gf.load_tensors(&ctx, Cursor::new(&data))?;
+        let t: GTensor<2> = tensors.remove("weight").unwrap().try_into()?;
+        assert_eq!(t.get_ne(), [3, 2, 1, 1]);
+        let mut output = [0.0; 6];
+        t.copy_to_slice_f32(&mut output)?;
+        assert_eq!(output, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_map() -> Result<()> {
+        let data = mk_gguf(GType::F32 as u32);
+        let gf = GgufFile::read(Cursor::new(&data))?;
+        let ctx = gf.mapped_context_builder().build()?;
+        let mut t: GTensor<2> = gf
+            .map_tensors(&ctx, data.clone())?
+            .remove("weight")
+            .unwrap()
+            .try_into()?;
+        assert!(ctx.used_mem()? < t.len() + gg::GGML_OBJECT_SIZE + gg::GGML_TENSOR_SIZE);
+        let mut output = [0.0; 6];
+        t.copy_to_slice_f32(&mut output)?;
+        assert_eq!(output, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert!(unsafe { t.with_data_mut(|_| ()) }.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_unknown_type() {
+        let err = GgufFile::read(Cursor::new(mk_gguf(1234))).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GgufError>(),
+            Some(&GgufError::UnknownTensorType {
+                name: "weight".to_string(),
+                typ: 1234
+            })
+        );
+    }
+
+    #[test]
+    fn test_gguf_4d() -> Result<()> {
+        let err = GgufFile::read(Cursor::new(mk_gguf_shaped(
+            GType::F32 as u32,
+            &[3, 2, 1, 1],
+        )))
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GgufError>(),
+            Some(&GgufError::UnsupportedDims {
+                name: "weight".to_string(),
+                n_dims: 4
+            })
+        );
+
+        let data = mk_gguf_shaped(GType::F32 as u32, &[3, 2, 2]);
+        let gf = GgufFile::read(Cursor::new(&data))?;
+        let ctx = gf.context_builder().build()?;
+        let t: GTensor<3> = gf
+            .load_tensors(&ctx, Cursor::new(&data))?
+            .remove("weight")
+            .unwrap()
+            .try_into()?;
+        assert_eq!(t.get_ne(), [3, 2, 2, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_bad_offsets() -> Result<()> {
+        let data = mk_gguf(GType::F32 as u32);
+        let mut gf = GgufFile::read(Cursor::new(&data))?;
+        gf.tensors[0].offset = u64::MAX - 31;
+        let ctx = gf.context_builder().build()?;
+        let err = gf.load_tensors(&ctx, Cursor::new(&data)).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<GgufError>(),
+            Some(&GgufError::OffsetOutOfRange {
+                name: "weight".to_string(),
+                offset: u64::MAX - 31
+            })
+        );
+
+        let ctx = gf.mapped_context_builder().build()?;
+        let err = gf.map_tensors(&ctx, data.clone()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<GContextError>(),
+            Some(GContextError::MappedRegionTooSmall {
+                needed: usize::MAX,
+                ..
+            })
+        ));
+        let region = ctx.register_mapped_region(data)?;
+        let result = ctx.mapped_tensor(GType::F32, [4], region, usize::MAX - 8);
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GContextError>()),
+            Err(Ok(GContextError::MappedRegionTooSmall {
+                needed: usize::MAX,
+                ..
+            }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_nested_arrays() {
+        let mut buf = vec![];
+        buf.extend(gg::GGUF_MAGIC.to_le_bytes());
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(0u64.to_le_bytes());
+        buf.extend(1u64.to_le_bytes());
+        push_string(&mut buf, "deep");
+        buf.extend((GgufValueType::Array as u32).to_le_bytes());
+        for _ in 0..GgufFile::MAX_ARRAY_DEPTH {
+            buf.extend((GgufValueType::Array as u32).to_le_bytes());
+            buf.extend(1u64.to_le_bytes());
+        }
+        let err = GgufFile::read(Cursor::new(&buf)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GgufError>(),
+            Some(&GgufError::ArrayTooDeep(GgufFile::MAX_ARRAY_DEPTH))
+        );
+    }
+}
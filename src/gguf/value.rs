@@ -0,0 +1,152 @@
+use ggml_sys_bleedingedge as gg;
+
+#[repr(u32)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    num_derive::FromPrimitive,
+    num_derive::ToPrimitive,
+)]
+/// GGUF metadata value type.
+pub enum GgufValueType {
+    U8 = gg::gguf_type_GGUF_TYPE_UINT8,
+    I8 = gg::gguf_type_GGUF_TYPE_INT8,
+    U16 = gg::gguf_type_GGUF_TYPE_UINT16,
+    I16 = gg::gguf_type_GGUF_TYPE_INT16,
+    U32 = gg::gguf_type_GGUF_TYPE_UINT32,
+    I32 = gg::gguf_type_GGUF_TYPE_INT32,
+    F32 = gg::gguf_type_GGUF_TYPE_FLOAT32,
+    Bool = gg::gguf_type_GGUF_TYPE_BOOL,
+    String = gg::gguf_type_GGUF_TYPE_STRING,
+    Array = gg::gguf_type_GGUF_TYPE_ARRAY,
+    U64 = gg::gguf_type_GGUF_TYPE_UINT64,
+    I64 = gg::gguf_type_GGUF_TYPE_INT64,
+    F64 = gg::gguf_type_GGUF_TYPE_FLOAT64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A GGUF metadata value.
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    /// Arrays are homogeneous. The element type is stored separately so
+    /// that empty arrays keep their type.
+    Array(GgufValueType, Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    /// Returns the GGUF type of this value.
+    pub fn value_type(&self) -> GgufValueType {
+        match self {
+            Self::U8(_) => GgufValueType::U8,
+            Self::I8(_) => GgufValueType::I8,
+            Self::U16(_) => GgufValueType::U16,
+            Self::I16(_) => GgufValueType::I16,
+            Self::U32(_) => GgufValueType::U32,
+            Self::I32(_) => GgufValueType::I32,
+            Self::F32(_) => GgufValueType::F32,
+            Self::Bool(_) => GgufValueType::Bool,
+            Self::String(_) => GgufValueType::String,
+            Self::Array(..) => GgufValueType::Array,
+            Self::U64(_) => GgufValueType::U64,
+            Self::I64(_) => GgufValueType::I64,
+            Self::F64(_) => GgufValueType::F64,
+        }
+    }
+
+    /// Returns the value as a `u64` if it is any unsigned or non-negative
+    /// signed integer type.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v as u64),
+            Self::U16(v) => Some(v as u64),
+            Self::U32(v) => Some(v as u64),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => u64::try_from(v).ok(),
+            Self::I16(v) => u64::try_from(v).ok(),
+            Self::I32(v) => u64::try_from(v).ok(),
+            Self::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a `f64` if it is a float type.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(v) => Some(v as f64),
+            Self::F64(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a `&str` if it is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a `bool` if it is a boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Self::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the array items if the value is an array.
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            Self::Array(_, v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! mk_ggufvalue_from {
+    ( $( ($typ:ty, $variant:ident) ),+ ) => { $(
+        impl From<$typ> for GgufValue {
+            fn from(value: $typ) -> Self {
+                Self::$variant(value)
+            }
+        }
+    )*};
+}
+
+mk_ggufvalue_from!(
+    (u8, U8),
+    (i8, I8),
+    (u16, U16),
+    (i16, I16),
+    (u32, U32),
+    (i32, I32),
+    (f32, F32),
+    (bool, Bool),
+    (String, String),
+    (u64, U64),
+    (i64, I64),
+    (f64, F64)
+);
+
+impl From<&str> for GgufValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
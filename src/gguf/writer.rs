@@ -0,0 +1,294 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Result};
+
+use ggml_sys_bleedingedge as gg;
+
+use super::{reader::*, value::*};
+use crate::gtensor::GAnyTensor;
+
+#[derive(Default)]
+/// Structure used to save named tensors and metadata to a GGUF file.
+///
+/// Metadata and tensors are written in the order they were added.
+pub struct GgufWriter {
+    metadata: Vec<(String, GgufValue)>,
+    tensors: Vec<(String, GAnyTensor)>,
+    keys: HashSet<String>,
+    names: HashSet<String>,
+}
+
+impl GgufWriter {
+    /// Create a new [GgufWriter].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a metadata key/value pair.
+    ///
+    /// **Invariants**
+    /// 1. The key must not already have been added.
+    /// 2. Array items must all have the array's element type.
+    /// 3. If the key is `general.alignment` the value must be a [GgufValue::U32]
+    ///    that's a power of two.
+    pub fn add_metadata<V: Into<GgufValue>>(&mut self, key: &str, value: V) -> Result<()> {
+        let value = value.into();
+        ensure!(
+            !self.keys.contains(key),
+            GgufError::DuplicateKey(key.to_string())
+        );
+        check_array(key, &value)?;
+        if key == GGUF_KEY_ALIGNMENT {
+            // GGML only accepts a U32 value.
+            ensure!(
+                matches!(value, GgufValue::U32(_)),
+                GgufError::BadAlignmentType(value.value_type())
+            );
+            let alignment = value.as_u64().unwrap_or(0);
+            ensure!(
+                alignment.is_power_of_two(),
+                GgufError::BadAlignment(alignment)
+            );
+        }
+        self.keys.insert(key.to_string());
+        self.metadata.push((key.to_string(), value));
+        Ok(())
+    }
+
+    /// Add a named tensor. The tensor's data is read when the file is written,
+    /// so it may be added before the graph that populates it runs.
+    ///
+    /// **Invariants**
+    /// 1. The name must not already have been added.
+    /// 2. The tensor's data must be contiguous. Use [GTensor::cont](crate::gtensor::GTensor::cont)
+    ///    to save a permuted or transposed view.
+    pub fn add_tensor<T: Into<GAnyTensor>>(&mut self, name: &str, tensor: T) -> Result<()> {
+        let tensor = tensor.into();
+        ensure!(
+            !self.names.contains(name),
+            GgufError::DuplicateTensor(name.to_string())
+        );
+        // The data is written as is, so the elements would end up in the wrong order.
+        ensure!(
+            tensor.is_contiguous(),
+            GgufError::NonContiguousData(name.to_string())
+        );
+        self.names.insert(name.to_string());
+        self.tensors.push((name.to_string(), tensor));
+        Ok(())
+    }
+
+    /// Returns the tensor data alignment that will be used.
+    pub fn alignment(&self) -> usize {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == GGUF_KEY_ALIGNMENT)
+            .and_then(|(_, v)| v.as_u64())
+            .map_or(gg::GGUF_DEFAULT_ALIGNMENT as usize, |v| v as usize)
+    }
+
+    /// Write the GGUF data.
+    ///
+    /// **Note**: Tensors must be in a context that isn't `no_alloc`.
+    pub fn write<W: Write>(&self, wtr: W) -> Result<()> {
+        let alignment = self.alignment() as u64;
+        let mut wtr = GgufWriterOutput { wtr, pos: 0 };
+
+        wtr.write_bytes(&gg::GGUF_MAGIC.to_le_bytes())?;
+        wtr.write_bytes(&gg::GGUF_VERSION.to_le_bytes())?;
+        wtr.write_bytes(&(self.tensors.len() as u64).to_le_bytes())?;
+        wtr.write_bytes(&(self.metadata.len() as u64).to_le_bytes())?;
+        for (key, value) in &self.metadata {
+            wtr.write_string(key)?;
+            wtr.write_bytes(&(value.value_type() as u32).to_le_bytes())?;
+            wtr.write_value(value)?;
+        }
+
+        let mut offset = 0u64;
+        for (name, t) in &self.tensors {
+            let dims = t.dims();
+            wtr.write_string(name)?;
+            wtr.write_bytes(&(dims as u32).to_le_bytes())?;
+            for ne in &t.get_ne()[..dims] {
+                wtr.write_bytes(&(*ne as u64).to_le_bytes())?;
+            }
+            wtr.write_bytes(&(t.element_type() as u32).to_le_bytes())?;
+            wtr.write_bytes(&offset.to_le_bytes())?;
+            offset = (offset + t.len() as u64).next_multiple_of(alignment);
+        }
+
+        for (_, t) in &self.tensors {
+            wtr.pad(alignment)?;
+            unsafe { t.with_data(|buf| wtr.write_bytes(buf))?? };
+        }
+        wtr.pad(alignment)?;
+        Ok(wtr.wtr.flush()?)
+    }
+
+    /// Write the GGUF data to a file at the specified path.
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+fn check_array(key: &str, value: &GgufValue) -> Result<()> {
+    if let GgufValue::Array(eltyp, items) = value {
+        for item in items {
+            ensure!(
+                item.value_type() == *eltyp,
+                GgufError::BadArray(key.to_string())
+            );
+            check_array(key, item)?;
+        }
+    }
+    Ok(())
+}
+
+struct GgufWriterOutput<W> {
+    wtr: W,
+    pos: u64,
+}
+
+impl<W: Write> GgufWriterOutput<W> {
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<()> {
+        self.wtr.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write_string(&mut self, s: &str) -> Result<()> {
+        self.write_bytes(&(s.len() as u64).to_le_bytes())?;
+        self.write_bytes(s.as_bytes())
+    }
+
+    fn pad(&mut self, alignment: u64) -> Result<()> {
+        let padding = self.pos.next_multiple_of(alignment) - self.pos;
+        self.write_bytes(&vec![0u8; padding as usize])
+    }
+
+    fn write_value(&mut self, value: &GgufValue) -> Result<()> {
+        match value {
+            GgufValue::U8(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::I8(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::U16(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::I16(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::U32(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::I32(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::F32(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::Bool(v) => self.write_bytes(&[*v as u8]),
+            GgufValue::String(v) => self.write_string(v),
+            GgufValue::U64(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::I64(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::F64(v) => self.write_bytes(&v.to_le_bytes()),
+            GgufValue::Array(eltyp, items) => {
+                self.write_bytes(&(*eltyp as u32).to_le_bytes())?;
+                self.write_bytes(&(items.len() as u64).to_le_bytes())?;
+                items.iter().try_for_each(|item| self.write_value(item))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{context::*, quantize::GQuantizer, util::GType};
+
+    #[test]
+    fn test_gguf_round_trip() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let input = (0..64).map(|v| v as f32 / 8.0).collect::<Vec<_>>();
+
+        let mut tf32 = ctx.tensor(GType::F32, [2, 3])?;
+        tf32.populate_f32(&input[..6]);
+        let mut tq = ctx.tensor(GType::Q8_0, [2, 32])?;
+        let mut quantizer = GQuantizer::default();
+        unsafe { tq.populate_raw(quantizer.quantize(GType::Q8_0, &input)?) };
+        let mut t3d = ctx.tensor(GType::I32, [1, 2, 3])?;
+        t3d.fill_i32(7);
+
+        let mut gw = GgufWriter::new();
+        gw.add_metadata(GGUF_KEY_ALIGNMENT, 64u32)?;
+        gw.add_metadata("general.name", "test")?;
+        gw.add_metadata(
+            "test.values",
+            GgufValue::Array(GgufValueType::F64, vec![1.5.into(), 2.5.into()]),
+        )?;
+        gw.add_tensor("f32", tf32.clone())?;
+        gw.add_tensor("q8_0", tq.clone())?;
+        gw.add_tensor("i32", t3d.clone())?;
+        let mut buf = vec![];
+        gw.write(&mut buf)?;
+
+        let gf = GgufFile::read(Cursor::new(&buf))?;
+        assert_eq!(gf.alignment, 64);
+        assert_eq!(gf.data_offset % 64, 0);
+        assert_eq!(gf.get("general.name"), Some(&GgufValue::from("test")));
+        assert_eq!(
+            gf.get("test.values").and_then(|v| v.as_array()),
+            Some(&[GgufValue::F64(1.5), GgufValue::F64(2.5)][..])
+        );
+        assert_eq!(gf.tensor_info("q8_0").map(|ti| ti.typ), Some(GType::Q8_0));
+
+        let ctx2 = gf.context_builder().build()?;
+        let tensors = gf.load_tensors(&ctx2, Cursor::new(&buf))?;
+        for (name, t) in [
+            ("f32", tf32.into()),
+            ("q8_0", tq.into()),
+            ("i32", t3d.into()),
+        ] {
+            let t: GAnyTensor = t;
+            let loaded = &tensors[name];
+            assert_eq!(loaded.element_type(), t.element_type());
+            assert_eq!(loaded.get_ne(), t.get_ne());
+            let (expected, got) = unsafe {
+                (
+                    t.with_data(|d| d.to_vec())?,
+                    loaded.with_data(|d| d.to_vec())?,
+                )
+            };
+            assert_eq!(expected, got, "data mismatch for {name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gguf_writer_duplicates() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let t = ctx.tensor(GType::F32, [1])?;
+        let mut gw = GgufWriter::new();
+        gw.add_metadata("a", 1u8)?;
+        assert!(gw.add_metadata("a", 2u8).is_err());
+        assert!(gw
+            .add_metadata("b", GgufValue::Array(GgufValueType::U8, vec![1u16.into()]))
+            .is_err());
+        let result = gw.add_metadata(GGUF_KEY_ALIGNMENT, 64u64);
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GgufError>()),
+            Err(Ok(GgufError::BadAlignmentType(GgufValueType::U64)))
+        ));
+        assert!(gw.add_metadata(GGUF_KEY_ALIGNMENT, 48u32).is_err());
+        gw.add_metadata(GGUF_KEY_ALIGNMENT, 64u32)?;
+        assert_eq!(gw.alignment(), 64);
+        gw.add_tensor("t", t.clone())?;
+        assert!(gw.add_tensor("t", t).is_err());
+
+        let m = ctx.tensor(GType::F32, [2, 3])?;
+        let result = gw.add_tensor("m", m.transpose());
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GgufError>()),
+            Err(Ok(GgufError::NonContiguousData(name))) if name == "m"
+        ));
+        gw.add_tensor("m", m.transpose().cont())?;
+        Ok(())
+    }
+}
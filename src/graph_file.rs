@@ -0,0 +1,1131 @@
+//! Saving computation graphs to a file and loading them again.
+//!
+//! A graph file contains the structure of a [GGraph] along with the data of the
+//! tensors that aren't the result of an operation, so a graph can be built once
+//! and computed later without running the code that built it.
+//!
+//! **Note**: Loading a graph file runs the operations it describes. Only load
+//! graph files from a source you trust.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    ptr, thread,
+};
+
+use anyhow::{bail, ensure, Result};
+use num_traits::FromPrimitive;
+use thiserror::Error;
+
+use ggml_sys_bleedingedge as gg;
+
+use crate::{
+    context::{GContext, GContextBuilder, GContextError, GGraph, IContext},
+    gtensor::GAnyTensor,
+    util::{GOp, GType, GUnaryOp},
+    validation::GMemoryRequest,
+};
+
+/// Magic number at the start of a graph file.
+pub const GGRAPH_FILE_MAGIC: u32 = u32::from_le_bytes(*b"GGRF");
+
+/// Version of the graph file format this crate reads and writes.
+pub const GGRAPH_FILE_VERSION: u32 = 1;
+
+const MAX_DIMS: usize = gg::GGML_MAX_DIMS as usize;
+const MAX_SRC: usize = gg::GGML_MAX_SRC as usize;
+const MAX_NAME: usize = gg::GGML_MAX_NAME as usize;
+const MAX_OP_PARAMS: usize = gg::GGML_MAX_OP_PARAMS as usize / 4;
+
+// Tensor index used for a missing source or view source.
+const NO_TENSOR: u32 = u32::MAX;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum GGraphFileError {
+    #[error("Bad magic {0:#010x}, not a graph file")]
+    BadMagic(u32),
+
+    #[error("Unsupported graph file version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Tensor {name}: operation {op} calls a user supplied function and can't be saved")]
+    UnsupportedOp { name: String, op: GOp },
+
+    #[error("Tensor {0}: data must be contiguous to be saved")]
+    NonContiguousData(String),
+
+    #[error("Tensor {0}: source is not part of the graph")]
+    MissingSource(String),
+
+    #[error("Duplicate input name {0}")]
+    DuplicateInput(String),
+
+    #[error("Duplicate output name {0}")]
+    DuplicateOutput(String),
+
+    #[error("Bad graph size: {n_leafs} leafs and {n_nodes} nodes")]
+    BadGraphSize { n_leafs: usize, n_nodes: usize },
+
+    #[error("Bad thread count {0}")]
+    BadThreadCount(usize),
+
+    #[error("Tensor {index}: {reason}")]
+    BadTensor { index: usize, reason: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Information about a tensor stored in a graph file.
+pub struct GGraphFileTensor {
+    /// The tensor's name.
+    pub name: String,
+
+    /// The type of tensor.
+    pub typ: GType,
+
+    /// The operation that created the tensor. Tensors with [GOp::None]
+    /// have their data stored in the file.
+    pub op: GOp,
+
+    /// GGML's conception of the tensor's shape. The length is the
+    /// number of dimensions.
+    ///
+    /// **Note**: Be aware that GGML shapes have the first two
+    /// dimensions swapped. See [GGraphFileTensor::shape].
+    pub ggml_ne: Vec<usize>,
+
+    /// `true` if the tensor is a parameter GGML can compute gradients for.
+    pub is_param: bool,
+
+    nb: [usize; MAX_DIMS],
+    op_params: [i32; MAX_OP_PARAMS],
+    // Indexes of the sources in the file.
+    sources: [Option<usize>; MAX_SRC],
+    // Index of the tensor whose data this tensor views and the offset in bytes.
+    view: Option<(usize, usize)>,
+    data: Vec<u8>,
+}
+
+impl GGraphFileTensor {
+    /// Return the number of dimensions for this tensor.
+    pub fn dims(&self) -> usize {
+        self.ggml_ne.len()
+    }
+
+    /// Return the shape of this tensor in the same order used by
+    /// [GContext::tensor].
+    pub fn shape(&self) -> Vec<usize> {
+        let mut shape = self.ggml_ne.clone();
+        if shape.len() > 1 {
+            shape.swap(0, 1);
+        }
+        shape
+    }
+
+    fn ne(&self) -> [usize; MAX_DIMS] {
+        let mut ne = [1; MAX_DIMS];
+        ne[..self.dims()].copy_from_slice(&self.ggml_ne);
+        ne
+    }
+
+    fn elements(&self) -> usize {
+        self.ggml_ne.iter().product()
+    }
+
+    fn is_contiguous(&self) -> bool {
+        contiguous_nb(self.typ, &self.ne()) == Some(self.nb)
+    }
+
+    /// Returns the index of the tensor that holds this tensor's data and the
+    /// offset in bytes. `index` is this tensor's index in the file.
+    fn data_src(&self, index: usize) -> (usize, usize) {
+        self.view.unwrap_or((index, 0))
+    }
+
+    /// Checks the tensor's shape and where its data comes from and, for the result
+    /// of an operation, that it's what GGML would create from its sources. `prev`
+    /// holds the preceding tensors in the file.
+    ///
+    /// Returns the length of the tensor's data in bytes.
+    fn check(&self, index: usize, prev: &[GGraphFileTensor]) -> Result<usize> {
+        let bad = |reason| GGraphFileError::BadTensor { index, reason };
+        ensure!(
+            (1..=MAX_DIMS).contains(&self.dims()),
+            bad("unsupported number of dimensions")
+        );
+        let ne = self.ne();
+        ensure!(
+            ne.iter().all(|v| (1..=i64::MAX as usize).contains(v))
+                && ne[0].is_multiple_of(self.typ.block_size()),
+            bad("bad shape")
+        );
+        let nbytes = self
+            .typ
+            .nbytes(&ne, &self.nb)
+            .ok_or_else(|| bad("bad shape"))?;
+
+        match self.view {
+            Some((src, offs)) => {
+                let src_ft = &prev[src];
+                let src_nbytes = src_ft.typ.nbytes(&src_ft.ne(), &src_ft.nb);
+                let data = GMemoryRequest::tensor_data_bytes(self.typ, &self.ggml_ne);
+                // GGML aborts if the data of the view doesn't fit in its source, and
+                // the strides must not reach past it either.
+                ensure!(
+                    src_ft.view.is_none()
+                        && src_ft.typ == self.typ
+                        && src_nbytes.is_some_and(|src_nbytes| {
+                            offs.saturating_add(nbytes.max(data)) <= src_nbytes
+                        }),
+                    bad("bad view")
+                );
+            }
+            None => ensure!(self.is_contiguous(), bad("data must be contiguous")),
+        }
+        match self.op {
+            GOp::None => ensure!(
+                self.sources.iter().all(|src| src.is_none()),
+                bad("tensor without an operation has sources")
+            ),
+            _ => self.check_op(index, prev)?,
+        }
+        Ok(nbytes)
+    }
+
+    /// Checks the result of an operation against its sources the same way the GGML
+    /// function that creates it would, so computing it can't read or write past the
+    /// data of a tensor.
+    fn check_op(&self, index: usize, prev: &[GGraphFileTensor]) -> Result<()> {
+        let bad = |reason| GGraphFileError::BadTensor { index, reason };
+        let n_src = match self.op {
+            GOp::Sqr
+            | GOp::Sqrt
+            | GOp::Sum
+            | GOp::Mean
+            | GOp::Norm
+            | GOp::RmsNorm
+            | GOp::Cont
+            | GOp::Reshape
+            | GOp::View
+            | GOp::Permute
+            | GOp::Transpose
+            | GOp::DiagMaskInf
+            | GOp::SoftMax
+            | GOp::Rope
+            | GOp::Unary => 1,
+            GOp::Add
+            | GOp::Sub
+            | GOp::Mul
+            | GOp::Div
+            | GOp::Repeat
+            | GOp::MulMat
+            | GOp::Scale
+            | GOp::Cpy
+            | GOp::GetRows
+            | GOp::Conv1D => 2,
+            _ => bail!(bad("unsupported operation")),
+        };
+        ensure!(
+            self.sources[..n_src].iter().all(|src| src.is_some())
+                && self.sources[n_src..].iter().all(|src| src.is_none()),
+            bad("wrong number of sources")
+        );
+        let srcs = self.sources[..n_src]
+            .iter()
+            .flatten()
+            .map(|src| &prev[*src])
+            .collect::<Vec<_>>();
+        let a_idx = self.sources[0].expect("Impossible: Missing first source");
+        let (a, b) = (srcs[0], srcs.get(1).copied().unwrap_or(srcs[0]));
+        let (ane, bne) = (a.ne(), b.ne());
+        let params = &self.op_params;
+        let can_mul_mat = |t0: &[usize; MAX_DIMS], t1: &[usize; MAX_DIMS]| {
+            t0[0] == t1[0] && t1[2].is_multiple_of(t0[2]) && t1[3].is_multiple_of(t0[3])
+        };
+
+        // Only views and copies share the data of another tensor.
+        let shares_data = self.op.is_view() || self.op == GOp::Cpy;
+        ensure!(
+            self.view.is_some() == shares_data,
+            bad("operation does not match where the data comes from")
+        );
+        let same_data =
+            |src: &GGraphFileTensor, src_idx: usize| self.view == Some(src.data_src(src_idx));
+
+        // The type, dimensions and shape GGML gives the result, or `None` when
+        // the sources or parameters aren't valid for the operation.
+        let expected = match self.op {
+            GOp::Sqr | GOp::Sqrt | GOp::Norm | GOp::RmsNorm | GOp::Cont | GOp::SoftMax => {
+                Some((a.typ, a.dims(), ane))
+            }
+            GOp::DiagMaskInf | GOp::Rope => (params[0] >= 0).then_some((a.typ, a.dims(), ane)),
+            GOp::Unary => GUnaryOp::from_i32(params[0]).map(|_| (a.typ, a.dims(), ane)),
+            GOp::Add | GOp::Mul => (ane[0] == bne[0]
+                && (0..MAX_DIMS).all(|i| ane[i].is_multiple_of(bne[i])))
+            .then_some((a.typ, a.dims(), ane)),
+            GOp::Sub | GOp::Div => (ane == bne).then_some((a.typ, a.dims(), ane)),
+            GOp::Scale => {
+                let padded_1d =
+                    (1..MAX_DIMS).all(|i| ane[i - 1].checked_mul(a.nb[i - 1]) == Some(a.nb[i]));
+                (b.elements() == 1 && padded_1d).then_some((a.typ, a.dims(), ane))
+            }
+            GOp::Repeat => ((0..MAX_DIMS).all(|i| bne[i].is_multiple_of(ane[i]))).then_some((
+                a.typ,
+                b.dims(),
+                bne,
+            )),
+            GOp::Sum => Some((a.typ, 1, [1; MAX_DIMS])),
+            GOp::Mean => Some((GType::F32, a.dims(), [1, ane[1], ane[2], ane[3]])),
+            GOp::MulMat => (can_mul_mat(&ane, &bne) && a.nb[0] <= a.nb[1]).then_some((
+                GType::F32,
+                a.dims().max(b.dims()),
+                [ane[1], bne[1], bne[2], bne[3]],
+            )),
+            GOp::GetRows => (ane[2..] == [1, 1] && bne[1..] == [1, 1, 1] && b.typ == GType::I32)
+                .then_some((GType::F32, 2, [ane[0], bne[0], 1, 1])),
+            GOp::Conv1D => {
+                // Shapes are at most i64::MAX so this can't overflow.
+                let [s0, p0, d0] = [params[0], params[1], params[2]].map(i128::from);
+                let (ka, lb) = (ane[0] as i128, bne[0] as i128);
+                let valid = bne[2..] == [1, 1] && ane[1] == bne[1] && s0 > 0 && p0 >= 0 && d0 >= 0;
+                let out = valid.then(|| (lb + 2 * p0 - d0 * (ka - 1) - 1) / s0 + 1);
+                out.filter(|out| *out >= 1)
+                    .map(|out| (GType::F32, 2, [out as usize, ane[2], 1, 1]))
+            }
+            GOp::Cpy => {
+                let b_idx = self.sources[1].expect("Impossible: Missing second source");
+                (a.elements() == b.elements() && same_data(b, b_idx) && self.nb == b.nb)
+                    .then_some((b.typ, b.dims(), bne))
+            }
+            GOp::Reshape => (a.is_contiguous()
+                && self.is_contiguous()
+                && a.elements() == self.elements()
+                && same_data(a, a_idx))
+            .then_some((a.typ, self.dims(), self.ne())),
+            GOp::View => {
+                let (root, offs) = a.data_src(a_idx);
+                (self
+                    .view
+                    .is_some_and(|(src, voffs)| src == root && voffs >= offs)
+                    && self.nb[0] == a.typ.element_size())
+                .then_some((a.typ, self.dims(), self.ne()))
+            }
+            GOp::Permute | GOp::Transpose => {
+                let axes = match self.op {
+                    GOp::Permute => params[..MAX_DIMS].to_vec(),
+                    _ => vec![1, 0, 2, 3],
+                };
+                let mut seen = [false; MAX_DIMS];
+                let valid = axes.iter().all(|ax| {
+                    usize::try_from(*ax)
+                        .ok()
+                        .and_then(|ax| seen.get_mut(ax))
+                        .is_some_and(|seen| !std::mem::replace(seen, true))
+                });
+                let (mut ne, mut nb) = ([1; MAX_DIMS], [0; MAX_DIMS]);
+                if valid {
+                    for (i, ax) in axes.iter().enumerate() {
+                        ne[*ax as usize] = ane[i];
+                        nb[*ax as usize] = a.nb[i];
+                    }
+                }
+                (valid && same_data(a, a_idx) && self.nb == nb).then_some((a.typ, a.dims(), ne))
+            }
+            _ => None,
+        };
+        let Some((typ, dims, ne)) = expected else {
+            bail!(bad("sources or parameters don't match the operation"));
+        };
+        ensure!(
+            self.typ == typ && self.dims() == dims && self.ne() == ne,
+            bad("type or shape doesn't match the operation")
+        );
+        Ok(())
+    }
+
+    /// Returns the context memory the tensor needs when it's loaded.
+    fn context_bytes(&self) -> usize {
+        let data = match self.view {
+            Some(_) => 0,
+            None => GMemoryRequest::tensor_data_bytes(self.typ, &self.ggml_ne),
+        };
+        GMemoryRequest::object_bytes(gg::GGML_TENSOR_SIZE + data)
+    }
+
+    /// Creates the tensor in a context that isn't `no_alloc`. `tptrs` holds the
+    /// tensors created for the preceding entries in the file.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held.
+    unsafe fn new_tensor(
+        &self,
+        ctx: &GContext,
+        ictx: &mut IContext,
+        tptrs: &[*mut gg::ggml_tensor],
+    ) -> Result<(GAnyTensor, *mut gg::ggml_tensor)> {
+        let [ne0, ne1, ne2, ne3] = self.ne().map(|v| v as i64);
+        let [_, nb1, nb2, nb3] = self.nb;
+        let (mr, p) = match self.view {
+            Some((src, offs)) => {
+                let mr =
+                    GMemoryRequest::estimate_view_request_ictx(ctx, ictx, self.typ, &self.ggml_ne)
+                        .fit_or_grow(ictx)?;
+                let (gctx, src) = (ictx.gptr(), tptrs[src]);
+                let p = match self.dims() {
+                    1 => gg::ggml_view_1d(gctx, src, ne0, offs),
+                    2 => gg::ggml_view_2d(gctx, src, ne0, ne1, nb1, offs),
+                    3 => gg::ggml_view_3d(gctx, src, ne0, ne1, ne2, nb1, nb2, offs),
+                    _ => gg::ggml_view_4d(gctx, src, ne0, ne1, ne2, ne3, nb1, nb2, nb3, offs),
+                };
+                (mr, p)
+            }
+            None => {
+                let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                    ctx,
+                    ictx,
+                    self.typ,
+                    &self.ggml_ne,
+                )
+                .fit_or_grow(ictx)?;
+                // Like GContext::tensor, the graph allocator places the data of tensors
+                // that aren't the result of an operation right away.
+                let gctx = ictx.gptr();
+                let galloc = ictx
+                    .graph_allocator
+                    .as_mut()
+                    .filter(|_| self.op == GOp::None);
+                if let Some(galloc) = &galloc {
+                    galloc
+                        .ensure_fits(GMemoryRequest::tensor_data_bytes(self.typ, &self.ggml_ne))?;
+                }
+                let ne = [ne0, ne1, ne2, ne3];
+                let p = gg::ggml_new_tensor(gctx, self.typ as u32, self.dims() as i32, ne.as_ptr());
+                if let (Some(galloc), false) = (galloc, p.is_null()) {
+                    galloc.alloc_tensor(p);
+                }
+                (mr, p)
+            }
+        };
+        let tref = p.as_mut().ok_or(GContextError::TensorCreationFailed)?;
+        tref.nb = self.nb;
+        tref.op = self.op as u32;
+        tref.op_params = self.op_params;
+        tref.is_param = self.is_param;
+        tref.src = self
+            .sources
+            .map(|src| src.map_or(ptr::null_mut(), |src| tptrs[src]));
+        // Names are read up to the first NUL so they can't contain one.
+        let name = CString::new(self.name.as_str()).unwrap_or_default();
+        gg::ggml_set_name(p, name.as_ptr());
+        if self.op == GOp::None {
+            ptr::copy_nonoverlapping(self.data.as_ptr(), tref.data as *mut u8, self.data.len());
+        }
+        Ok((GAnyTensor::new_from_ptr(ctx, ictx, (mr, p))?, p))
+    }
+}
+
+/// A graph loaded from a graph file. See [GGraphFile::build].
+pub struct GLoadedGraph {
+    /// The context the graph's tensors were created in.
+    pub ctx: GContext,
+
+    /// The graph, ready to be computed.
+    pub graph: GGraph,
+
+    /// The tensors whose data was stored in the file by name: the
+    /// graph's inputs and weights.
+    pub inputs: HashMap<String, GAnyTensor>,
+
+    /// The tensors the saved graph was expanded with by name.
+    pub outputs: HashMap<String, GAnyTensor>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The structure and data of a graph stored in a graph file.
+///
+/// Example:
+/// ```rust,ignore
+/// // When building the model, for example in a build script.
+/// graph.write_to_path("model.graph")?;
+///
+/// // Later, without running the code that built the graph.
+/// let mut loaded = GGraphFile::load("model.graph")?;
+/// let mut input = loaded.inputs["input"].clone().into_typed::<1>()?;
+/// input.populate_f32(&values);
+/// loaded.ctx.compute(&mut loaded.graph)?;
+/// let result = loaded.outputs["result"].clone().into_typed::<1>()?;
+/// ```
+pub struct GGraphFile {
+    /// The number of threads used when computing the graph. When reading a file,
+    /// this is limited to the number of threads the machine can run in parallel.
+    pub n_threads: usize,
+
+    /// Every tensor in the graph: the leafs followed by the nodes, in
+    /// the order GGML computes them.
+    pub tensors: Vec<GGraphFileTensor>,
+
+    /// Indexes of the tensors the graph was expanded with, in order.
+    pub outputs: Vec<usize>,
+}
+
+impl GGraphFile {
+    /// Parse a graph file, including the data of the tensors that aren't the
+    /// result of an operation.
+    ///
+    /// Each tensor that is the result of an operation is checked against its
+    /// sources the same way GGML checks it when the operation is created. Only
+    /// the operations [GTensor](crate::gtensor::GTensor) can create are supported.
+    pub fn read<R: Read>(rdr: R) -> Result<Self> {
+        let mut rdr = GGraphReader { rdr };
+
+        let magic = rdr.read_u32()?;
+        ensure!(magic == GGRAPH_FILE_MAGIC, GGraphFileError::BadMagic(magic));
+        let version = rdr.read_u32()?;
+        ensure!(
+            version == GGRAPH_FILE_VERSION,
+            GGraphFileError::UnsupportedVersion(version)
+        );
+        let n_threads = rdr.read_u32()? as usize;
+        ensure!(n_threads > 0, GGraphFileError::BadThreadCount(n_threads));
+        // The file may have been written on a machine with more cores.
+        let n_threads = n_threads.min(thread::available_parallelism().map_or(1, |n| n.get()));
+        let n_leafs = rdr.read_u32()? as usize;
+        let n_nodes = rdr.read_u32()? as usize;
+        let n_outputs = rdr.read_u32()? as usize;
+        let max = gg::GGML_MAX_NODES as usize;
+        ensure!(
+            n_leafs <= max && n_nodes <= max,
+            GGraphFileError::BadGraphSize { n_leafs, n_nodes }
+        );
+
+        let mut tensors: Vec<GGraphFileTensor> = Vec::with_capacity(n_leafs + n_nodes);
+        for index in 0..n_leafs + n_nodes {
+            let tensor = rdr.read_tensor(index, &tensors)?;
+            ensure!(
+                index >= n_leafs || tensor.op == GOp::None,
+                GGraphFileError::BadTensor {
+                    index,
+                    reason: "leaf is the result of an operation"
+                }
+            );
+            tensors.push(tensor);
+        }
+        let mut outputs = Vec::with_capacity(n_outputs);
+        for _ in 0..n_outputs {
+            let index = rdr.read_u32()? as usize;
+            ensure!(
+                index < tensors.len(),
+                GGraphFileError::BadTensor {
+                    index,
+                    reason: "output does not exist"
+                }
+            );
+            // Expanding a graph with the same tensor again doesn't change it.
+            if !outputs.contains(&index) {
+                outputs.push(index);
+            }
+        }
+
+        let gf = Self {
+            n_threads,
+            tensors,
+            outputs,
+        };
+        check_unique_names(gf.inputs(), GGraphFileError::DuplicateInput)?;
+        check_unique_names(
+            gf.outputs.iter().map(|idx| &gf.tensors[*idx].name),
+            GGraphFileError::DuplicateOutput,
+        )?;
+        Ok(gf)
+    }
+
+    fn inputs(&self) -> impl Iterator<Item = &String> {
+        self.tensors
+            .iter()
+            .filter(|ft| ft.op == GOp::None)
+            .map(|ft| &ft.name)
+    }
+
+    /// Returns a [GContextBuilder] with enough memory to hold every
+    /// tensor in the graph.
+    pub fn context_builder(&self) -> GContextBuilder {
+        let mem_size = self.tensors.iter().map(|ft| ft.context_bytes()).sum();
+        GContextBuilder::new().mem_size(mem_size)
+    }
+
+    /// Create every tensor in the graph in the specified context and build
+    /// a [GGraph] from them.
+    ///
+    /// The tensors are checked again first since their public fields may
+    /// have been changed after the file was read.
+    ///
+    /// **Invariants**
+    /// 1. The context must not be `no_alloc`.
+    pub fn build(&self, ctx: &GContext) -> Result<GLoadedGraph> {
+        ensure!(!ctx.no_alloc, GContextError::NoAlloc);
+        for (index, ft) in self.tensors.iter().enumerate() {
+            let nbytes = ft.check(index, &self.tensors[..index])?;
+            ensure!(
+                ft.op != GOp::None || ft.data.len() == nbytes,
+                GGraphFileError::BadTensor {
+                    index,
+                    reason: "data doesn't match the shape"
+                }
+            );
+        }
+        if let Some(index) = self.outputs.iter().find(|idx| **idx >= self.tensors.len()) {
+            bail!(GGraphFileError::BadTensor {
+                index: *index,
+                reason: "output does not exist"
+            });
+        }
+        let tensors = ctx.with_icontext(|ctx, mut ictx| {
+            let mut tptrs = Vec::with_capacity(self.tensors.len());
+            let mut tensors = Vec::with_capacity(self.tensors.len());
+            for ft in &self.tensors {
+                let (t, tptr) = unsafe { ft.new_tensor(ctx, &mut ictx, &tptrs)? };
+                tptrs.push(tptr);
+                tensors.push(t);
+            }
+            Ok(tensors)
+        })?;
+
+        let mut graph = GGraph::new(self.n_threads);
+        // Expanding with the same tensors in the same order gives the same graph.
+        for idx in &self.outputs {
+            match &tensors[*idx] {
+                GAnyTensor::D1(t) => graph.build_forward_expand(t)?,
+                GAnyTensor::D2(t) => graph.build_forward_expand(t)?,
+                GAnyTensor::D3(t) => graph.build_forward_expand(t)?,
+                GAnyTensor::D4(t) => graph.build_forward_expand(t)?,
+            }
+        }
+
+        let named = |idx: usize| (self.tensors[idx].name.clone(), tensors[idx].clone());
+        Ok(GLoadedGraph {
+            ctx: ctx.clone(),
+            graph,
+            inputs: (0..self.tensors.len())
+                .filter(|idx| self.tensors[*idx].op == GOp::None)
+                .map(named)
+                .collect(),
+            outputs: self.outputs.iter().map(|idx| named(*idx)).collect(),
+        })
+    }
+
+    /// Load a graph file from the specified path. A [GContext] sized to hold
+    /// all the tensors is created and returned along with the graph.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<GLoadedGraph> {
+        let gf = Self::read(BufReader::new(File::open(path)?))?;
+        let ctx = gf.context_builder().build()?;
+        gf.build(&ctx)
+    }
+}
+
+impl GGraph {
+    /// Save the structure of the graph along with the data of the tensors that
+    /// aren't the result of an operation. Use [GGraphFile::load] to load it again.
+    ///
+    /// **Invariants**
+    /// 1. Tensors that aren't the result of an operation must be in a context that
+    ///    isn't `no_alloc` and have contiguous data.
+    /// 2. Operations that call a user supplied function can't be saved.
+    /// 3. Tensors that aren't the result of an operation must have unique names, as
+    ///    must the tensors the graph was expanded with.
+    ///
+    /// **Note**: Gradients are not saved.
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<()> {
+        let cgraph = self.cgraph();
+        let tptrs = cgraph.leafs[..self.n_leafs()]
+            .iter()
+            .chain(&cgraph.nodes[..self.n_nodes()])
+            .copied()
+            .collect::<Vec<_>>();
+        let gts = self.leafs()?.chain(self.nodes()?).collect::<Vec<_>>();
+        let index = tptrs
+            .iter()
+            .enumerate()
+            .map(|(idx, tptr)| (*tptr, idx as u32))
+            .collect::<HashMap<_, _>>();
+        let mut outputs = Vec::with_capacity(self.outputs().len());
+        for tptr in self.outputs() {
+            let idx = index[tptr] as usize;
+            if !outputs.contains(&idx) {
+                outputs.push(idx);
+            }
+        }
+        check_unique_names(
+            gts.iter()
+                .filter(|gt| gt.op == GOp::None)
+                .map(|gt| &gt.name),
+            GGraphFileError::DuplicateInput,
+        )?;
+        check_unique_names(
+            outputs.iter().map(|idx| &gts[*idx].name),
+            GGraphFileError::DuplicateOutput,
+        )?;
+
+        for val in [
+            GGRAPH_FILE_MAGIC,
+            GGRAPH_FILE_VERSION,
+            self.n_threads() as u32,
+            self.n_leafs() as u32,
+            self.n_nodes() as u32,
+            outputs.len() as u32,
+        ] {
+            wtr.write_all(&val.to_le_bytes())?;
+        }
+
+        for (tptr, gt) in tptrs.iter().zip(&gts) {
+            ensure!(
+                !gt.op.is_custom(),
+                GGraphFileError::UnsupportedOp {
+                    name: gt.name.clone(),
+                    op: gt.op,
+                }
+            );
+            let tref = unsafe { &**tptr };
+            let find = |src: *mut gg::ggml_tensor| match src.is_null() {
+                true => Ok(NO_TENSOR),
+                false => index
+                    .get(&src)
+                    .copied()
+                    .ok_or_else(|| GGraphFileError::MissingSource(gt.name.clone())),
+            };
+            // Tensors that aren't the result of an operation are stored with their data.
+            let (sources, view_src) = match gt.op {
+                GOp::None => (vec![NO_TENSOR; MAX_SRC], NO_TENSOR),
+                _ => (
+                    tref.src.into_iter().map(find).collect::<Result<_, _>>()?,
+                    find(tref.view_src)?,
+                ),
+            };
+
+            let ints = [
+                tref.type_,
+                tref.op,
+                tref.n_dims as u32,
+                tref.is_param as u32,
+            ];
+            ints.iter()
+                .try_for_each(|v| wtr.write_all(&v.to_le_bytes()))?;
+            let sizes = tref
+                .ne
+                .map(|v| v as u64)
+                .into_iter()
+                .chain(tref.nb.map(|v| v as u64));
+            sizes
+                .into_iter()
+                .try_for_each(|v| wtr.write_all(&v.to_le_bytes()))?;
+            tref.op_params
+                .iter()
+                .try_for_each(|v| wtr.write_all(&v.to_le_bytes()))?;
+            wtr.write_all(&tref.name.map(|c| c as u8))?;
+            sources
+                .iter()
+                .try_for_each(|v| wtr.write_all(&v.to_le_bytes()))?;
+            wtr.write_all(&view_src.to_le_bytes())?;
+            wtr.write_all(&(tref.view_offs as u64).to_le_bytes())?;
+
+            if gt.op == GOp::None {
+                ensure!(
+                    unsafe { gg::ggml_is_contiguous(tref) },
+                    GGraphFileError::NonContiguousData(gt.name.clone())
+                );
+                unsafe { gt.tensor.with_data(|buf| wtr.write_all(buf))?? };
+            }
+        }
+
+        outputs
+            .iter()
+            .try_for_each(|idx| wtr.write_all(&(*idx as u32).to_le_bytes()))?;
+        Ok(wtr.flush()?)
+    }
+
+    /// Save the graph to a file at the specified path. See [GGraph::write].
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+}
+
+fn check_unique_names<'a, F>(names: impl Iterator<Item = &'a String>, err: F) -> Result<()>
+where
+    F: Fn(String) -> GGraphFileError,
+{
+    let mut seen = HashSet::new();
+    for name in names {
+        ensure!(seen.insert(name), err(name.clone()));
+    }
+    Ok(())
+}
+
+/// Returns the strides of a contiguous tensor, or `None` on overflow.
+fn contiguous_nb(typ: GType, ne: &[usize; MAX_DIMS]) -> Option<[usize; MAX_DIMS]> {
+    let mut nb = [typ.element_size(), 0, 0, 0];
+    nb[1] = typ.row_size(ne[0]);
+    for i in 2..MAX_DIMS {
+        nb[i] = nb[i - 1].checked_mul(ne[i - 1])?;
+    }
+    Some(nb)
+}
+
+struct GGraphReader<R> {
+    rdr: R,
+}
+
+impl<R: Read> GGraphReader<R> {
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.rdr.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        self.read_array().map(u32::from_le_bytes)
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        self.read_array().map(u64::from_le_bytes)
+    }
+
+    // Reads and validates the tensor at `index`. `prev` holds the preceding tensors.
+    fn read_tensor(&mut self, index: usize, prev: &[GGraphFileTensor]) -> Result<GGraphFileTensor> {
+        let bad = |reason| GGraphFileError::BadTensor { index, reason };
+        let [typ, op, n_dims, is_param] = [(); 4].map(|_| self.read_u32());
+        let Some(typ) = GType::from_u32(typ?) else {
+            bail!(bad("unknown type"));
+        };
+        let Some(op) = GOp::from_u32(op?) else {
+            bail!(bad("unknown operation"));
+        };
+        ensure!(
+            !op.is_custom(),
+            bad("operation calls a user supplied function")
+        );
+        let n_dims = n_dims? as usize;
+        ensure!(
+            (1..=MAX_DIMS).contains(&n_dims),
+            bad("unsupported number of dimensions")
+        );
+        let mut sizes = [0usize; MAX_DIMS * 2];
+        for size in sizes.iter_mut() {
+            *size = usize::try_from(self.read_u64()?).map_err(|_e| bad("bad shape"))?;
+        }
+        let ne: [usize; MAX_DIMS] = sizes[..MAX_DIMS].try_into()?;
+        let nb: [usize; MAX_DIMS] = sizes[MAX_DIMS..].try_into()?;
+        ensure!(ne[n_dims..].iter().all(|v| *v == 1), bad("bad shape"));
+        let op_params = [(); MAX_OP_PARAMS].map(|_| self.read_array().map(i32::from_le_bytes));
+        let op_params = op_params.into_iter().collect::<io::Result<Vec<_>>>()?;
+        let name = self.read_array::<MAX_NAME>()?;
+        let name = name.split(|c| *c == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let mut read_index = || -> Result<Option<usize>> {
+            let src = self.read_u32()?;
+            if src == NO_TENSOR {
+                return Ok(None);
+            }
+            ensure!(
+                (src as usize) < index,
+                bad("source must precede the tensor")
+            );
+            Ok(Some(src as usize))
+        };
+        let sources = [(); MAX_SRC].map(|_| read_index());
+        let sources = sources.into_iter().collect::<Result<Vec<_>>>()?;
+        let view_src = read_index()?;
+        let view_offs = usize::try_from(self.read_u64()?).map_err(|_e| bad("bad view"))?;
+
+        let mut ft = GGraphFileTensor {
+            name,
+            typ,
+            op,
+            ggml_ne: ne[..n_dims].to_vec(),
+            is_param: is_param? != 0,
+            nb,
+            op_params: op_params
+                .try_into()
+                .expect("Impossible: Op params count mismatch"),
+            sources: sources
+                .try_into()
+                .expect("Impossible: Source count mismatch"),
+            view: view_src
+                .filter(|_| op != GOp::None)
+                .map(|src| (src, view_offs)),
+            data: vec![],
+        };
+        let nbytes = ft.check(index, prev)?;
+        if op == GOp::None {
+            // Using take means a corrupt shape can't make us allocate a huge buffer up front.
+            let got = (&mut self.rdr)
+                .take(nbytes as u64)
+                .read_to_end(&mut ft.data)?;
+            if got != nbytes {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+            }
+        }
+        Ok(ft)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        gtensor::{GMulMat, GTensor},
+        map_unop,
+    };
+
+    fn data(t: &GAnyTensor) -> Result<Vec<u8>> {
+        unsafe { t.with_data(|buf| buf.to_vec()) }
+    }
+
+    // Returns the offset of the tensor at `index` in the file `gf` was read from.
+    fn tensor_offset(gf: &GGraphFile, index: usize) -> usize {
+        let fixed = 4 * 4 + 8 * MAX_DIMS * 2 + 4 * MAX_OP_PARAMS + MAX_NAME + 4 * MAX_SRC + 12;
+        let data = gf.tensors[..index].iter().map(|ft| ft.data.len());
+        6 * 4 + index * fixed + data.sum::<usize>()
+    }
+
+    fn index_of(gf: &GGraphFile, op: GOp) -> usize {
+        gf.tensors.iter().position(|ft| ft.op == op).unwrap()
+    }
+
+    fn index_of_name(gf: &GGraphFile, name: &str) -> usize {
+        gf.tensors.iter().position(|ft| ft.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_graph_file_round_trip() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let mut a = ctx.tensor(GType::F32, [2, 3])?;
+        a.set_name("a")?;
+        a.populate_f32([1.0, -2.0, 3.0, -4.0, 5.0, -6.0]);
+        let mut w = ctx.tensor(GType::F32, [2, 3])?;
+        w.set_name("w")?;
+        w.fill_f32(2.0);
+        let x = (&a * &w).relu();
+        let mut out = x.transpose();
+        out.set_name("out")?;
+        let mut sum = x.sum::<1>();
+        sum.set_name("sum")?;
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&out)?;
+        g.build_forward_expand(&sum)?;
+        ctx.compute(&mut g)?;
+
+        let mut buf = vec![];
+        g.write(&mut buf)?;
+        let gf = GGraphFile::read(Cursor::new(&buf))?;
+        assert_eq!(gf.tensors.len(), g.n_leafs() + g.n_nodes());
+        assert_eq!(gf.tensors[0].shape(), [2, 3]);
+        assert_eq!(gf.outputs.len(), 2);
+
+        let loaded_ctx = gf.context_builder().build()?;
+        let mut loaded = gf.build(&loaded_ctx)?;
+        let mem_size = gf
+            .tensors
+            .iter()
+            .map(|ft| ft.context_bytes())
+            .sum::<usize>();
+        assert_eq!(loaded.ctx.used_mem()?, mem_size);
+        assert_eq!(loaded.graph.to_dot()?, g.to_dot()?);
+        let mut names = loaded.inputs.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a", "w"]);
+
+        loaded.ctx.compute(&mut loaded.graph)?;
+        assert_eq!(data(&loaded.outputs["out"])?, data(&out.into())?);
+        let loaded_sum = loaded.outputs["sum"].clone().into_typed::<1>()?;
+        assert_eq!(data(&loaded_sum.clone().into())?, data(&sum.into())?);
+
+        let mut loaded_w: GTensor<2> = loaded.inputs["w"].clone().try_into()?;
+        loaded_w.fill_f32(1.0);
+        loaded.ctx.compute(&mut loaded.graph)?;
+        let mut result = [0.0];
+        loaded_sum.copy_to_slice_f32(&mut result)?;
+        assert_eq!(result, [9.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_file_ops() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let input = |name: &str, typ, shape: [usize; 2]| -> Result<GTensor<2>> {
+            let mut t = ctx.tensor(typ, shape)?;
+            t.set_name(name)?;
+            Ok(t)
+        };
+        let a = input("a", GType::F32, [4, 8])?;
+        let k = input("k", GType::F32, [3, 8])?;
+        let kernel = input("kernel", GType::F32, [3, 4])?;
+        let row = input("row", GType::F32, [1, 8])?;
+        let mut s = ctx.tensor(GType::F32, [1])?;
+        s.set_name("s")?;
+        let mut rows = ctx.tensor(GType::I32, [2])?;
+        rows.set_name("rows")?;
+        let mut dst = input("dst", GType::F32, [4, 3])?;
+
+        let x = (&a + &row).scale(&s) - &a;
+        let mm = x.mul_mat(&k).soft_max().norm(1e-5).rms_norm(1e-5);
+        let mm = mm.diag_mask_inf(0).rope(0, 2, 0, 8).neg().sqr().sqrt();
+        let y = mm.transpose().cont().reshape([4, 3]);
+        dst.copy_from(&y);
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(dst.view([4], [2]).mean::<1>())?;
+        g.build_forward_expand(row.repeat(&a).permute([1, 0, 2, 3]))?;
+        g.build_forward_expand(x.get_rows::<1, 2, _>(&rows))?;
+        g.build_forward_expand((&x / &x).sum::<1>())?;
+        g.build_forward_expand(kernel.conv_1d::<2, 2, _>(&k, 1, 1, 1))?;
+
+        let mut buf = vec![];
+        g.write(&mut buf)?;
+        let gf = GGraphFile::read(Cursor::new(&buf))?;
+        let loaded = gf.build(&gf.context_builder().build()?)?;
+        assert_eq!(loaded.graph.to_dot()?, g.to_dot()?);
+        assert_eq!(
+            gf.tensors.iter().filter(|ft| ft.op != GOp::None).count(),
+            24
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_file_errors() -> Result<()> {
+        let err = GGraphFile::read(Cursor::new([0u8; 32])).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GGraphFileError>(),
+            Some(&GGraphFileError::BadMagic(0))
+        );
+
+        let mut header = [0u8; 6 * 4];
+        header[..4].copy_from_slice(&GGRAPH_FILE_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&GGRAPH_FILE_VERSION.to_le_bytes());
+        let err = GGraphFile::read(Cursor::new(header)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GGraphFileError>(),
+            Some(&GGraphFileError::BadThreadCount(0))
+        );
+        header[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let gf = GGraphFile::read(Cursor::new(header))?;
+        assert!(gf.tensors.is_empty());
+        assert_eq!(
+            gf.n_threads,
+            thread::available_parallelism().map_or(1, |n| n.get())
+        );
+
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let mut a = ctx.tensor(GType::F32, [4])?;
+        a.set_name("x")?;
+        let mut b = ctx.tensor(GType::F32, [4])?;
+        b.set_name("x")?;
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&a + &b)?;
+        let err = g.write(vec![]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GGraphFileError>(),
+            Some(&GGraphFileError::DuplicateInput("x".to_string()))
+        );
+
+        b.set_name("y")?;
+        let mut buf = vec![];
+        g.write(&mut buf)?;
+        assert!(GGraphFile::read(Cursor::new(&buf[..buf.len() - 1])).is_err());
+        // Point the first source of the node at itself.
+        let src_offs = buf.len() - 4 - 12 - 4 * MAX_SRC;
+        buf[src_offs..src_offs + 4].copy_from_slice(&2u32.to_le_bytes());
+        let err = GGraphFile::read(Cursor::new(&buf)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GGraphFileError>(),
+            Some(&GGraphFileError::BadTensor {
+                index: 2,
+                reason: "source must precede the tensor"
+            })
+        );
+
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(a.map_unary(map_unop!(|v| v + 1.0)))?;
+        let err = g.write(vec![]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GGraphFileError>(),
+            Some(GGraphFileError::UnsupportedOp {
+                op: GOp::MapUnary,
+                ..
+            })
+        ));
+
+        let input = |name: &str, shape: [usize; 2]| -> Result<GTensor<2>> {
+            let mut t = ctx.tensor(GType::F32, shape)?;
+            t.set_name(name)?;
+            Ok(t)
+        };
+        let (p, q, r) = (
+            input("p", [2, 4])?,
+            input("q", [3, 4])?,
+            input("r", [4, 6])?,
+        );
+        let r2 = r.sqr();
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&r2)?;
+        g.build_forward_expand(p.mul_mat(&q).relu())?;
+        g.build_forward_expand(&r2)?;
+        let mut buf = vec![];
+        g.write(&mut buf)?;
+        let gf = GGraphFile::read(Cursor::new(&buf))?;
+        assert_eq!(gf.outputs.len(), 2);
+        let bad_tensor = |buf: &[u8]| {
+            let err = GGraphFile::read(Cursor::new(buf)).unwrap_err();
+            err.downcast_ref::<GGraphFileError>().cloned()
+        };
+
+        // Expanding with the same tensor again doesn't make it a duplicate output.
+        let mut dup = buf.clone();
+        let len = dup.len();
+        dup.copy_within(len - 8..len - 4, len - 4);
+        assert_eq!(GGraphFile::read(Cursor::new(&dup))?.outputs.len(), 1);
+
+        // Make the second source of the MUL_MAT a tensor it can't be multiplied with.
+        let (mul_mat, r_idx) = (index_of(&gf, GOp::MulMat), index_of_name(&gf, "r"));
+        let mut bad = buf.clone();
+        let src_offs = tensor_offset(&gf, mul_mat) + 16 + 16 * MAX_DIMS + 4 * MAX_OP_PARAMS;
+        let src_offs = src_offs + MAX_NAME + 4;
+        bad[src_offs..src_offs + 4].copy_from_slice(&(r_idx as u32).to_le_bytes());
+        assert_eq!(
+            bad_tensor(&bad),
+            Some(GGraphFileError::BadTensor {
+                index: mul_mat,
+                reason: "sources or parameters don't match the operation"
+            })
+        );
+
+        // An UNARY tensor with an operation that doesn't exist.
+        let unary = index_of(&gf, GOp::Unary);
+        let mut bad = buf.clone();
+        let param_offs = tensor_offset(&gf, unary) + 16 + 16 * MAX_DIMS;
+        bad[param_offs..param_offs + 4].copy_from_slice(&99i32.to_le_bytes());
+        assert_eq!(
+            bad_tensor(&bad),
+            Some(GGraphFileError::BadTensor {
+                index: unary,
+                reason: "sources or parameters don't match the operation"
+            })
+        );
+
+        // The tensors are checked again when the graph is built.
+        let mut gf = gf;
+        gf.tensors[mul_mat].typ = GType::I32;
+        let err = gf.build(&gf.context_builder().build()?).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<GGraphFileError>(),
+            Some(&GGraphFileError::BadTensor {
+                index: mul_mat,
+                reason: "type or shape doesn't match the operation"
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use std::ptr::NonNull;
+
+use anyhow::{bail, ensure, Result};
+
+use ggml_sys_bleedingedge as gg;
+
+use super::tensor::*;
+use crate::{
+    context::{GContext, IContext},
+    dims::*,
+    util::{GOp, GType, GUnaryOp},
+    validation::GMemoryRequest,
+};
+
+macro_rules! with_any_tensor {
+    ( $val:expr, $tid:ident => $body:expr ) => {
+        match $val {
+            GAnyTensor::D1($tid) => $body,
+            GAnyTensor::D2($tid) => $body,
+            GAnyTensor::D3($tid) => $body,
+            GAnyTensor::D4($tid) => $body,
+        }
+    };
+}
+
+#[derive(Clone)]
+/// A [GTensor] with the dimensions erased. This is useful when the number
+/// of dimensions is only known at runtime, for example when loading a model file.
+///
+/// Use [GAnyTensor::into_typed] or `TryFrom` to get the [GTensor] back.
+pub enum GAnyTensor {
+    D1(GTensor<1>),
+    D2(GTensor<2>),
+    D3(GTensor<3>),
+    D4(GTensor<4>),
+}
+
+impl GAnyTensor {
+    /// Creates a [GAnyTensor] for a tensor that already exists in the context.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held. The tensor must belong to the context.
+    pub(crate) unsafe fn from_existing_ptr(
+        ctx: &GContext,
+        ictx: &IContext,
+        tptr: NonNull<gg::ggml_tensor>,
+    ) -> Result<Self> {
+        Ok(match tptr.as_ref().n_dims {
+            1 => Self::D1(GTensor::from_existing_ptr(ctx, ictx, tptr)?),
+            2 => Self::D2(GTensor::from_existing_ptr(ctx, ictx, tptr)?),
+            3 => Self::D3(GTensor::from_existing_ptr(ctx, ictx, tptr)?),
+            4 => Self::D4(GTensor::from_existing_ptr(ctx, ictx, tptr)?),
+            n => bail!("Unexpected number of dimensions {n}"),
+        })
+    }
+
+    /// Creates a [GAnyTensor] for a newly created tensor. See [GTensor::new_from_ptr].
+    ///
+    /// # Safety
+    /// Must be called with context mutex held.
+    pub(crate) unsafe fn new_from_ptr(
+        ctx: &GContext,
+        ictx: &mut IContext,
+        (mr, p): (GMemoryRequest, *mut gg::ggml_tensor),
+    ) -> Result<Self> {
+        let n_dims = p.as_ref().ok_or(GTensorError::NullPointer)?.n_dims;
+        Ok(match n_dims {
+            1 => Self::D1(GTensor::new_from_ptr(ctx, ictx, (mr, p))?),
+            2 => Self::D2(GTensor::new_from_ptr(ctx, ictx, (mr, p))?),
+            3 => Self::D3(GTensor::new_from_ptr(ctx, ictx, (mr, p))?),
+            4 => Self::D4(GTensor::new_from_ptr(ctx, ictx, (mr, p))?),
+            n => bail!("Unexpected number of dimensions {n}"),
+        })
+    }
+
+    /// Return the number of dimensions for this tensor.
+    pub fn dims(&self) -> usize {
+        with_any_tensor!(self, t => t.dims())
+    }
+
+    /// Returns the tensor data length in bytes.
+    pub fn len(&self) -> usize {
+        with_any_tensor!(self, t => t.len())
+    }
+
+    /// `true` if the tensor is empty.
+    pub fn is_empty(&self) -> bool {
+        with_any_tensor!(self, t => t.is_empty())
+    }
+
+    /// Returns the number of elements in this tensor.
+    pub fn elements(&self) -> usize {
+        with_any_tensor!(self, t => t.elements())
+    }
+
+    /// Returns the element type.
+    pub fn element_type(&self) -> GType {
+        with_any_tensor!(self, t => t.element_type())
+    }
+
+    /// `true` if the tensor's data is contiguous in memory.
+    pub fn is_contiguous(&self) -> bool {
+        with_any_tensor!(self, t => t.metadata().is_contiguous())
+    }
+
+    /// Returns the operation associated with this tensor.
+    ///
+    /// See [GTensor::op].
+    pub fn op(&self) -> GOp {
+        with_any_tensor!(self, t => t.op())
+    }
+
+    /// Returns the specific operation for [GOp::Unary] tensors.
+    ///
+    /// See [GTensor::unary_op].
+    pub fn unary_op(&self) -> Option<GUnaryOp> {
+        with_any_tensor!(self, t => t.unary_op())
+    }
+
+    /// Return the shape of this tensor. The length of the result will
+    /// be equal to the tensor's dimensions.
+    pub fn shape(&self) -> Vec<usize> {
+        with_any_tensor!(self, t => t.shape().to_vec())
+    }
+
+    /// Returns the tensor's name.
+    ///
+    /// See [GTensor::name].
+    pub fn name(&self) -> Result<String> {
+        with_any_tensor!(self, t => t.name())
+    }
+
+    /// Returns GGML's conception of this tensor's shape.
+    ///
+    /// See [GTensor::get_ne].
+    pub fn get_ne(&self) -> [u32; 4] {
+        with_any_tensor!(self, t => t.get_ne())
+    }
+
+    /// Returns GGML's conception of this tensor's strides in bytes.
+    ///
+    /// See [GTensor::get_nb].
+    pub fn get_nb(&self) -> [u32; 4] {
+        with_any_tensor!(self, t => t.get_nb())
+    }
+
+    /// Low level function that allows mutably accessing a tensor's
+    /// data as a slice of `u8`.
+    ///
+    /// # Safety
+    /// See [GTensor::with_data_mut].
+    pub unsafe fn with_data_mut<F, O>(&mut self, fun: F) -> Result<O>
+    where
+        F: FnOnce(&mut [u8]) -> O,
+    {
+        with_any_tensor!(self, t => t.with_data_mut(fun))
+    }
+
+    /// Low level function that allows accessing a tensor's
+    /// data as a slice of `u8`.
+    ///
+    /// # Safety
+    /// See [GTensor::with_data].
+    pub unsafe fn with_data<F, O>(&self, fun: F) -> Result<O>
+    where
+        F: FnOnce(&[u8]) -> O,
+    {
+        with_any_tensor!(self, t => t.with_data(fun))
+    }
+
+    /// Convert to a [GTensor] with the specified dimensions.
+    ///
+    /// **Invariants**
+    /// 1. `DIMS` must match the number of dimensions of the tensor.
+    pub fn into_typed<const DIMS: usize>(self) -> Result<GTensor<DIMS>>
+    where
+        Dim<DIMS>: DimValid,
+    {
+        let dims = self.dims();
+        ensure!(
+            dims == DIMS,
+            GTensorError::DimensionMismatch {
+                got: dims,
+                expected: DIMS
+            }
+        );
+        let (ctx, tptr, scratch_ref, generation) =
+            with_any_tensor!(self, t => (t.ctx, t.tptr, t.scratch_ref, t.generation));
+        let md = ctx.with_icontext_read(|_ctx, ictx| {
+            ictx.ensure_live(tptr.as_ptr(), generation)?;
+            GTensorMetadata::from_ptr(tptr)
+        })?;
+        Ok(GTensor {
+            ctx,
+            md,
+            tptr,
+            scratch_ref,
+            generation,
+        })
+    }
+}
+
+macro_rules! mk_anytensor_conversions {
+    ( $( ($dims:literal, $variant:ident) ),+ ) => { $(
+        impl From<GTensor<$dims>> for GAnyTensor {
+            fn from(value: GTensor<$dims>) -> Self {
+                Self::$variant(value)
+            }
+        }
+
+        impl TryFrom<GAnyTensor> for GTensor<$dims> {
+            type Error = anyhow::Error;
+
+            fn try_from(value: GAnyTensor) -> Result<Self> {
+                value.into_typed()
+            }
+        }
+    )*};
+}
+
+mk_anytensor_conversions!((1, D1), (2, D2), (3, D3), (4, D4));
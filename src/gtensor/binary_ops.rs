@@ -6,13 +6,23 @@ use super::tensor::*;
 use crate::{dims::*, util::GType, validation::*};
 
 macro_rules! mk_simple_bops {
-  ( $( $(#[$attr:meta])* [$opname:ident, $gfname:ident]),* $(,)? ) => { $(
+  ( $( $(#[$attr:meta])* [$opname:ident, $try_opname:ident, $gfname:ident, $check:ident]),* $(,)? ) => { $(
     $(#[$attr])*
     pub fn $opname<T: AsRef<GTensor<DIMS>>>(&self, rhs: T) -> Self {
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.$try_opname(rhs))
+    }
+
+    #[doc = concat!("Fallible version of [Self::", stringify!($opname), "].")]
+    pub fn $try_opname<T: AsRef<GTensor<DIMS>>>(&self, rhs: T) -> Result<Self, GTensorError> {
+        let rmd = rhs.as_ref().md.clone();
+        self.try_new_binary(stringify!($opname), rhs, |ctx, ictx, ltptr, rtptr| {
+            if !rmd.$check(&self.md) {
+                Err(GTensorError::ShapeMismatch { lhs: self.md.ggml_ne, rhs: rmd.ggml_ne })?;
+            }
             let mr = GMemoryRequest::estimate_tensor_request_ictx(
-                ctx, ictx, self.md.typ, self.md.shape
-            ).fit_or_die()?;
+                ctx, ictx, self.md.typ, self.md.ggml_shape()
+            ).fit_or_grow(ictx)?;
             Ok((mr, unsafe { gg::$gfname(ictx.gptr(), ltptr, rtptr) }))
         })
     }
@@ -56,7 +66,9 @@ where
         /// `a.add(b)` or `a + b`
         ///
         /// **Invariants**
-        /// 1. `A` and `B` must have the same shape.
+        /// 1. `A` and `B` must have the same number of columns, and each of
+        ///    the other dimensions of `A` must be a multiple of that of `B`.
+        ///    The rows of `B` are repeated as needed.
         /// 2. Result will have the shape of `A`.
         ///
         /// **Example** (pseudocode):
@@ -66,7 +78,7 @@ where
         /// let result = a.add(b);
         /// assert_eq!(result, [3, 3, 3]);
         /// ```
-        [add,ggml_add],
+        [add, try_add, ggml_add, can_repeat_rows_with],
         /// Subtract tensor `B` from tensor `A`.
         /// Returns a new tensor.
         ///
@@ -83,7 +95,7 @@ where
         /// let result = a.div(b);
         /// assert_eq!(result, [2, 2, 2]);
         /// ```
-        [sub,ggml_sub],
+        [sub, try_sub, ggml_sub, is_same_shape],
 
         /// Multiply tensor `A` by tensor `B`.
         /// Returns a new tensor.
@@ -93,7 +105,9 @@ where
         /// `a.mul(b)` or `a * b`
         ///
         /// **Invariants**
-        /// 1. `A` and `B` must have the same shape.
+        /// 1. `A` and `B` must have the same number of columns, and each of
+        ///    the other dimensions of `A` must be a multiple of that of `B`.
+        ///    The rows of `B` are repeated as needed.
         /// 2. Result will have the shape of `A`.
         ///
         /// **Example** (pseudocode):
@@ -103,7 +117,7 @@ where
         /// let result = a.mul(b);
         /// assert_eq!(result, [6, 6, 6]);
         /// ```
-        [mul,ggml_mul],
+        [mul, try_mul, ggml_mul, can_repeat_rows_with],
 
         /// Elementwise divide tensor `A` by tensor `B`.
         /// Returns a new tensor.
@@ -121,7 +135,7 @@ where
         /// let result = a.div(b);
         /// assert_eq!(result, [3, 3, 3]);
         /// ```
-        [div,ggml_div],
+        [div, try_div, ggml_div, is_same_shape],
     }
 
     /// Scale tensor `A` by tensor `B`.
@@ -146,10 +160,37 @@ where
         Dim<RDIMS>: DimValid,
         DimPair<1, RDIMS>: DimEq,
     {
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, self.md.shape)
-                    .fit_or_die()?;
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_scale(rhs))
+    }
+
+    /// Fallible version of [Self::scale].
+    pub fn try_scale<const RDIMS: usize, T: AsRef<GTensor<RDIMS>>>(
+        &self,
+        rhs: T,
+    ) -> Result<Self, GTensorError>
+    where
+        Dim<RDIMS>: DimValid,
+        DimPair<1, RDIMS>: DimEq,
+    {
+        let rmd = rhs.as_ref().md.clone();
+        self.try_new_binary("scale", rhs, |ctx, ictx, ltptr, rtptr| {
+            if !rmd.is_scalar() {
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rmd.ggml_ne,
+                })?;
+            }
+            if !self.md.is_padded_1d() {
+                Err(GTensorError::InvalidOperation)?;
+            }
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             Ok((mr, unsafe { gg::ggml_scale(ictx.gptr(), ltptr, rtptr) }))
         })
     }
@@ -187,16 +228,40 @@ where
     /// assert_eq!(result, expected);
     /// ```
     pub fn repeat<const RDIMS: usize, T: AsRef<GTensor<RDIMS>>>(&self, rhs: T) -> GTensor<RDIMS>
+    where
+        Dim<RDIMS>: DimValid,
+        DimPair<DIMS, 3>: DimLt,
+        DimPair<RDIMS, 3>: DimLt,
+    {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_repeat(rhs))
+    }
+
+    /// Fallible version of [Self::repeat].
+    pub fn try_repeat<const RDIMS: usize, T: AsRef<GTensor<RDIMS>>>(
+        &self,
+        rhs: T,
+    ) -> Result<GTensor<RDIMS>, GTensorError>
     where
         Dim<RDIMS>: DimValid,
         DimPair<DIMS, 3>: DimLt,
         DimPair<RDIMS, 3>: DimLt,
     {
         let rmd = rhs.as_ref().md.clone();
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, rmd.shape)
-                    .fit_or_die()?;
+        self.try_new_binary("repeat", rhs, |ctx, ictx, ltptr, rtptr| {
+            if !self.md.can_repeat_with(&rmd) {
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rmd.ggml_ne,
+                })?;
+            }
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                rmd.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             Ok((mr, unsafe { gg::ggml_repeat(ictx.gptr(), ltptr, rtptr) }))
         })
     }
@@ -211,6 +276,26 @@ where
         p0: usize,
         d0: usize,
     ) -> Self
+    where
+        Dim<RDIMS>: DimValid,
+        Dim<ODIMS>: DimValid,
+        DimPair<DIMS, 2>: DimGtE,
+        DimPair<DIMS, 4>: DimLt,
+        DimPair<RDIMS, 2>: DimGtE,
+        DimPair<ODIMS, 2>: DimEq,
+    {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_conv_1d(rhs, s0, p0, d0))
+    }
+
+    /// Fallible version of [Self::conv_1d].
+    pub fn try_conv_1d<const RDIMS: usize, const ODIMS: usize, T: AsRef<GTensor<RDIMS>>>(
+        &self,
+        rhs: T,
+        s0: usize,
+        p0: usize,
+        d0: usize,
+    ) -> Result<Self, GTensorError>
     where
         Dim<RDIMS>: DimValid,
         Dim<ODIMS>: DimValid,
@@ -220,15 +305,30 @@ where
         DimPair<ODIMS, 2>: DimEq,
     {
         let rmd = rhs.as_ref().md.clone();
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            // FIXME: Double check this calculation.
-            let shp = match ODIMS {
-                2 => vec![self.md.ggml_ne[2] as usize, rmd.ggml_ne[1] as usize],
-                3 => vec![self.md.ggml_ne[2] as usize, rmd.ggml_ne[1] as usize / 2],
-                _ => Err(GTensorError::InvalidOperation)?,
-            };
-            let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::F32, shp)
-                .fit_or_die()?;
+        self.try_new_binary("conv_1d", rhs, |ctx, ictx, ltptr, rtptr| {
+            if s0 == 0 {
+                Err(GTensorError::InvalidOperation)?;
+            }
+            let (ne, rne) = (self.md.ggml_shape(), rmd.ggml_shape());
+            // GGML aborts unless `B` is a matrix with one row per channel of the kernel.
+            if ne[1] != rne[1] || rne[2] != 1 || rne[3] != 1 {
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rmd.ggml_ne,
+                })?;
+            }
+            let (s0i, p0i, d0i) = (s0 as i64, p0 as i64, d0 as i64);
+            let out_len = (rne[0] as i64 + 2 * p0i - d0i * (ne[0] as i64 - 1) - 1) / s0i + 1;
+            if out_len < 1 {
+                Err(GTensorError::InvalidOperation)?;
+            }
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                GType::F32,
+                [out_len as usize, ne[2]],
+            )
+            .fit_or_grow(ictx)?;
             Ok((mr, unsafe {
                 gg::ggml_conv_1d(ictx.gptr(), ltptr, rtptr, s0 as i32, p0 as i32, d0 as i32)
             }))
@@ -261,9 +361,26 @@ where
     /// assert_eq!(result, expected);
     /// ```
     pub fn permute(&self, axes: [usize; 4]) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
-            let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, [])
-                .fit_or_die()?;
+        self.or_dead(self.try_permute(axes))
+    }
+
+    /// Fallible version of [Self::permute].
+    pub fn try_permute(&self, axes: [usize; 4]) -> Result<Self, GTensorError> {
+        self.try_new_unary("permute", |ctx, ictx, tptr| {
+            let mut seen = [false; 4];
+            for &axis in axes.iter() {
+                if axis >= seen.len() || seen[axis] {
+                    Err(GTensorError::InvalidOperation)?;
+                }
+                seen[axis] = true;
+            }
+            let mr = GMemoryRequest::estimate_view_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe {
                 Ok((
                     mr,
@@ -287,14 +404,39 @@ where
         &self,
         rhs: T,
     ) -> GTensor<RDIMS>
+    where
+        Dim<RDIMS>: DimValid,
+    {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_reshape_with(rhs))
+    }
+
+    /// Fallible version of [Self::reshape_with].
+    pub fn try_reshape_with<const RDIMS: usize, T: AsRef<GTensor<RDIMS>>>(
+        &self,
+        rhs: T,
+    ) -> Result<GTensor<RDIMS>, GTensorError>
     where
         Dim<RDIMS>: DimValid,
     {
         let rmd = rhs.as_ref().md.clone();
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, rmd.shape)
-                    .fit_or_die()?;
+        self.try_new_binary("reshape_with", rhs, |ctx, ictx, ltptr, rtptr| {
+            if !self.md.is_contiguous() || !rmd.is_contiguous() {
+                Err(GTensorError::InvalidOperation)?;
+            }
+            if self.md.len_elements != rmd.len_elements {
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rmd.ggml_ne,
+                })?;
+            }
+            let mr = GMemoryRequest::estimate_view_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                rmd.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             Ok((mr, unsafe { gg::ggml_reshape(ictx.gptr(), ltptr, rtptr) }))
         })
     }
@@ -306,6 +448,22 @@ where
         &self,
         rhs: T,
     ) -> GTensor<ODIMS>
+    where
+        Dim<RDIMS>: DimValid,
+        Dim<ODIMS>: DimValid,
+        DimPair<DIMS, 2>: DimGtE,
+        DimPair<RDIMS, 2>: DimLt,
+        DimPair<ODIMS, 2>: DimEq,
+    {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_get_rows(rhs))
+    }
+
+    /// Fallible version of [Self::get_rows].
+    pub fn try_get_rows<const RDIMS: usize, const ODIMS: usize, T: AsRef<GTensor<RDIMS>>>(
+        &self,
+        rhs: T,
+    ) -> Result<GTensor<ODIMS>, GTensorError>
     where
         Dim<RDIMS>: DimValid,
         Dim<ODIMS>: DimValid,
@@ -314,14 +472,20 @@ where
         DimPair<ODIMS, 2>: DimEq,
     {
         let rmd = rhs.as_ref().md.clone();
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
+        self.try_new_binary("get_rows", rhs, |ctx, ictx, ltptr, rtptr| {
+            if rmd.typ != GType::I32 {
+                Err(GTensorError::TypeMismatch)?;
+            }
+            if !self.md.is_matrix() {
+                Err(GTensorError::InvalidOperation)?;
+            }
             let mr = GMemoryRequest::estimate_tensor_request_ictx(
                 ctx,
                 ictx,
                 GType::F32,
-                [self.md.shape[1], rmd.shape[0]],
+                [self.md.ggml_ne[0] as usize, rmd.ggml_ne[0] as usize],
             )
-            .fit_or_die()?;
+            .fit_or_grow(ictx)?;
             Ok((mr, unsafe { gg::ggml_get_rows(ictx.gptr(), ltptr, rtptr) }))
         })
     }
@@ -331,7 +495,11 @@ mk_gopinstances!((Add, add), (Sub, sub), (Mul, mul), (Div, div));
 
 #[cfg(test)]
 mod tests {
-    use crate::{context::*, gtensor::GMulMat, util::GType};
+    use crate::{
+        context::*,
+        gtensor::{GMulMat, GTensorError},
+        util::GType,
+    };
     use anyhow::Result;
 
     macro_rules! test_binop_simple {
@@ -412,4 +580,212 @@ mod tests {
             4.0, 5.0, 4.0, 5.0,
         ]
     ));
+
+    #[test]
+    pub fn test_try_ops() -> Result<()> {
+        // Room for the inputs but not the result.
+        let ctx = GContextBuilder::new().mem_size(160 * 1024).build()?;
+        let mut ta = ctx.tensor(GType::F32, [4, 4096])?;
+        ta.populate_f32(vec![2.0; 4 * 4096]);
+        let mut tb = ctx.tensor(GType::F32, [4, 4096])?;
+
+        match ta.try_add(&tb) {
+            Err(GTensorError::Failed(failure)) => {
+                assert_eq!(failure.op, "add");
+                assert!(failure.memory_request.is_some());
+            }
+            _ => panic!("Expected add to fail"),
+        }
+        let tc = ctx.tensor(GType::F32, [3])?;
+        assert!(matches!(
+            tc.try_mul_mat(&tb),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            tb.try_populate_f32([1.0]),
+            Err(GTensorError::BadPopulate {
+                got: 1,
+                expected: _
+            })
+        ));
+
+        // The context is still usable.
+        let t = ta.try_view([4], [0])?.try_sqr()?;
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+        ctx.compute(&mut g)?;
+        let mut output = [0.0; 4];
+        t.copy_to_slice_f32(&mut output)?;
+        assert_eq!(output, [4.0; 4]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_shape_mismatch() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let ta = ctx.tensor(GType::F32, [2, 3])?;
+        let tb = ctx.tensor(GType::F32, [1, 3])?;
+        let tc = ctx.tensor(GType::F32, [2, 4])?;
+        let td = ctx.tensor(GType::F32, [4, 3])?;
+
+        // Rows of `B` are repeated for add and mul.
+        assert_eq!(ta.try_add(&tb)?.shape(), ta.shape());
+        assert_eq!(ta.try_mul(&tb)?.shape(), ta.shape());
+        assert!(matches!(
+            ta.try_add(&tc),
+            Err(GTensorError::ShapeMismatch {
+                lhs: [3, 2, 1, 1],
+                rhs: [4, 2, 1, 1]
+            })
+        ));
+        assert!(matches!(
+            ta.try_mul(&td),
+            Err(GTensorError::ShapeMismatch { .. })
+        ));
+        assert_eq!(ta.try_conv_1d::<2, 2, _>(&tc, 1, 0, 1)?.shape(), [2, 1]);
+        assert!(matches!(
+            ta.try_conv_1d::<2, 2, _>(&td, 1, 0, 1),
+            Err(GTensorError::ShapeMismatch {
+                lhs: [3, 2, 1, 1],
+                rhs: [3, 4, 1, 1]
+            })
+        ));
+        assert!(matches!(
+            ta.try_sub(&tb),
+            Err(GTensorError::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            ta.try_div(&tc),
+            Err(GTensorError::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            ta.try_repeat(&tc),
+            Err(GTensorError::ShapeMismatch {
+                lhs: [3, 2, 1, 1],
+                rhs: [4, 2, 1, 1]
+            })
+        ));
+        assert_eq!(ta.try_repeat(&td)?.shape(), td.shape());
+        assert!(matches!(
+            ta.try_reshape_with(&td),
+            Err(GTensorError::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            ta.transpose().try_reshape_with(&ta),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            ta.try_permute([0, 0, 1, 2]),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            ta.try_permute([0, 1, 2, 4]),
+            Err(GTensorError::InvalidOperation)
+        ));
+
+        // The context is still usable.
+        ta.try_permute([1, 0, 2, 3])?;
+        assert_eq!(ta.try_sub(&ta)?.shape(), ta.shape());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_try_ops_errors() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let ta = ctx.tensor(GType::F32, [2, 3])?;
+        let tb = ctx.tensor(GType::F32, [3])?;
+        let tc = ctx.tensor(GType::F32, [3, 2])?;
+        let ti = ctx.tensor(GType::I32, [2])?;
+        let mut td = ctx.tensor(GType::F32, [4])?;
+
+        assert!(matches!(
+            ta.try_scale(&tb),
+            Err(GTensorError::ShapeMismatch { .. })
+        ));
+        let scalar = ctx.tensor(GType::F32, [1])?;
+        assert!(matches!(
+            ta.transpose().try_scale(&scalar),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            ta.try_get_rows::<1, 2, _>(&tb),
+            Err(GTensorError::TypeMismatch)
+        ));
+        let t3 = ctx.tensor(GType::F32, [2, 2, 3])?;
+        assert!(matches!(
+            t3.try_get_rows::<1, 2, _>(&ti),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            ta.try_conv_1d::<2, 2, _>(&ta, 0, 0, 1),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            ta.try_map_binary(&tc, crate::map_binop!(|a, b| a + b)),
+            Err(GTensorError::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            ti.try_map_binary(&ti, crate::map_binop!(|a, b| a + b)),
+            Err(GTensorError::TypeMismatch)
+        ));
+        let before = td.clone();
+        assert!(matches!(
+            td.try_copy_from(&tb),
+            Err(GTensorError::ShapeMismatch {
+                lhs: [4, 1, 1, 1],
+                rhs: [3, 1, 1, 1]
+            })
+        ));
+        assert_eq!(td.op(), before.op());
+
+        // The context is still usable.
+        assert_eq!(ta.try_scale(&scalar)?.shape(), ta.shape());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_cross_context_op() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let other = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let mut ta = ctx.tensor(GType::F32, [3])?;
+        let mut tb = other.tensor(GType::F32, [3])?;
+        ta.populate_f32([1.0, 2.0, 3.0]);
+        tb.populate_f32([10.0, 20.0, 30.0]);
+        let t = ta.try_add(&tb)?;
+        // The other context stays alive while this one uses its data.
+        drop(tb);
+        drop(other);
+        let mut g = GGraph::new(1);
+        g.build_forward_expand(&t)?;
+        ctx.compute(&mut g)?;
+        let mut output = [0.0; 3];
+        t.copy_to_slice_f32(&mut output)?;
+        assert_eq!(output, [11.0, 22.0, 33.0]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_mul_mat_bad_dims() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let ta = ctx.tensor(GType::F32, [3])?;
+        let tb = ctx.tensor(GType::F32, [2, 3])?;
+        // The result would have 2 dimensions.
+        assert!(matches!(
+            ta.try_mul_mat(&tb),
+            Err(GTensorError::DimensionMismatch {
+                got: 2,
+                expected: 1
+            })
+        ));
+        let tc = ctx.tensor(GType::F32, [2, 4])?;
+        assert!(matches!(
+            tb.try_mul_mat(&tc),
+            Err(GTensorError::InvalidOperation)
+        ));
+
+        let _ = &ta ^ &tb;
+        let mut g = GGraph::new(1);
+        assert!(g.build_forward_expand(&ta).is_err());
+        Ok(())
+    }
 }
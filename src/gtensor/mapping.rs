@@ -100,13 +100,25 @@ where
         &self,
         fun: unsafe extern "C" fn(arg1: ::std::os::raw::c_int, arg2: *mut f32, arg3: *const f32),
     ) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
+        self.or_dead(self.try_map_unary(fun))
+    }
+
+    /// Fallible version of [Self::map_unary].
+    pub fn try_map_unary(
+        &self,
+        fun: unsafe extern "C" fn(arg1: ::std::os::raw::c_int, arg2: *mut f32, arg3: *const f32),
+    ) -> Result<Self, GTensorError> {
+        self.try_new_unary("map_unary", |ctx, ictx, tptr| {
             if self.md.typ != GType::F32 {
                 Err(GTensorError::TypeMismatch)?;
             }
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, self.md.shape)
-                    .fit_or_die()?;
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_map_unary_f32(ictx.gptr(), tptr, Some(fun)))) }
         })
     }
@@ -158,14 +170,39 @@ where
             arg4: *const f32,
         ),
     ) -> Self {
-        let rtyp = rhs.as_ref().md.typ;
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            if self.md.typ != GType::F32 || rtyp != GType::F32 {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_map_binary(rhs, fun))
+    }
+
+    /// Fallible version of [Self::map_binary].
+    pub fn try_map_binary<T: AsRef<GTensor<DIMS>>>(
+        &self,
+        rhs: T,
+        fun: unsafe extern "C" fn(
+            arg1: ::std::os::raw::c_int,
+            arg2: *mut f32,
+            arg3: *const f32,
+            arg4: *const f32,
+        ),
+    ) -> Result<Self, GTensorError> {
+        let rmd = rhs.as_ref().md.clone();
+        self.try_new_binary("map_binary", rhs, |ctx, ictx, ltptr, rtptr| {
+            if self.md.typ != GType::F32 || rmd.typ != GType::F32 {
                 Err(GTensorError::TypeMismatch)?;
             }
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, self.md.shape)
-                    .fit_or_die()?;
+            if !self.md.is_same_shape(&rmd) {
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rmd.ggml_ne,
+                })?;
+            }
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe {
                 Ok((
                     mr,
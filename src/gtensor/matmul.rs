@@ -3,7 +3,7 @@ use std::ops;
 use ggml_sys_bleedingedge as gg;
 
 use super::tensor::*;
-use crate::{dims::*, validation::*};
+use crate::{dims::*, util::GType, validation::*};
 
 impl<const DIMS: usize> GTensor<DIMS> where Dim<DIMS>: DimValid {}
 
@@ -66,6 +66,9 @@ where
     /// assert_eq!(result, expected);
     /// ```
     fn mul_mat<T: AsRef<GTensor<RDIMS>>>(&self, rhs: T) -> Self::Output;
+
+    /// Fallible version of [Self::mul_mat].
+    fn try_mul_mat<T: AsRef<GTensor<RDIMS>>>(&self, rhs: T) -> Result<Self::Output, GTensorError>;
 }
 
 impl<const DIMS: usize> GMulMat<DIMS, DIMS> for GTensor<DIMS>
@@ -75,14 +78,19 @@ where
     type Output = GTensor<DIMS>;
 
     fn mul_mat<T: AsRef<GTensor<DIMS>>>(&self, rhs: T) -> Self::Output {
+        let rhs = rhs.as_ref();
+        self.or_dead_in(self.binary_ctx(rhs), self.try_mul_mat(rhs))
+    }
+
+    fn try_mul_mat<T: AsRef<GTensor<DIMS>>>(&self, rhs: T) -> Result<Self::Output, GTensorError> {
         let rmd = rhs.as_ref().md.clone();
-        self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            if !self.md.can_mul_mat_with(&rmd) {
+        self.try_new_binary("mul_mat", rhs, |ctx, ictx, ltptr, rtptr| {
+            if !self.md.can_mul_mat_with(&rmd) || self.md.is_transposed() {
                 Err(GTensorError::InvalidOperation)?;
             }
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, self.md.shape)
-                    .fit_or_die()?;
+            let ne = self.md.mul_mat_ne(&rmd).map(|v| v as usize);
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::F32, ne)
+                .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_mul_mat(ictx.gptr(), ltptr, rtptr))) }
         })
     }
@@ -95,27 +103,37 @@ macro_rules! mk_gmulmatinstances {
             type Output = GTensor<$o>;
 
             fn mul_mat<T: AsRef<GTensor<$r>>>(&self, rhs: T) -> Self::Output {
+                let rhs = rhs.as_ref();
+                self.or_dead_in(self.binary_ctx(rhs), self.try_mul_mat(rhs))
+            }
+
+            fn try_mul_mat<T: AsRef<GTensor<$r>>>(
+                &self,
+                rhs: T,
+            ) -> Result<Self::Output, GTensorError> {
                 let rmd = rhs.as_ref().md.clone();
-                self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-                    if !self.md.can_mul_mat_with(&rmd) {
+                self.try_new_binary("mul_mat", rhs, |ctx, ictx, ltptr, rtptr| {
+                    if !self.md.can_mul_mat_with(&rmd) || self.md.is_transposed() {
                         Err(GTensorError::InvalidOperation)?;
                     }
-                    let shp = if self.md.shape.len() < rmd.shape.len() {
-                        &self.md.shape[..]
-                    } else {
-                        &rmd.shape[..]
-                    };
+                    // GGML gives the result as many dimensions as the larger input,
+                    // so it only fits in the output if the extra ones are all 1.
+                    let ne = self.md.mul_mat_ne(&rmd).map(|v| v as usize);
+                    let got = ne
+                        .iter()
+                        .rposition(|d| *d != 1)
+                        .map_or(1, |idx| idx + 1);
+                    if got > $o {
+                        Err(GTensorError::DimensionMismatch { got, expected: $o })?;
+                    }
                     let mr =
-                        GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, shp)
-                            .fit_or_die()?;
+                        GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::F32, ne)
+                            .fit_or_grow(ictx)?;
                     unsafe {
                         let t = gg::ggml_mul_mat(ictx.gptr(), ltptr, rtptr);
-                        // FIXME: Horrible hack to pretend mul_mat has the old non-broadcasting behavior.
-                        let real_dims = (*t).ne.iter().take_while(|i| **i != 1).collect::<Vec<_>>().len();
-                        if real_dims != $o {
-                            Err(GTensorError::InvalidOperation)?;
+                        if let Some(t) = t.as_mut() {
+                            t.n_dims = $o;
                         }
-                        (*t).n_dims = $o;
                         Ok((mr, t))
                     }
                 })
@@ -1,3 +1,4 @@
+mod any;
 mod binary_ops;
 mod mapping;
 mod matmul;
@@ -6,6 +7,7 @@ mod tensor;
 mod unary_ops;
 // mod validation;
 
+pub use any::*;
 pub use binary_ops::*;
 pub use mapping::*;
 pub use matmul::*;
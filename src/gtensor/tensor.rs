@@ -1,7 +1,4 @@
-use std::{
-    ptr::NonNull,
-    sync::{atomic::Ordering::SeqCst, Arc},
-};
+use std::{ptr::NonNull, sync::Arc};
 
 use anyhow::{ensure, Result};
 use num_traits::FromPrimitive;
@@ -10,7 +7,7 @@ use thiserror::Error;
 use ggml_sys_bleedingedge as gg;
 
 use crate::{
-    context::{GContext, GContextError, IContext},
+    context::{GContext, GContextError, GFailure, GFailureInput, GOpTrace, IContext},
     dims::*,
     util::*,
     validation::*,
@@ -22,10 +19,29 @@ pub enum GTensorError {
     TypeMismatch,
     #[error("Bad data length in populate - got {got}, expected {expected}")]
     BadPopulate { got: usize, expected: usize },
+    #[error("Dimension mismatch - got {got}, expected {expected}")]
+    DimensionMismatch { got: usize, expected: usize },
+    #[error("Shape mismatch - {lhs:?} is not compatible with {rhs:?} (GGML order)")]
+    ShapeMismatch {
+        lhs: [u32; gg::GGML_MAX_DIMS as usize],
+        rhs: [u32; gg::GGML_MAX_DIMS as usize],
+    },
     #[error("Invalid tensor operation: invariants violated")]
     InvalidOperation,
+    #[error("Attempt to modify data of a read-only (mapped) tensor")]
+    ReadOnly,
+    #[error("Tensor data has not been allocated by the graph allocator yet")]
+    Unallocated,
+    #[error("Bad tensor name {0:?}: must be shorter than 64 bytes and not contain NUL")]
+    BadName(String),
     #[error("GGML tensor operation returned NULL")]
     NullPointer,
+    #[error("Unknown GGML tensor type {0}")]
+    UnknownType(u32),
+    #[error("Unknown GGML tensor operation {0}")]
+    UnknownOp(u32),
+    #[error("{0}")]
+    Failed(Arc<GFailure>),
     #[error("General error: {0}")]
     General(Arc<anyhow::Error>),
 }
@@ -37,7 +53,10 @@ pub struct GTensorMetadata<const DIMS: usize> {
     pub typ: GType,
 
     /// The associated GGML operation if available.
-    pub op: gg::ggml_op,
+    pub op: GOp,
+
+    /// The specific operation when `op` is [GOp::Unary].
+    pub unary_op: Option<GUnaryOp>,
 
     /// The shape of the tensor.
     pub shape: [usize; DIMS],
@@ -63,51 +82,50 @@ where
 {
     /// # Safety
     /// Must be called with context mutex held.
-    pub(crate) fn from_ptr(tp: NonNull<gg::ggml_tensor>) -> Self {
+    pub(crate) fn from_ptr(tp: NonNull<gg::ggml_tensor>) -> Result<Self> {
         let (tr, tp) = (unsafe { tp.as_ref() }, tp.as_ptr());
-        let (op, typ, shape) = {
-            assert_eq!(
-                DIMS, tr.n_dims as usize,
-                "Unexpected number of dimensions {:?}!",
-                tr.ne
-            );
-            let mut shp = [0; DIMS];
-            shp.iter_mut()
-                .zip(tr.ne[0..DIMS].iter())
-                .for_each(|(d, s)| *d = *s as usize);
-            (tr.op, tr.type_, shp)
-        };
-        let typ = GType::from_u32(typ).expect("Bad type!");
+        ensure!(
+            DIMS == tr.n_dims as usize,
+            GTensorError::DimensionMismatch {
+                got: tr.n_dims as usize,
+                expected: DIMS
+            }
+        );
+        let mut shape = [0; DIMS];
+        shape
+            .iter_mut()
+            .zip(tr.ne[0..DIMS].iter())
+            .for_each(|(d, s)| *d = *s as usize);
+        let (op, typ) = (tr.op, tr.type_);
+        let typ = GType::from_u32(typ).ok_or(GTensorError::UnknownType(typ))?;
+        let op = GOp::from_u32(op).ok_or(GTensorError::UnknownOp(op))?;
         unsafe {
-            Self {
+            let unary_op = match op {
+                GOp::Unary => {
+                    let uop = gg::ggml_get_unary_op(tp);
+                    Some(GUnaryOp::from_u32(uop).ok_or(GTensorError::UnknownOp(uop))?)
+                }
+                _ => None,
+            };
+            Ok(Self {
                 typ,
                 op,
+                unary_op,
                 shape,
                 len_bytes: gg::ggml_nbytes(tp),
                 len_elements: gg::ggml_nelements(tp) as usize,
                 element_size: typ.element_size(),
-                ggml_ne: tr
-                    .ne
-                    .iter()
-                    .map(|v| *v as u32)
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap(),
-                ggml_nb: tr
-                    .nb
-                    .iter()
-                    .map(|v| *v as u32)
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .unwrap(),
-            }
+                ggml_ne: tr.ne.map(|v| v as u32),
+                ggml_nb: tr.nb.map(|v| v as u32),
+            })
         }
     }
 
     pub(crate) fn new_empty() -> Self {
         Self {
             typ: GType::F32,
-            op: 0,
+            op: GOp::None,
+            unary_op: None,
             shape: [0; DIMS],
             len_bytes: 0,
             len_elements: 0,
@@ -137,6 +155,11 @@ where
         self.typ.is_quantized()
     }
 
+    /// Returns the shape in GGML order with all four dimensions.
+    pub(crate) fn ggml_shape(&self) -> [usize; gg::GGML_MAX_DIMS as usize] {
+        self.ggml_ne.map(|v| v as usize)
+    }
+
     pub fn can_mul_mat_with<const RDIMS: usize>(&self, other: &GTensorMetadata<RDIMS>) -> bool {
         self.ggml_ne
             .iter()
@@ -145,6 +168,15 @@ where
             .all(|(idx, (lels, rels))| idx == 1 || lels == rels)
     }
 
+    /// Returns the GGML shape of the result of matrix multiplication with `other`.
+    pub fn mul_mat_ne<const RDIMS: usize>(
+        &self,
+        other: &GTensorMetadata<RDIMS>,
+    ) -> [u32; gg::GGML_MAX_DIMS as usize] {
+        let (ne, rne) = (&self.ggml_ne, &other.ggml_ne);
+        [ne[1], rne[1], rne[2], rne[3]]
+    }
+
     pub fn can_repeat_with<const RDIMS: usize>(&self, other: &GTensorMetadata<RDIMS>) -> bool
     where
         Dim<RDIMS>: DimValid,
@@ -160,6 +192,20 @@ where
                 .all(|(lels, rels)| lels > &0 && rels % lels == 0)
     }
 
+    /// `true` if the rows of this tensor can be repeated to fill `other`, as
+    /// required by the broadcasting elementwise operations.
+    pub fn can_repeat_rows_with<const RDIMS: usize>(&self, other: &GTensorMetadata<RDIMS>) -> bool
+    where
+        Dim<RDIMS>: DimValid,
+    {
+        self.ggml_ne[0] == other.ggml_ne[0]
+            && self
+                .ggml_ne
+                .iter()
+                .zip(other.ggml_ne.iter())
+                .all(|(lels, rels)| lels > &0 && rels % lels == 0)
+    }
+
     pub fn is_permuted(&self) -> bool {
         self.ggml_nb[0] > self.ggml_nb[1]
             || self.ggml_nb[1] > self.ggml_nb[2]
@@ -195,15 +241,25 @@ where
 }
 
 #[derive(Clone)]
-// TODO: Don't panic when something goes wrong, instead
-// set state in tensor and context to indicate we're dead and
-// just allow other operations (except actually creating/runnning
-// the graph).
 /// A GGML tensor. It uses a const generic for the dimensions.
+///
+/// **Note**: When an operation like [GTensor::add] fails, the context is marked
+/// as dead and the error is reported the next time it's used. Each operation has
+/// a `try_` version, for example [GTensor::try_add], that returns the error
+/// immediately and leaves the context usable.
+///
+/// **Note**: Binary operations accept tensors from different contexts. The tensor
+/// from the other context is used through a view created with [GContext::import_view],
+/// so it must not be the result of an operation itself.
 pub struct GTensor<const DIMS: usize> {
     pub(crate) ctx: GContext,
     pub(crate) md: GTensorMetadata<DIMS>,
     pub(crate) tptr: NonNull<gg::ggml_tensor>,
+    // Set when the tensor's data is in a scratch buffer, so the buffer
+    // can't be reset or released while the tensor is alive.
+    pub(crate) scratch_ref: Option<Arc<()>>,
+    // The context generation when the tensor was created. See `GContext::rewind`.
+    pub(crate) generation: usize,
 }
 
 impl<const DIMS: usize> PartialEq for GTensor<DIMS> {
@@ -236,59 +292,127 @@ where
         (mr, p): (GMemoryRequest, *mut gg::ggml_tensor),
     ) -> Result<Self> {
         let tptr = NonNull::new(p).ok_or(GTensorError::NullPointer)?;
-        ictx.update_used_memory(&mr)?;
+        let md = GTensorMetadata::from_ptr(tptr)?;
+        ictx.update_used_memory(&mr, p)?;
+        Ok(Self {
+            ctx: ctx.clone(),
+            md,
+            tptr,
+            scratch_ref: ictx.scratch_ref(p),
+            generation: ictx.generation(),
+        })
+    }
+
+    /// Creates a [GTensor] for a tensor that already exists in the context.
+    ///
+    /// # Safety
+    /// Must be called with context mutex held. The tensor must belong to the
+    /// context and have `DIMS` dimensions.
+    pub(crate) unsafe fn from_existing_ptr(
+        ctx: &GContext,
+        ictx: &IContext,
+        tptr: NonNull<gg::ggml_tensor>,
+    ) -> Result<Self> {
         Ok(Self {
             ctx: ctx.clone(),
-            md: GTensorMetadata::from_ptr(tptr),
+            md: GTensorMetadata::from_ptr(tptr)?,
             tptr,
+            scratch_ref: ictx.scratch_ref(tptr.as_ptr()),
+            generation: ictx.generation(),
         })
     }
 
-    pub(crate) fn make_dead_clone<const ODIMS: usize>(&self) -> GTensor<ODIMS>
+    pub(crate) fn make_dead_clone<const ODIMS: usize>(
+        &self,
+        ctx: &GContext,
+        generation: usize,
+    ) -> GTensor<ODIMS>
     where
         Dim<ODIMS>: DimValid,
     {
         GTensor {
-            ctx: self.ctx.clone(),
+            ctx: ctx.clone(),
             tptr: self.tptr,
             md: GTensorMetadata::new_empty(),
+            scratch_ref: None,
+            generation,
         }
     }
 
+    pub(crate) fn ensure_writable(ictx: &IContext, tptr: *mut gg::ggml_tensor) -> Result<()> {
+        Self::ensure_allocated(ictx, tptr)?;
+        ensure!(!unsafe { ictx.is_mapped(tptr) }, GTensorError::ReadOnly);
+        Ok(())
+    }
+
+    pub(crate) fn ensure_allocated(ictx: &IContext, tptr: *mut gg::ggml_tensor) -> Result<()> {
+        // Results only get data once the graph allocator has processed the graph.
+        ensure!(
+            ictx.graph_allocator.is_none() || !unsafe { (*tptr).data.is_null() },
+            GTensorError::Unallocated
+        );
+        Ok(())
+    }
+
     pub(crate) fn with_tensor<OUT, F>(&self, fun: F) -> Result<OUT>
     where
         F: FnOnce(&GContext, &mut IContext, *mut gg::ggml_tensor) -> Result<OUT>,
     {
-        self.ctx
-            .with_icontext(|ctx, mut ictx| fun(ctx, &mut ictx, self.tptr.as_ptr()))
+        self.ctx.with_icontext(|ctx, mut ictx| {
+            ictx.ensure_live(self.tptr.as_ptr(), self.generation)?;
+            fun(ctx, &mut ictx, self.tptr.as_ptr())
+        })
     }
 
-    pub(crate) fn with_tensor_infallible<OUT, F>(&self, fun: F) -> Result<OUT>
+    // Like `with_tensor` for functions that only read the tensor. This works with frozen contexts.
+    pub(crate) fn with_tensor_read<OUT, F>(&self, fun: F) -> Result<OUT>
     where
-        F: FnOnce(&GContext, &mut IContext, *mut gg::ggml_tensor) -> OUT,
+        F: FnOnce(&GContext, &IContext, *mut gg::ggml_tensor) -> Result<OUT>,
     {
-        self.ctx
-            .with_icontext_infallible(|mut ictx| fun(&self.ctx, &mut ictx, self.tptr.as_ptr()))
+        self.ctx.with_icontext_read(|ctx, ictx| {
+            ictx.ensure_live(self.tptr.as_ptr(), self.generation)?;
+            fun(ctx, ictx, self.tptr.as_ptr())
+        })
     }
 
-    pub(crate) fn with_tensor_delay_failure<OUT, DF, F>(&self, dfun: DF, fun: F) -> OUT
+    pub(crate) fn with_tensor_infallible<OUT, F>(&self, fun: F) -> Result<OUT>
     where
-        DF: Fn() -> OUT,
-        F: FnOnce(&GContext, &mut IContext, *mut gg::ggml_tensor) -> Result<OUT>,
+        F: FnOnce(&GContext, &mut IContext, *mut gg::ggml_tensor) -> OUT,
     {
-        self.ctx
-            .delay_failure_with_icontext(dfun, |ictx| fun(&self.ctx, ictx, self.tptr.as_ptr()))
+        self.ctx.with_icontext(|ctx, mut ictx| {
+            ictx.ensure_live(self.tptr.as_ptr(), self.generation)?;
+            Ok(fun(ctx, &mut ictx, self.tptr.as_ptr()))
+        })
     }
 
-    pub(crate) fn with_tensor_unit_delay_failure<F>(&self, fun: F)
+    // Describes this tensor as an input to a failed operation.
+    pub(crate) fn failure_input(&self) -> GFailureInput {
+        GFailureInput {
+            typ: self.md.typ,
+            ggml_ne: self.md.ggml_ne.map(|v| v as usize),
+        }
+    }
+
+    pub(crate) fn try_with_tensor_unit<F>(
+        &self,
+        op: &'static str,
+        fun: F,
+    ) -> Result<(), GTensorError>
     where
         F: FnOnce(&GContext, &IContext, *mut gg::ggml_tensor) -> Result<()>,
     {
-        self.ctx
-            .delay_failure_with_icontext(|| (), |ictx| fun(&self.ctx, ictx, self.tptr.as_ptr()))
+        let trace = GOpTrace::new(op, vec![self.failure_input()]);
+        self.ctx.with_icontext_traced(trace, |ictx, _trace| {
+            ictx.ensure_live(self.tptr.as_ptr(), self.generation)?;
+            fun(&self.ctx, ictx, self.tptr.as_ptr())
+        })
     }
 
-    pub(crate) fn new_unary<const ODIMS: usize, F>(&self, fun: F) -> GTensor<ODIMS>
+    pub(crate) fn try_new_unary<const ODIMS: usize, F>(
+        &self,
+        op: &'static str,
+        fun: F,
+    ) -> Result<GTensor<ODIMS>, GTensorError>
     where
         Dim<ODIMS>: DimValid,
         F: FnOnce(
@@ -297,21 +421,23 @@ where
             *mut gg::ggml_tensor,
         ) -> Result<(GMemoryRequest, *mut gg::ggml_tensor)>,
     {
-        self.with_tensor_delay_failure(
-            || self.make_dead_clone(),
-            |ctx, ictx, tptr| {
-                let fresult = fun(ctx, ictx, tptr)?;
-                unsafe { GTensor::<ODIMS>::new_from_ptr(ctx, ictx, fresult) }
-            },
-        )
+        let trace = GOpTrace::new(op, vec![self.failure_input()]);
+        self.ctx.with_icontext_traced(trace, |ictx, trace| {
+            let tptr = self.tptr.as_ptr();
+            ictx.ensure_live(tptr, self.generation)?;
+            let fresult = fun(&self.ctx, ictx, tptr)?;
+            trace.memory_request = Some(fresult.0);
+            unsafe { GTensor::<ODIMS>::new_from_ptr(&self.ctx, ictx, fresult) }
+        })
     }
 
     // RHS dims enforced elsewhere if necessary.
-    pub(crate) fn new_binary<const RDIMS: usize, const ODIMS: usize, F, T>(
+    pub(crate) fn try_new_binary<const RDIMS: usize, const ODIMS: usize, F, T>(
         &self,
+        op: &'static str,
         rhs: T,
         fun: F,
-    ) -> GTensor<ODIMS>
+    ) -> Result<GTensor<ODIMS>, GTensorError>
     where
         Dim<RDIMS>: DimValid,
         Dim<ODIMS>: DimValid,
@@ -324,25 +450,87 @@ where
         T: AsRef<GTensor<RDIMS>>,
     {
         let rhs = rhs.as_ref();
-        if self.ctx.dead.load(SeqCst) || rhs.ctx.dead.load(SeqCst) {
-            self.ctx.dead.store(true, SeqCst);
-            rhs.ctx.dead.store(true, SeqCst);
-            return self.make_dead_clone();
+        let inputs = vec![self.failure_input(), rhs.failure_input()];
+        let ctx = self.binary_ctx(rhs);
+        // Tensors from other contexts are used through a view in this one. Only
+        // leafs can be imported, see `GContext::import_view`.
+        let imported_lhs;
+        let imported_rhs;
+        let (lhs, rhs) = if rhs.ctx.ptrval == self.ctx.ptrval {
+            (self, rhs)
+        } else if ctx.ptrval == self.ctx.ptrval {
+            imported_rhs = ctx
+                .import_view(rhs)
+                .map_err(|e| ctx.record_failure(GOpTrace::new(op, inputs.clone()), e))?;
+            (self, &imported_rhs)
+        } else {
+            imported_lhs = ctx
+                .import_view(self)
+                .map_err(|e| ctx.record_failure(GOpTrace::new(op, inputs.clone()), e))?;
+            (&imported_lhs, rhs)
+        };
+        let trace = GOpTrace::new(op, inputs);
+        ctx.with_icontext_traced(trace, |ictx, trace| {
+            let (ltptr, rtptr) = (lhs.tptr.as_ptr(), rhs.tptr.as_ptr());
+            ictx.ensure_live(ltptr, lhs.generation)?;
+            ictx.ensure_live(rtptr, rhs.generation)?;
+            let fresult = fun(ctx, ictx, ltptr, rtptr)?;
+            trace.memory_request = Some(fresult.0);
+            unsafe { GTensor::<ODIMS>::new_from_ptr(ctx, ictx, fresult) }
+        })
+    }
+
+    // The context binary operations with `rhs` create their result in. Frozen
+    // contexts can't hold it, so it goes in the context of `rhs` instead.
+    pub(crate) fn binary_ctx<'a, const RDIMS: usize>(
+        &'a self,
+        rhs: &'a GTensor<RDIMS>,
+    ) -> &'a GContext
+    where
+        Dim<RDIMS>: DimValid,
+    {
+        if self.ctx.frozen.get().is_some() && rhs.ctx.frozen.get().is_none() {
+            &rhs.ctx
+        } else {
+            &self.ctx
         }
-        assert_eq!(
-            self.ctx.ptrval, rhs.ctx.ptrval,
-            "Cannot perform operation between tensors from different contexts!"
-        );
+    }
 
-        self.ctx.delay_failure_with_icontext(
-            || self.make_dead_clone(),
-            |mut ictx| {
-                let ictx = &mut ictx;
-                let (ltptr, rtptr) = (self.tptr.as_ptr(), rhs.tptr.as_ptr());
-                let fresult = fun(&self.ctx, ictx, ltptr, rtptr)?;
-                unsafe { GTensor::<ODIMS>::new_from_ptr(&self.ctx, ictx, fresult) }
-            },
-        )
+    // Unwraps the result of a fallible operation. On failure the context is
+    // killed and a dead tensor is returned, so the error is only reported
+    // once the context is used again. Frozen contexts aren't killed, only the
+    // dead tensor reports the error.
+    pub(crate) fn or_dead<const ODIMS: usize>(
+        &self,
+        result: Result<GTensor<ODIMS>, GTensorError>,
+    ) -> GTensor<ODIMS>
+    where
+        Dim<ODIMS>: DimValid,
+    {
+        self.or_dead_in(&self.ctx, result)
+    }
+
+    // Like `or_dead` for operations that create their result in the specified context.
+    pub(crate) fn or_dead_in<const ODIMS: usize>(
+        &self,
+        ctx: &GContext,
+        result: Result<GTensor<ODIMS>, GTensorError>,
+    ) -> GTensor<ODIMS>
+    where
+        Dim<ODIMS>: DimValid,
+    {
+        result.unwrap_or_else(|e| {
+            let (ctx, generation) = ctx.fail_with_last_failure(&e);
+            self.make_dead_clone(&ctx, generation.unwrap_or(self.generation))
+        })
+    }
+
+    // Like `or_dead` for operations that don't create a tensor. For frozen
+    // contexts this tensor becomes the dead tensor.
+    pub(crate) fn or_fail(&mut self, result: Result<(), GTensorError>) {
+        if let Err(e) = result {
+            (self.ctx, _) = self.ctx.fail_with_last_failure(&e);
+        }
     }
 }
 
@@ -393,10 +581,23 @@ where
         self.md.shape
     }
 
+    /// Returns the operation associated with this tensor if available.
+    pub fn op(&self) -> GOp {
+        self.md.op
+    }
+
+    /// Returns the specific operation if this tensor is the result of a
+    /// [GOp::Unary] operation.
+    pub fn unary_op(&self) -> Option<GUnaryOp> {
+        self.md.unary_op
+    }
+
     /// Returns the GGML operation associated with this
     /// tensor if available.
+    ///
+    /// **Note**: This is a low level function. See [Self::op].
     pub fn ggml_op(&self) -> gg::ggml_op {
-        self.md.op
+        self.md.op as gg::ggml_op
     }
 
     /// Returns the element type.
@@ -429,9 +630,42 @@ where
         self.md.ggml_nb
     }
 
+    /// Set the tensor's name. Names don't need to be unique but
+    /// [GContext::get_tensor] will only find the first tensor with a name.
+    ///
+    /// **Invariants**
+    /// 1. The name must be shorter than `GGML_MAX_NAME` (64) bytes.
+    /// 2. The name must not contain NUL characters.
+    pub fn set_name(&mut self, name: &str) -> Result<()> {
+        ensure!(
+            name.len() < gg::GGML_MAX_NAME as usize && !name.contains('\0'),
+            GTensorError::BadName(name.to_string())
+        );
+        let name = std::ffi::CString::new(name)?;
+        self.with_tensor_infallible(|_ctx, _ictx, tptr| unsafe {
+            gg::ggml_set_name(tptr, name.as_ptr());
+        })
+    }
+
+    /// Returns the tensor's name. This will be empty if it was never set.
+    pub fn name(&self) -> Result<String> {
+        self.with_tensor_read(|_ctx, _ictx, tptr| unsafe {
+            Ok(std::ffi::CStr::from_ptr(gg::ggml_get_name(tptr))
+                .to_string_lossy()
+                .into_owned())
+        })
+    }
+
     /// Immediately fills the tensor's data with zeros.
     pub fn fill_zero(&mut self) {
-        self.with_tensor_unit_delay_failure(|ctx, _ictx, tptr| {
+        let result = self.try_fill_zero();
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::fill_zero].
+    pub fn try_fill_zero(&mut self) -> Result<(), GTensorError> {
+        self.try_with_tensor_unit("fill_zero", |ctx, ictx, tptr| {
+            Self::ensure_writable(ictx, tptr)?;
             if !ctx.no_alloc {
                 unsafe {
                     gg::ggml_set_zero(tptr);
@@ -447,10 +681,17 @@ where
     /// **Invariants**
     /// 1. The tensor's type must not be quantized.
     pub fn fill_i32(&mut self, val: i32) {
-        self.with_tensor_unit_delay_failure(|ctx, _ictx, tptr| {
+        let result = self.try_fill_i32(val);
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::fill_i32].
+    pub fn try_fill_i32(&mut self, val: i32) -> Result<(), GTensorError> {
+        self.try_with_tensor_unit("fill_i32", |ctx, ictx, tptr| {
             if self.md.typ.is_quantized() {
                 Err(GTensorError::TypeMismatch)?
             }
+            Self::ensure_writable(ictx, tptr)?;
 
             if !ctx.no_alloc {
                 unsafe {
@@ -467,10 +708,17 @@ where
     /// **Invariants**
     /// 1. The tensor's type must not be quantized.
     pub fn fill_f32(&mut self, val: f32) {
-        self.with_tensor_unit_delay_failure(|ctx, _ictx, tptr| {
+        let result = self.try_fill_f32(val);
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::fill_f32].
+    pub fn try_fill_f32(&mut self, val: f32) -> Result<(), GTensorError> {
+        self.try_with_tensor_unit("fill_f32", |ctx, ictx, tptr| {
             if self.md.typ.is_quantized() {
                 Err(GTensorError::TypeMismatch)?
             }
+            Self::ensure_writable(ictx, tptr)?;
 
             if !ctx.no_alloc {
                 unsafe {
@@ -488,7 +736,7 @@ where
     /// 1. The tensor's type must not be quantized.
     /// 2. The index must be valid.
     pub fn get_f32_1d(&self, index: usize) -> Result<f32> {
-        self.with_tensor(|ctx, _ictx, tptr| {
+        self.with_tensor_read(|ctx, ictx, tptr| {
             if index >= self.md.len_elements {
                 Err(GTensorError::InvalidOperation)?
             }
@@ -496,6 +744,7 @@ where
                 Err(GTensorError::TypeMismatch)?
             }
             ensure!(!ctx.no_alloc, GContextError::NoAlloc);
+            Self::ensure_allocated(ictx, tptr)?;
             Ok(unsafe { gg::ggml_get_f32_1d(tptr, index as i32) })
         })
     }
@@ -507,7 +756,7 @@ where
     /// 1. The tensor's type must not be quantized.
     /// 2. The index must be valid.
     pub fn get_i32_1d(&self, index: usize) -> Result<i32> {
-        self.with_tensor(|ctx, _ictx, tptr| {
+        self.with_tensor_read(|ctx, ictx, tptr| {
             if index >= self.md.len_elements {
                 Err(GTensorError::InvalidOperation)?
             }
@@ -515,6 +764,7 @@ where
                 Err(GTensorError::TypeMismatch)?
             }
             ensure!(!ctx.no_alloc, GContextError::NoAlloc);
+            Self::ensure_allocated(ictx, tptr)?;
             Ok(unsafe { gg::ggml_get_i32_1d(tptr, index as i32) })
         })
     }
@@ -526,13 +776,20 @@ where
     /// 1. The tensor's type must not be quantized.
     /// 2. The index must be valid.
     pub fn set_f32_1d(&mut self, index: usize, val: f32) {
-        self.with_tensor_unit_delay_failure(|_ctx, _ictx, tptr| {
+        let result = self.try_set_f32_1d(index, val);
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::set_f32_1d].
+    pub fn try_set_f32_1d(&mut self, index: usize, val: f32) -> Result<(), GTensorError> {
+        self.try_with_tensor_unit("set_f32_1d", |_ctx, ictx, tptr| {
             if index >= self.md.len_elements {
                 Err(GTensorError::InvalidOperation)?
             }
             if self.md.typ.is_quantized() {
                 Err(GTensorError::TypeMismatch)?
             }
+            Self::ensure_writable(ictx, tptr)?;
             if self.ctx.no_alloc {
                 return Ok(());
             }
@@ -548,13 +805,20 @@ where
     /// 1. The tensor's type must not be quantized.
     /// 2. The index must be valid.
     pub fn set_i32_1d(&mut self, index: usize, val: i32) {
-        self.with_tensor_unit_delay_failure(|_ctx, _ictx, tptr| {
+        let result = self.try_set_i32_1d(index, val);
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::set_i32_1d].
+    pub fn try_set_i32_1d(&mut self, index: usize, val: i32) -> Result<(), GTensorError> {
+        self.try_with_tensor_unit("set_i32_1d", |_ctx, ictx, tptr| {
             if index >= self.md.len_elements {
                 Err(GTensorError::InvalidOperation)?
             }
             if self.md.typ.is_quantized() {
                 Err(GTensorError::TypeMismatch)?
             }
+            Self::ensure_writable(ictx, tptr)?;
             if self.ctx.no_alloc {
                 return Ok(());
             }
@@ -585,11 +849,12 @@ where
         F: FnOnce(&mut [u8]) -> O,
     {
         ensure!(!self.ctx.no_alloc, GContextError::NoAlloc);
-        self.with_tensor_infallible(|_ctx, _ictx, tptr| {
-            fun(std::slice::from_raw_parts_mut(
+        self.with_tensor(|_ctx, ictx, tptr| {
+            Self::ensure_writable(ictx, tptr)?;
+            Ok(fun(std::slice::from_raw_parts_mut(
                 tptr.as_ref().unwrap().data as *mut u8,
                 self.md.len_bytes,
-            ))
+            )))
         })
     }
 
@@ -604,25 +869,36 @@ where
         F: FnOnce(&[u8]) -> O,
     {
         ensure!(!self.ctx.no_alloc, GContextError::NoAlloc);
-        self.with_tensor_infallible(|_ctx, _ictx, tptr| {
-            fun(std::slice::from_raw_parts_mut(
+        self.with_tensor_read(|_ctx, ictx, tptr| {
+            Self::ensure_allocated(ictx, tptr)?;
+            Ok(fun(std::slice::from_raw_parts_mut(
                 tptr.as_ref().unwrap().data as *mut u8,
                 self.md.len_bytes,
-            ))
+            )))
         })
     }
 
     /// # Safety
     /// Fills a tensor with raw data. It's your responsibility to make sure the format is correct.
     pub unsafe fn populate_raw<S: AsRef<[u8]>>(&mut self, data: S) {
+        let result = self.try_populate_raw(data);
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::populate_raw].
+    ///
+    /// # Safety
+    /// See [Self::populate_raw].
+    pub unsafe fn try_populate_raw<S: AsRef<[u8]>>(&mut self, data: S) -> Result<(), GTensorError> {
         let data = data.as_ref();
-        self.with_tensor_unit_delay_failure(|ctx, _ictx, tptr| {
+        self.try_with_tensor_unit("populate_raw", |ctx, ictx, tptr| {
             if self.len() != data.len() {
                 Err(GTensorError::BadPopulate {
                     got: data.len(),
                     expected: self.len(),
                 })?
             }
+            Self::ensure_writable(ictx, tptr)?;
             if ctx.no_alloc {
                 return Ok(());
             }
@@ -643,14 +919,40 @@ where
     /// Copies data from the specified tensor into this tensor when the graph runs.
     ///
     /// **Note**: This immediately overwrites `self` with the copy.
+    ///
+    /// **Invariants**
+    /// 1. Both tensors must have the same number of elements.
     pub fn copy_from<T: AsRef<GTensor<DIMS>>>(&mut self, rhs: T) {
-        let nt = self.new_binary(rhs, |ctx, ictx, ltptr, rtptr| {
-            let md = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::F32, [])
-                .fit_or_die()?;
-            Ok((md, unsafe { gg::ggml_cpy(ictx.gptr(), rtptr, ltptr) }))
-        });
+        let result = self.try_new_copy(rhs);
+        *self = self.or_dead(result);
+    }
 
-        *self = nt;
+    /// Fallible version of [Self::copy_from]. `self` is left unchanged
+    /// if this fails.
+    pub fn try_copy_from<T: AsRef<GTensor<DIMS>>>(&mut self, rhs: T) -> Result<(), GTensorError> {
+        *self = self.try_new_copy(rhs)?;
+        Ok(())
+    }
+
+    fn try_new_copy<T: AsRef<GTensor<DIMS>>>(&self, rhs: T) -> Result<Self, GTensorError> {
+        let rmd = rhs.as_ref().md.clone();
+        self.try_new_binary("copy_from", rhs, |ctx, ictx, ltptr, rtptr| {
+            if rmd.len_elements != self.md.len_elements {
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rmd.ggml_ne,
+                })?;
+            }
+            // The result is a view of `self`.
+            let md = GMemoryRequest::estimate_view_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
+            Ok((md, unsafe { gg::ggml_cpy(ictx.gptr(), rtptr, ltptr) }))
+        })
     }
 
     // FIXME: More generic versions of these functions.
@@ -661,8 +963,14 @@ where
     /// 2. The length of the incoming data must match the size of the
     ///     tensor.
     pub fn populate_f32<S: AsRef<[f32]>>(&mut self, data: S) {
+        let result = self.try_populate_f32(data);
+        self.or_fail(result)
+    }
+
+    /// Fallible version of [Self::populate_f32].
+    pub fn try_populate_f32<S: AsRef<[f32]>>(&mut self, data: S) -> Result<(), GTensorError> {
         let data = data.as_ref();
-        self.with_tensor_unit_delay_failure(|ctx, _ictx, tptr| {
+        self.try_with_tensor_unit("populate_f32", |ctx, ictx, tptr| {
             if self.md.typ != GType::F32 {
                 Err(GTensorError::TypeMismatch)?
             }
@@ -672,6 +980,7 @@ where
                     expected: self.elements(),
                 })?
             }
+            Self::ensure_writable(ictx, tptr)?;
             if ctx.no_alloc {
                 return Ok(());
             }
@@ -695,7 +1004,7 @@ where
         let dest = dest.as_mut();
         let elements = self.elements();
 
-        self.with_tensor(|ctx, _ictx, tptr| {
+        self.with_tensor_read(|ctx, ictx, tptr| {
             if self.md.typ != GType::F32 {
                 Err(GTensorError::TypeMismatch)?
             }
@@ -706,6 +1015,7 @@ where
                 })?
             }
             ensure!(!ctx.no_alloc, GContextError::NoAlloc);
+            Self::ensure_allocated(ictx, tptr)?;
             let ts = unsafe {
                 std::slice::from_raw_parts(tptr.as_ref().unwrap().data as *const f32, elements)
             };
@@ -714,3 +1024,63 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::context::GContextBuilder;
+
+    #[test]
+    fn test_metadata_from_bad_ptr() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let t = ctx.tensor(GType::F32, [4])?;
+        assert!(matches!(
+            GTensorMetadata::<2>::from_ptr(t.tptr).map_err(|e| e.downcast::<GTensorError>()),
+            Err(Ok(GTensorError::DimensionMismatch {
+                got: 1,
+                expected: 2
+            }))
+        ));
+
+        let typ = unsafe { std::mem::replace(&mut (*t.tptr.as_ptr()).type_, 1000) };
+        let result = GTensorMetadata::<1>::from_ptr(t.tptr);
+        unsafe { (*t.tptr.as_ptr()).type_ = typ };
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GTensorError>()),
+            Err(Ok(GTensorError::UnknownType(1000)))
+        ));
+
+        let op = unsafe { std::mem::replace(&mut (*t.tptr.as_ptr()).op, 1000) };
+        let result = GTensorMetadata::<1>::from_ptr(t.tptr);
+        unsafe { (*t.tptr.as_ptr()).op = op };
+        assert!(matches!(
+            result.map_err(|e| e.downcast::<GTensorError>()),
+            Err(Ok(GTensorError::UnknownOp(1000)))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_op() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let t = ctx.tensor(GType::F32, [2, 2])?;
+        assert_eq!(t.op(), GOp::None);
+
+        let sqr = t.sqr();
+        assert_eq!((sqr.op().name(), sqr.op().symbol()), ("SQR", "x^2"));
+        assert_eq!(sqr.op().to_string(), "SQR");
+        assert!(sqr.op().is_elementwise() && !sqr.op().is_view());
+        assert_eq!(sqr.unary_op(), None);
+
+        let relu = t.relu();
+        assert_eq!(relu.op(), GOp::Unary);
+        assert_eq!(relu.unary_op(), Some(GUnaryOp::Relu));
+        assert_eq!(relu.unary_op().map(|uop| uop.name()), Some("RELU"));
+
+        assert!(t.transpose().op().is_view());
+        assert!(t.sum::<1>().op().is_reduction());
+        Ok(())
+    }
+}
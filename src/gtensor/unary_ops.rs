@@ -4,13 +4,18 @@ use super::tensor::*;
 use crate::{dims::*, util::GType, validation::GMemoryRequest};
 
 macro_rules! mk_simple_uops {
-  ( $($(#[$attr:meta])* [$opname:ident, $gfname:ident]),* $(,)? ) => { $(
+  ( $($(#[$attr:meta])* [$opname:ident, $try_opname:ident, $gfname:ident]),* $(,)? ) => { $(
     $(#[$attr])*
     pub fn $opname(&self) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
+        self.or_dead(self.$try_opname())
+    }
+
+    #[doc = concat!("Fallible version of [Self::", stringify!($opname), "].")]
+    pub fn $try_opname(&self) -> Result<Self, GTensorError> {
+        self.try_new_unary(stringify!($opname), |ctx, ictx, tptr| {
             let mr = GMemoryRequest::estimate_tensor_request_ictx(
-                ctx, ictx, self.md.typ, self.md.shape
-            ).fit_or_die()?;
+                ctx, ictx, self.md.typ, self.md.ggml_shape()
+            ).fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::$gfname(ictx.gptr(), tptr))) }
         })
     }
@@ -40,7 +45,7 @@ where
         /// let result = a.sqr();
         /// assert_eq!(result, [4, 4, 4]);
         /// ```
-        [sqr, ggml_sqr],
+        [sqr, try_sqr, ggml_sqr],
 
         /// Elementwise square root of tensor `A`.
         /// Returns a new tensor.
@@ -56,7 +61,7 @@ where
         /// let result = a.sqrt();
         /// assert_eq!(result, [3, 3, 3]);
         /// ```
-        [sqrt, ggml_sqrt],
+        [sqrt, try_sqrt, ggml_sqrt],
 
         // [mean, ggml_mean],
 
@@ -74,7 +79,7 @@ where
         /// let result = a.abs();
         /// assert_eq!(result, [1, 2, 3]);
         /// ```
-        [abs, ggml_abs],
+        [abs, try_abs, ggml_abs],
 
         /// Elementwise sign operation on tensor `A`.
         /// Returns a new tensor.
@@ -93,7 +98,7 @@ where
         /// let result = a.sgn();
         /// assert_eq!(result, [-1, 0, 1]);
         /// ```
-        [sgn, ggml_sgn],
+        [sgn, try_sgn, ggml_sgn],
 
         /// Elementwise negation operation on tensor `A`.
         /// In other words, it just flips the sign.
@@ -110,7 +115,7 @@ where
         /// let result = a.sgn();
         /// assert_eq!(result, [-1, 1, 6, -7]);
         /// ```
-        [neg, ggml_neg],
+        [neg, try_neg, ggml_neg],
 
         /// Elementwise step operation on tensor `A`.
         /// Returns a new tensor.
@@ -130,7 +135,7 @@ where
         /// let result = a.step();
         /// assert_eq!(result, [1, 0, 0, 1]);
         /// ```
-        [step, ggml_step],
+        [step, try_step, ggml_step],
 
         /// Perform ReLU operation on tensor `A`.
         /// Returns a new tensor.
@@ -149,7 +154,7 @@ where
         /// let a = [1, -1, -6, 7];
         /// let result = a.relu();
         /// assert_eq!(result, [1, 0, 0, 7]);
-        [relu, ggml_relu],
+        [relu, try_relu, ggml_relu],
 
         /// Perform GELU (AKA "Gaussian Error Linear Unit")
         /// operation on tensor `A`.
@@ -158,7 +163,7 @@ where
         /// `a.gelu()`
         ///
         /// See <https://en.wikipedia.org/wiki/Activation_function>
-        [gelu, ggml_gelu],
+        [gelu, try_gelu, ggml_gelu],
 
         /// Perform SiLU (AKA "Sigmoid Linear Unit")
         /// operation on tensor `A`.
@@ -167,35 +172,12 @@ where
         /// `a.silu()`
         ///
         /// See <https://en.wikipedia.org/wiki/Activation_function>
-        [silu, ggml_silu],
+        [silu, try_silu, ggml_silu],
 
         /// # !!!! FIXME !!!!
         /// # !!!! FIXME !!!!
         /// # !!!! FIXME !!!!
-        [cont, ggml_cont],
-
-        /// Create a view `A` with the first and second dimensions flipped.
-        /// Returns a new tensor.
-        ///
-        /// **Note**: This is the same as
-        /// `a.permute([1, 0, 2, 3])`.
-        ///
-        /// `a.transpose()`
-        ///
-        /// **Example** (pseudocode):
-        /// ```ignore
-        /// let a =
-        ///     [ [1, 1, 1],
-        ///       [2, 2, 3],
-        ///       [3, 3, 3] ];
-        /// let expected =
-        ///     [ [1, 2, 3],
-        ///       [1, 2, 3],
-        ///       [1, 2, 3] ];
-        /// let result = a.transpose();
-        /// assert_eq!(result, expected);
-        /// ```
-        [transpose, ggml_transpose],
+        [cont, try_cont, ggml_cont],
 
         /// Apply the `softmax` (AKA `softargmax` or "normalized
         /// exponential function") to `A`.
@@ -208,7 +190,46 @@ where
         ///
         /// **Invariants**
         /// 1. Result will have the shape and type of `A`.
-        [soft_max, ggml_soft_max],
+        [soft_max, try_soft_max, ggml_soft_max],
+    }
+
+    /// Create a view `A` with the first and second dimensions flipped.
+    /// Returns a new tensor.
+    ///
+    /// **Note**: This is the same as
+    /// `a.permute([1, 0, 2, 3])`.
+    ///
+    /// `a.transpose()`
+    ///
+    /// **Example** (pseudocode):
+    /// ```ignore
+    /// let a =
+    ///     [ [1, 1, 1],
+    ///       [2, 2, 3],
+    ///       [3, 3, 3] ];
+    /// let expected =
+    ///     [ [1, 2, 3],
+    ///       [1, 2, 3],
+    ///       [1, 2, 3] ];
+    /// let result = a.transpose();
+    /// assert_eq!(result, expected);
+    /// ```
+    pub fn transpose(&self) -> Self {
+        self.or_dead(self.try_transpose())
+    }
+
+    /// Fallible version of [Self::transpose].
+    pub fn try_transpose(&self) -> Result<Self, GTensorError> {
+        self.try_new_unary("transpose", |ctx, ictx, tptr| {
+            let mr = GMemoryRequest::estimate_view_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
+            unsafe { Ok((mr, gg::ggml_transpose(ictx.gptr(), tptr))) }
+        })
     }
 
     /// Perform LayerNorm operation on tensor `A`.
@@ -219,10 +240,19 @@ where
     /// See [this helpful explanation](https://github.com/bzhangGo/rmsnorm/blob/2e726f1a3f106bb719056422f4f9b6aca03d3ce6/README.md)
     /// for more information and comparison with the [GTensor::rms_norm] function.
     pub fn norm(&self, eps: f32) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, self.md.shape)
-                    .fit_or_die()?;
+        self.or_dead(self.try_norm(eps))
+    }
+
+    /// Fallible version of [Self::norm].
+    pub fn try_norm(&self, eps: f32) -> Result<Self, GTensorError> {
+        self.try_new_unary("norm", |ctx, ictx, tptr| {
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_norm(ictx.gptr(), tptr, eps))) }
         })
     }
@@ -235,10 +265,19 @@ where
     /// See [this helpful explanation](https://github.com/bzhangGo/rmsnorm/blob/2e726f1a3f106bb719056422f4f9b6aca03d3ce6/README.md)
     /// for more information and comparison with the [GTensor::norm] function.
     pub fn rms_norm(&self, eps: f32) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
-            let mr =
-                GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, self.md.shape)
-                    .fit_or_die()?;
+        self.or_dead(self.try_rms_norm(eps))
+    }
+
+    /// Fallible version of [Self::rms_norm].
+    pub fn try_rms_norm(&self, eps: f32) -> Result<Self, GTensorError> {
+        self.try_new_unary("rms_norm", |ctx, ictx, tptr| {
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_rms_norm(ictx.gptr(), tptr, eps))) }
         })
     }
@@ -263,9 +302,24 @@ where
         Dim<ODIMS>: DimValid,
         DimPair<ODIMS, 2>: DimLt,
     {
-        self.new_unary(|ctx, ictx, tptr| {
-            let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::F32, [1])
-                .fit_or_die()?;
+        self.or_dead(self.try_mean())
+    }
+
+    /// Fallible version of [Self::mean].
+    pub fn try_mean<const ODIMS: usize>(&self) -> Result<GTensor<ODIMS>, GTensorError>
+    where
+        Dim<ODIMS>: DimValid,
+        DimPair<ODIMS, 2>: DimLt,
+    {
+        self.try_new_unary("mean", |ctx, ictx, tptr| {
+            let [_, ne1, ne2, ne3] = self.md.ggml_shape();
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                GType::F32,
+                [1, ne1, ne2, ne3],
+            )
+            .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_mean(ictx.gptr(), tptr))) }
         })
     }
@@ -290,9 +344,18 @@ where
         Dim<ODIMS>: DimValid,
         DimPair<ODIMS, 2>: DimLt,
     {
-        self.new_unary(|ctx, ictx, tptr| {
+        self.or_dead(self.try_sum())
+    }
+
+    /// Fallible version of [Self::sum].
+    pub fn try_sum<const ODIMS: usize>(&self) -> Result<GTensor<ODIMS>, GTensorError>
+    where
+        Dim<ODIMS>: DimValid,
+        DimPair<ODIMS, 2>: DimLt,
+    {
+        self.try_new_unary("sum", |ctx, ictx, tptr| {
             let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, [1])
-                .fit_or_die()?;
+                .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_sum(ictx.gptr(), tptr))) }
         })
     }
@@ -307,14 +370,40 @@ where
         DimPair<ODIMS, 2>: DimGtE,
         DimPair<ODIMS, 4>: DimLt,
     {
-        self.new_unary(|ctx, ictx, tptr| {
+        self.or_dead(self.try_reshape(ne))
+    }
+
+    /// Fallible version of [Self::reshape].
+    pub fn try_reshape<const ODIMS: usize>(
+        &self,
+        ne: [usize; ODIMS],
+    ) -> Result<GTensor<ODIMS>, GTensorError>
+    where
+        Dim<ODIMS>: DimValid,
+        DimPair<ODIMS, 2>: DimGtE,
+        DimPair<ODIMS, 4>: DimLt,
+    {
+        self.try_new_unary("reshape", |ctx, ictx, tptr| {
             let shp = match ODIMS {
                 2 => vec![ne[1], ne[0]],
                 3 => vec![ne[1], ne[0], ne[2]],
                 _ => Err(GTensorError::InvalidOperation)?,
             };
-            let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, shp)
-                .fit_or_die()?;
+            if shp.iter().product::<usize>() != self.md.len_elements {
+                let mut rne = [1; 4];
+                rne.iter_mut()
+                    .zip(shp.iter())
+                    .for_each(|(d, s)| *d = *s as u32);
+                Err(GTensorError::ShapeMismatch {
+                    lhs: self.md.ggml_ne,
+                    rhs: rne,
+                })?;
+            }
+            if !self.md.is_contiguous() {
+                Err(GTensorError::InvalidOperation)?;
+            }
+            let mr = GMemoryRequest::estimate_view_request_ictx(ctx, ictx, self.md.typ, shp)
+                .fit_or_grow(ictx)?;
             Ok((
                 mr,
                 match ODIMS {
@@ -348,9 +437,29 @@ where
         Dim<ODIMS>: DimValid,
         DimPair<ODIMS, 3>: DimLt,
     {
-        self.new_unary(|ctx, ictx, tptr| {
-            let mr = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, [])
-                .fit_or_die()?;
+        self.or_dead(self.try_view(ne, offset))
+    }
+
+    /// Fallible version of [Self::view].
+    pub fn try_view<const ODIMS: usize>(
+        &self,
+        ne: [i64; ODIMS],
+        offset: [usize; ODIMS],
+    ) -> Result<GTensor<ODIMS>, GTensorError>
+    where
+        Dim<ODIMS>: DimValid,
+        DimPair<ODIMS, 3>: DimLt,
+    {
+        self.try_new_unary("view", |ctx, ictx, tptr| {
+            let mut shp = ne.map(|v| v as usize);
+            if ODIMS > 1 {
+                shp.swap(0, 1);
+            }
+            if ne.iter().any(|v| *v < 1) || !self.view_in_bounds(tptr, shp, offset) {
+                Err(GTensorError::InvalidOperation)?;
+            }
+            let mr = GMemoryRequest::estimate_view_request_ictx(ctx, ictx, self.md.typ, shp)
+                .fit_or_grow(ictx)?;
             unsafe {
                 let elsize = gg::ggml_element_size(tptr);
                 Ok((
@@ -382,15 +491,59 @@ where
         })
     }
 
+    // Checks that a view with the GGML shape `shp` and the `offset` argument
+    // of [Self::view] stays within the data of `self`.
+    fn view_in_bounds<const ODIMS: usize>(
+        &self,
+        tptr: *mut gg::ggml_tensor,
+        shp: [usize; ODIMS],
+        offset: [usize; ODIMS],
+    ) -> bool {
+        let typ = self.md.typ;
+        let elsize = typ.element_size();
+        let mut ne = [1; 4];
+        ne[..ODIMS].copy_from_slice(&shp);
+        let check = || {
+            let nb1 = match ODIMS {
+                1 => typ.row_size(ne[0]),
+                _ => offset[1].checked_mul(elsize)?,
+            };
+            let offs = offset[0].checked_mul(elsize)?;
+            let extent = typ.nbytes(&ne, &[elsize, nb1, nb1, nb1])?;
+            let data_size = typ.row_size(ne[0]).checked_mul(ne[1])?;
+            // GGML checks the view against the tensor `self` is a view of, if any.
+            let (base_offs, base_nbytes) = unsafe {
+                let tr = &*tptr;
+                match tr.view_src.is_null() {
+                    true => (0, self.md.len_bytes),
+                    false => (tr.view_offs, gg::ggml_nbytes(tr.view_src)),
+                }
+            };
+            Some(
+                offs.checked_add(extent.max(data_size))? <= self.md.len_bytes
+                    && base_offs.checked_add(offs)?.checked_add(data_size)? <= base_nbytes,
+            )
+        };
+        check().unwrap_or(false)
+    }
+
     /// # !!!! FIXME !!!!
     /// # !!!! FIXME !!!!
     /// # !!!! FIXME !!!!
     pub fn diag_mask_inf(self, val: usize) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
-            // Creates a view plus a i32 tensor with one item.
-            let mr1 = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, []);
-            let mr2 = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::I32, [1]);
-            let mr = (mr1 + mr2).fit_or_die()?;
+        self.or_dead(self.try_diag_mask_inf(val))
+    }
+
+    /// Fallible version of [Self::diag_mask_inf].
+    pub fn try_diag_mask_inf(&self, val: usize) -> Result<Self, GTensorError> {
+        self.try_new_unary("diag_mask_inf", |ctx, ictx, tptr| {
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe { Ok((mr, gg::ggml_diag_mask_inf(ictx.gptr(), tptr, val as i32))) }
         })
     }
@@ -399,11 +552,25 @@ where
     /// # !!!! FIXME !!!!
     /// # !!!! FIXME !!!!
     pub fn rope(self, n_past: usize, n_dims: usize, mode: usize, n_ctx: usize) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
-            // Creates a view plus a i32 tensor with three items.
-            let mr1 = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, []);
-            let mr2 = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::I32, [3]);
-            let mr = (mr1 + mr2).fit_or_die()?;
+        self.or_dead(self.try_rope(n_past, n_dims, mode, n_ctx))
+    }
+
+    /// Fallible version of [Self::rope].
+    pub fn try_rope(
+        &self,
+        n_past: usize,
+        n_dims: usize,
+        mode: usize,
+        n_ctx: usize,
+    ) -> Result<Self, GTensorError> {
+        self.try_new_unary("rope", |ctx, ictx, tptr| {
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe {
                 Ok((
                     mr,
@@ -432,11 +599,27 @@ where
         freq_base: f32,
         freq_scale: f32,
     ) -> Self {
-        self.new_unary(|ctx, ictx, tptr| {
-            // Creates a view plus a i32 tensor with three items.
-            let mr1 = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, self.md.typ, []);
-            let mr2 = GMemoryRequest::estimate_tensor_request_ictx(ctx, ictx, GType::I32, [3]);
-            let mr = (mr1 + mr2).fit_or_die()?;
+        self.or_dead(self.try_rope_custom(n_past, n_dims, mode, n_ctx, freq_base, freq_scale))
+    }
+
+    /// Fallible version of [Self::rope_custom].
+    pub fn try_rope_custom(
+        &self,
+        n_past: usize,
+        n_dims: usize,
+        mode: usize,
+        n_ctx: usize,
+        freq_base: f32,
+        freq_scale: f32,
+    ) -> Result<Self, GTensorError> {
+        self.try_new_unary("rope_custom", |ctx, ictx, tptr| {
+            let mr = GMemoryRequest::estimate_tensor_request_ictx(
+                ctx,
+                ictx,
+                self.md.typ,
+                self.md.ggml_shape(),
+            )
+            .fit_or_grow(ictx)?;
             unsafe {
                 Ok((
                     mr,
@@ -458,7 +641,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::{context::*, util::GType};
+    use crate::{context::*, gtensor::GTensorError, util::GType};
     use anyhow::Result;
 
     macro_rules! test_uop_simple {
@@ -522,6 +705,105 @@ mod tests {
         [1.0, 2.0, 3.0, 4.0]; [4] => [10.0]
     ));
 
+    #[test]
+    pub fn test_reshape_and_view_bounds() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(64 * 1024).build()?;
+        let t = ctx.tensor(GType::F32, [2, 4])?;
+
+        assert_eq!(t.try_reshape([4, 2])?.shape(), [2, 4]);
+        assert!(matches!(
+            t.try_reshape([3, 2]),
+            Err(GTensorError::ShapeMismatch {
+                lhs: [4, 2, 1, 1],
+                rhs: [2, 3, 1, 1]
+            })
+        ));
+        assert!(matches!(
+            t.transpose().try_reshape([2, 4]),
+            Err(GTensorError::InvalidOperation)
+        ));
+
+        assert_eq!(t.try_view([4], [4])?.shape(), [4]);
+        assert!(matches!(
+            t.try_view([4], [5]),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            t.try_view([9], [0]),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert!(matches!(
+            t.try_view([0], [0]),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert_eq!(t.try_view([2, 2], [2, 4])?.shape(), [2, 2]);
+        assert!(matches!(
+            t.try_view([2, 4], [1, 4]),
+            Err(GTensorError::InvalidOperation)
+        ));
+        // Views of views are checked against the original tensor.
+        let v = t.try_view([4], [4])?;
+        assert!(matches!(
+            v.try_view([2], [3]),
+            Err(GTensorError::InvalidOperation)
+        ));
+        assert_eq!(v.try_view([2], [2])?.shape(), [2]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_try_uops_errors() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(16 * 1024).build()?;
+        let t = ctx.tensor(GType::F32, [2, 4])?;
+        let t1 = ctx.tensor(GType::F32, [4])?;
+        let ti = ctx.tensor(GType::I32, [4])?;
+
+        // The result of mean has as many dimensions as the input.
+        assert!(matches!(
+            t.try_mean::<1>(),
+            Err(GTensorError::DimensionMismatch {
+                got: 2,
+                expected: 1
+            })
+        ));
+        assert!(matches!(
+            ti.try_map_unary(crate::map_unop!(|el| el)),
+            Err(GTensorError::TypeMismatch)
+        ));
+
+        // Operations without constraints on the shape fail once the context is full.
+        while ctx.tensor(GType::F32, [1]).is_ok() {}
+        let results = [
+            t.try_sqr().err(),
+            t.try_sqrt().err(),
+            t.try_abs().err(),
+            t.try_sgn().err(),
+            t.try_neg().err(),
+            t.try_step().err(),
+            t.try_relu().err(),
+            t.try_gelu().err(),
+            t.try_silu().err(),
+            t.try_cont().err(),
+            t.try_soft_max().err(),
+            t.try_transpose().err(),
+            t.try_norm(1e-5).err(),
+            t.try_rms_norm(1e-5).err(),
+            t1.try_mean::<1>().err(),
+            t.try_sum::<1>().err(),
+            t.try_diag_mask_inf(0).err(),
+            t.try_rope(0, 4, 0, 0).err(),
+            t.try_rope_custom(0, 4, 0, 0, 10000.0, 1.0).err(),
+            t.try_map_unary(crate::map_unop!(|el| el)).err(),
+        ];
+        for (idx, result) in results.into_iter().enumerate() {
+            assert!(
+                matches!(result, Some(GTensorError::Failed(_))),
+                "operation {idx} did not fail"
+            );
+        }
+        Ok(())
+    }
+
     // #[test]
     // pub fn test_sqr() {
     //     let ctx = GgmlContextBuilder::new().mem_size(1024 * 1024).build();
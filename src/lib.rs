@@ -1,5 +1,8 @@
 pub mod context;
 pub mod dims;
+pub mod galloc;
+pub mod gguf;
+pub mod graph_file;
 pub mod gtensor;
 pub mod quantize;
 pub mod util;
@@ -7,4 +10,6 @@ pub mod validation;
 
 pub mod prelude;
 
+mod sync;
+
 pub use ggml_sys_bleedingedge as ggml_sys;
@@ -1,7 +1,7 @@
 /// Re-export of low level GGML binding.
 pub use ggml_sys_bleedingedge as ggml_sys;
 
-pub use crate::{context::*, dims::*, gtensor::*, map_binop, map_unop, quantize::*, util::*};
+pub use crate::{context::*, dims::*, galloc::*, gtensor::*, map_binop, map_unop, quantize::*, util::*};
 
 /// Alias for one dimensional tensors.
 pub type GTensor1 = GTensor<1>;
@@ -0,0 +1,89 @@
+// Primitives used to share context state between a context and its tensors.
+//
+// By default these are thread safe. With the `single_threaded` feature they're
+// replaced with cheaper versions based on `Rc` and `RefCell`, which makes
+// contexts and tensors `!Send` but avoids locking a mutex for every operation.
+
+#[cfg(not(feature = "single_threaded"))]
+mod imp {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    };
+
+    pub(crate) type Shared<T> = Arc<T>;
+
+    pub(crate) type LockGuard<'a, T> = MutexGuard<'a, T>;
+
+    pub(crate) struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) fn new(val: T) -> Self {
+            Self(Mutex::new(val))
+        }
+
+        // Fails if the mutex is poisoned.
+        pub(crate) fn lock(&self) -> Result<LockGuard<'_, T>, ()> {
+            self.0.lock().map_err(|_e| ())
+        }
+    }
+
+    pub(crate) struct Flag(AtomicBool);
+
+    impl Flag {
+        pub(crate) fn new(val: bool) -> Self {
+            Self(AtomicBool::new(val))
+        }
+
+        pub(crate) fn get(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        pub(crate) fn set(&self, val: bool) {
+            self.0.store(val, Ordering::SeqCst)
+        }
+    }
+}
+
+#[cfg(feature = "single_threaded")]
+mod imp {
+    use std::{
+        cell::{Cell, RefCell, RefMut},
+        rc::Rc,
+    };
+
+    pub(crate) type Shared<T> = Rc<T>;
+
+    pub(crate) type LockGuard<'a, T> = RefMut<'a, T>;
+
+    pub(crate) struct Lock<T>(RefCell<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) fn new(val: T) -> Self {
+            Self(RefCell::new(val))
+        }
+
+        // Fails if the value is already borrowed.
+        pub(crate) fn lock(&self) -> Result<LockGuard<'_, T>, ()> {
+            self.0.try_borrow_mut().map_err(|_e| ())
+        }
+    }
+
+    pub(crate) struct Flag(Cell<bool>);
+
+    impl Flag {
+        pub(crate) fn new(val: bool) -> Self {
+            Self(Cell::new(val))
+        }
+
+        pub(crate) fn get(&self) -> bool {
+            self.0.get()
+        }
+
+        pub(crate) fn set(&self, val: bool) {
+            self.0.set(val)
+        }
+    }
+}
+
+pub(crate) use imp::*;
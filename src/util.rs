@@ -11,6 +11,8 @@ pub enum GError {
     Context(crate::context::GContextError),
     #[error("Tensor error: {0}")]
     Tensor(crate::gtensor::GTensorError),
+    #[error("GGUF error: {0}")]
+    Gguf(crate::gguf::GgufError),
 }
 
 #[repr(u32)]
@@ -87,6 +89,256 @@ impl GType {
         self.to_u32()
             .map_or(0, |val| unsafe { gg::ggml_blck_size(val) } as usize)
     }
+
+    /// Returns the size in bytes of a row of `ne0` elements of this type.
+    ///
+    /// **Note**: For quantized types, `ne0` should be a multiple of the
+    /// block size.
+    pub fn row_size(&self, ne0: usize) -> usize {
+        self.element_size() * (ne0 / self.block_size().max(1))
+    }
+
+    /// Returns the byte size GGML computes for a tensor of this type with
+    /// the specified shape and strides, or `None` on overflow. See `ggml_nbytes`.
+    pub(crate) fn nbytes(
+        &self,
+        ne: &[usize; gg::GGML_MAX_DIMS as usize],
+        nb: &[usize; gg::GGML_MAX_DIMS as usize],
+    ) -> Option<usize> {
+        let blck = self.block_size();
+        let first = match blck {
+            1 => self
+                .element_size()
+                .checked_add(ne[0].checked_sub(1)?.checked_mul(nb[0])?)?,
+            _ => ne[0].checked_mul(nb[0])? / blck,
+        };
+        ne[1..]
+            .iter()
+            .zip(&nb[1..])
+            .try_fold(first, |acc, (ne, nb)| {
+                acc.checked_add(ne.checked_sub(1)?.checked_mul(*nb)?)
+            })
+    }
+}
+
+#[repr(u32)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    num_derive::FromPrimitive,
+    num_derive::ToPrimitive,
+)]
+/// GGML tensor operation. Tensors that aren't the result of an operation
+/// have [GOp::None].
+///
+/// For [GOp::Unary] the specific operation is a [GUnaryOp].
+pub enum GOp {
+    None = gg::ggml_op_GGML_OP_NONE,
+    Dup = gg::ggml_op_GGML_OP_DUP,
+    Add = gg::ggml_op_GGML_OP_ADD,
+    Add1 = gg::ggml_op_GGML_OP_ADD1,
+    Acc = gg::ggml_op_GGML_OP_ACC,
+    Sub = gg::ggml_op_GGML_OP_SUB,
+    Mul = gg::ggml_op_GGML_OP_MUL,
+    Div = gg::ggml_op_GGML_OP_DIV,
+    Sqr = gg::ggml_op_GGML_OP_SQR,
+    Sqrt = gg::ggml_op_GGML_OP_SQRT,
+    Log = gg::ggml_op_GGML_OP_LOG,
+    Sum = gg::ggml_op_GGML_OP_SUM,
+    SumRows = gg::ggml_op_GGML_OP_SUM_ROWS,
+    Mean = gg::ggml_op_GGML_OP_MEAN,
+    Argmax = gg::ggml_op_GGML_OP_ARGMAX,
+    Repeat = gg::ggml_op_GGML_OP_REPEAT,
+    RepeatBack = gg::ggml_op_GGML_OP_REPEAT_BACK,
+    Concat = gg::ggml_op_GGML_OP_CONCAT,
+    SiluBack = gg::ggml_op_GGML_OP_SILU_BACK,
+    Norm = gg::ggml_op_GGML_OP_NORM,
+    RmsNorm = gg::ggml_op_GGML_OP_RMS_NORM,
+    RmsNormBack = gg::ggml_op_GGML_OP_RMS_NORM_BACK,
+    GroupNorm = gg::ggml_op_GGML_OP_GROUP_NORM,
+    MulMat = gg::ggml_op_GGML_OP_MUL_MAT,
+    OutProd = gg::ggml_op_GGML_OP_OUT_PROD,
+    Scale = gg::ggml_op_GGML_OP_SCALE,
+    Set = gg::ggml_op_GGML_OP_SET,
+    Cpy = gg::ggml_op_GGML_OP_CPY,
+    Cont = gg::ggml_op_GGML_OP_CONT,
+    Reshape = gg::ggml_op_GGML_OP_RESHAPE,
+    View = gg::ggml_op_GGML_OP_VIEW,
+    Permute = gg::ggml_op_GGML_OP_PERMUTE,
+    Transpose = gg::ggml_op_GGML_OP_TRANSPOSE,
+    GetRows = gg::ggml_op_GGML_OP_GET_ROWS,
+    GetRowsBack = gg::ggml_op_GGML_OP_GET_ROWS_BACK,
+    Diag = gg::ggml_op_GGML_OP_DIAG,
+    DiagMaskInf = gg::ggml_op_GGML_OP_DIAG_MASK_INF,
+    DiagMaskZero = gg::ggml_op_GGML_OP_DIAG_MASK_ZERO,
+    SoftMax = gg::ggml_op_GGML_OP_SOFT_MAX,
+    SoftMaxBack = gg::ggml_op_GGML_OP_SOFT_MAX_BACK,
+    Rope = gg::ggml_op_GGML_OP_ROPE,
+    RopeBack = gg::ggml_op_GGML_OP_ROPE_BACK,
+    Alibi = gg::ggml_op_GGML_OP_ALIBI,
+    Clamp = gg::ggml_op_GGML_OP_CLAMP,
+    Conv1D = gg::ggml_op_GGML_OP_CONV_1D,
+    Conv2D = gg::ggml_op_GGML_OP_CONV_2D,
+    ConvTranspose2D = gg::ggml_op_GGML_OP_CONV_TRANSPOSE_2D,
+    Pool1D = gg::ggml_op_GGML_OP_POOL_1D,
+    Pool2D = gg::ggml_op_GGML_OP_POOL_2D,
+    Upscale = gg::ggml_op_GGML_OP_UPSCALE,
+    FlashAttn = gg::ggml_op_GGML_OP_FLASH_ATTN,
+    FlashFf = gg::ggml_op_GGML_OP_FLASH_FF,
+    FlashAttnBack = gg::ggml_op_GGML_OP_FLASH_ATTN_BACK,
+    WinPart = gg::ggml_op_GGML_OP_WIN_PART,
+    WinUnpart = gg::ggml_op_GGML_OP_WIN_UNPART,
+    GetRelPos = gg::ggml_op_GGML_OP_GET_REL_POS,
+    AddRelPos = gg::ggml_op_GGML_OP_ADD_REL_POS,
+    Unary = gg::ggml_op_GGML_OP_UNARY,
+    MapUnary = gg::ggml_op_GGML_OP_MAP_UNARY,
+    MapBinary = gg::ggml_op_GGML_OP_MAP_BINARY,
+    MapCustom1F32 = gg::ggml_op_GGML_OP_MAP_CUSTOM1_F32,
+    MapCustom2F32 = gg::ggml_op_GGML_OP_MAP_CUSTOM2_F32,
+    MapCustom3F32 = gg::ggml_op_GGML_OP_MAP_CUSTOM3_F32,
+    MapCustom1 = gg::ggml_op_GGML_OP_MAP_CUSTOM1,
+    MapCustom2 = gg::ggml_op_GGML_OP_MAP_CUSTOM2,
+    MapCustom3 = gg::ggml_op_GGML_OP_MAP_CUSTOM3,
+    CrossEntropyLoss = gg::ggml_op_GGML_OP_CROSS_ENTROPY_LOSS,
+    CrossEntropyLossBack = gg::ggml_op_GGML_OP_CROSS_ENTROPY_LOSS_BACK,
+}
+
+impl GOp {
+    /// Returns GGML's name for the operation, for example `MUL_MAT`.
+    pub fn name(&self) -> &'static str {
+        unsafe { Self::static_str(gg::ggml_op_name(*self as gg::ggml_op)) }
+    }
+
+    /// Returns GGML's symbol for the operation, for example `X*Y` for [GOp::MulMat].
+    pub fn symbol(&self) -> &'static str {
+        unsafe { Self::static_str(gg::ggml_op_symbol(*self as gg::ggml_op)) }
+    }
+
+    // # Safety
+    // The pointer must be to a static NUL terminated string.
+    unsafe fn static_str(ptr: *const std::os::raw::c_char) -> &'static str {
+        std::ffi::CStr::from_ptr(ptr).to_str().unwrap_or("?")
+    }
+
+    /// Is this an operation that creates a view of its source's data
+    /// without copying it?
+    pub fn is_view(&self) -> bool {
+        matches!(
+            self,
+            Self::Reshape | Self::View | Self::Permute | Self::Transpose
+        )
+    }
+
+    /// Is this an operation where each element of the result only depends
+    /// on the elements at the same position in the sources?
+    pub fn is_elementwise(&self) -> bool {
+        matches!(
+            self,
+            Self::Dup
+                | Self::Add
+                | Self::Add1
+                | Self::Sub
+                | Self::Mul
+                | Self::Div
+                | Self::Sqr
+                | Self::Sqrt
+                | Self::Log
+                | Self::SiluBack
+                | Self::Scale
+                | Self::Cpy
+                | Self::Cont
+                | Self::Clamp
+                | Self::Unary
+                | Self::MapUnary
+                | Self::MapBinary
+        )
+    }
+
+    /// Is this an operation that reduces one or more dimensions of its source?
+    pub fn is_reduction(&self) -> bool {
+        matches!(
+            self,
+            Self::Sum | Self::SumRows | Self::Mean | Self::Argmax | Self::CrossEntropyLoss
+        )
+    }
+
+    /// Is this an operation that calls a user supplied function? See [crate::map_unop].
+    pub fn is_custom(&self) -> bool {
+        matches!(
+            self,
+            Self::MapUnary
+                | Self::MapBinary
+                | Self::MapCustom1F32
+                | Self::MapCustom2F32
+                | Self::MapCustom3F32
+                | Self::MapCustom1
+                | Self::MapCustom2
+                | Self::MapCustom3
+        )
+    }
+}
+
+impl std::fmt::Display for GOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[repr(u32)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    num_derive::FromPrimitive,
+    num_derive::ToPrimitive,
+)]
+/// The specific operation of a [GOp::Unary] tensor.
+pub enum GUnaryOp {
+    Abs = gg::ggml_unary_op_GGML_UNARY_OP_ABS,
+    Sgn = gg::ggml_unary_op_GGML_UNARY_OP_SGN,
+    Neg = gg::ggml_unary_op_GGML_UNARY_OP_NEG,
+    Step = gg::ggml_unary_op_GGML_UNARY_OP_STEP,
+    Tanh = gg::ggml_unary_op_GGML_UNARY_OP_TANH,
+    Elu = gg::ggml_unary_op_GGML_UNARY_OP_ELU,
+    Relu = gg::ggml_unary_op_GGML_UNARY_OP_RELU,
+    Gelu = gg::ggml_unary_op_GGML_UNARY_OP_GELU,
+    GeluQuick = gg::ggml_unary_op_GGML_UNARY_OP_GELU_QUICK,
+    Silu = gg::ggml_unary_op_GGML_UNARY_OP_SILU,
+}
+
+impl GUnaryOp {
+    /// Returns the name of the operation, for example `RELU`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Abs => "ABS",
+            Self::Sgn => "SGN",
+            Self::Neg => "NEG",
+            Self::Step => "STEP",
+            Self::Tanh => "TANH",
+            Self::Elu => "ELU",
+            Self::Relu => "RELU",
+            Self::Gelu => "GELU",
+            Self::GeluQuick => "GELU_QUICK",
+            Self::Silu => "SILU",
+        }
+    }
+}
+
+impl std::fmt::Display for GUnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 #[repr(u32)]
@@ -15,6 +15,17 @@ pub enum GMemoryRequestType {
         typ: GType,
         shape: [usize; gg::GGML_MAX_DIMS as usize],
     },
+    /// A tensor whose data lives in a mapped region rather than the
+    /// context or a scratch buffer.
+    MappedTensor {
+        typ: GType,
+        shape: [usize; gg::GGML_MAX_DIMS as usize],
+    },
+    /// A view of another tensor's data.
+    View {
+        typ: GType,
+        shape: [usize; gg::GGML_MAX_DIMS as usize],
+    },
 }
 
 impl GMemoryRequestType {
@@ -39,6 +50,9 @@ pub struct GMemoryRequest {
     pub available_ctx: usize,
     pub available_scratch: usize,
     pub current_scratch_buffer: Option<usize>,
+    /// Data bytes that will be placed by the context's graph allocator rather
+    /// than the context memory or a scratch buffer.
+    pub required_graph: usize,
     pub fits: bool,
 }
 
@@ -53,6 +67,7 @@ impl std::ops::Add for GMemoryRequest {
         assert_eq!(self.available_scratch, rhs.available_scratch);
         self.required_ctx += rhs.required_ctx;
         self.required_scratch += rhs.required_scratch;
+        self.required_graph += rhs.required_graph;
         self.total_required += rhs.total_required;
         self.fits = self.required_ctx <= self.available_ctx
             && self.required_scratch <= self.available_scratch;
@@ -61,51 +76,80 @@ impl std::ops::Add for GMemoryRequest {
 }
 
 impl GMemoryRequest {
-    pub(crate) fn estimate_tensor_request_ictx(
-        ctx: &GContext,
-        ictx: &IContext,
-        typ: GType,
-        shape: impl AsRef<[usize]>,
-    ) -> Self {
-        let shape = shape.as_ref();
-        let padded_shape = shape
-            .iter()
+    /// Returns the bytes of data GGML uses for a tensor with the specified type and
+    /// shape, in GGML order (`ne[0]` first). Rows of quantized types are stored as
+    /// whole blocks.
+    pub(crate) fn tensor_data_bytes(typ: GType, ne: &[usize]) -> usize {
+        let (ne0, rows) = ne.split_first().unwrap_or((&1, &[]));
+        typ.row_size(*ne0) * rows.iter().product::<usize>()
+    }
+
+    /// Returns the context memory used by a GGML object with `size` bytes of
+    /// tensor structure and data: GGML pads the object to `GGML_MEM_ALIGN` and
+    /// precedes it with an object header.
+    pub(crate) fn object_bytes(size: usize) -> usize {
+        gg::GGML_OBJECT_SIZE + size.next_multiple_of(gg::GGML_MEM_ALIGN as usize)
+    }
+
+    fn padded_shape(ne: &[usize]) -> [usize; gg::GGML_MAX_DIMS as usize] {
+        ne.iter()
             .copied()
             .chain(std::iter::repeat(1))
             .take(gg::GGML_MAX_DIMS as usize)
             .collect::<Vec<_>>()
             .try_into()
-            .expect("Impossible: Could not convert to array");
+            .expect("Impossible: Could not convert to array")
+    }
+
+    /// Estimate the memory a new tensor with the specified type and shape (in GGML
+    /// order) requires. This matches what GGML will actually use.
+    ///
+    /// **Note**: In `no_alloc` contexts the context memory required includes the data
+    /// GGML would allocate, so the result can be used to size a real context.
+    pub(crate) fn estimate_tensor_request_ictx(
+        ctx: &GContext,
+        ictx: &IContext,
+        typ: GType,
+        ne: impl AsRef<[usize]>,
+    ) -> Self {
+        let ne = ne.as_ref();
         let reqtype = GMemoryRequestType::Tensor {
             typ,
-            shape: padded_shape,
+            shape: Self::padded_shape(ne),
         };
-        let elsize = typ.element_sizef() as f64;
-        let elcount = shape.iter().map(|i| *i as f64).product::<f64>();
-        let required_ctx = gg::GGML_OBJECT_SIZE + std::mem::size_of::<gg::ggml_tensor>();
-        // 16 is the worst case for alignment but it probably doesn't hurt to be a little
-        // bit conservative here.
-        let required = (elsize * elcount).round() as usize + typ.block_size() + 16;
+        let data = Self::tensor_data_bytes(typ, ne);
+        let object_ctx = Self::object_bytes(gg::GGML_TENSOR_SIZE);
         let used_ctx = ictx.context_used;
-        let available_ctx = ictx.context_memory - used_ctx;
+        let available_ctx = ictx.available_ctx();
 
-        if let Some(bufid) = ictx.current_scratch_buffer {
-            let sbuf = &ictx.scratch_buffers[bufid];
-            let available_scratch = sbuf.buf.len() - sbuf.used;
+        if ictx.graph_allocator.is_some() {
+            // Only the GGML object lives in the context, the allocator places the data.
             Self {
                 reqtype,
-                required_ctx,
-                required_scratch: required,
-                total_required: required + required_ctx,
+                required_ctx: object_ctx,
+                required_graph: data,
+                total_required: data + object_ctx,
+                available_ctx,
+                fits: object_ctx <= available_ctx,
+                ..Default::default()
+            }
+        } else if let Some(bufid) = ictx.current_scratch_buffer {
+            let available_scratch = ictx.available_scratch(bufid);
+            Self {
+                reqtype,
+                required_ctx: object_ctx,
+                required_scratch: data,
+                total_required: data + object_ctx,
                 available_scratch,
                 available_ctx,
                 current_scratch_buffer: ictx.current_scratch_buffer,
+                required_graph: 0,
                 fits: ctx.no_alloc
-                    || (required_ctx + used_ctx <= ictx.context_memory
-                        && sbuf.used + required <= sbuf.buf.len()),
+                    || (object_ctx + used_ctx <= ictx.context_memory && data <= available_scratch),
             }
         } else {
-            let total_required = required_ctx + required;
+            // The data is part of the GGML object.
+            let total_required = Self::object_bytes(gg::GGML_TENSOR_SIZE + data);
             Self {
                 reqtype,
                 required_ctx: total_required,
@@ -117,6 +161,59 @@ impl GMemoryRequest {
         }
     }
 
+    /// Estimate the memory a view with the specified type and shape (in GGML order)
+    /// requires. Views only use context memory for the GGML object.
+    pub(crate) fn estimate_view_request_ictx(
+        ctx: &GContext,
+        ictx: &IContext,
+        typ: GType,
+        ne: impl AsRef<[usize]>,
+    ) -> Self {
+        let reqtype = GMemoryRequestType::View {
+            typ,
+            shape: Self::padded_shape(ne.as_ref()),
+        };
+        Self {
+            reqtype,
+            ..Self::estimate_mapped_tensor_request_ictx(ctx, ictx, typ, ne)
+        }
+    }
+
+    pub(crate) fn estimate_mapped_tensor_request_ictx(
+        ctx: &GContext,
+        ictx: &IContext,
+        typ: GType,
+        ne: impl AsRef<[usize]>,
+    ) -> Self {
+        // Only the GGML object is allocated, the data is never placed in the context
+        // or a scratch buffer.
+        let required_ctx = Self::object_bytes(gg::GGML_TENSOR_SIZE);
+        let available_ctx = ictx.available_ctx();
+        Self {
+            reqtype: GMemoryRequestType::MappedTensor {
+                typ,
+                shape: Self::padded_shape(ne.as_ref()),
+            },
+            required_ctx,
+            total_required: required_ctx,
+            available_ctx,
+            fits: ctx.no_alloc || required_ctx <= available_ctx,
+            ..Default::default()
+        }
+    }
+
+    /// Like [Self::fit_or_die], but auto-growing contexts get a new arena
+    /// when the request doesn't fit in the context memory.
+    pub(crate) fn fit_or_grow(mut self, ictx: &mut IContext) -> Result<Self> {
+        let scratch_fits = self.required_scratch <= self.available_scratch;
+        if ictx.auto_grow && scratch_fits && self.required_ctx > self.available_ctx {
+            unsafe { ictx.grow(self.required_ctx)? };
+            self.available_ctx = ictx.available_ctx();
+            self.fits = self.required_ctx <= self.available_ctx;
+        }
+        self.fit_or_die()
+    }
+
     pub fn fit_or_die(self) -> Result<Self> {
         if self.required_ctx <= self.available_ctx
             && self.required_scratch <= self.available_scratch
@@ -127,3 +224,130 @@ impl GMemoryRequest {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::c_int;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{context::*, gtensor::GMulMat};
+
+    unsafe extern "C" fn unary_fun(_n: c_int, _dst: *mut f32, _src: *const f32) {}
+
+    unsafe extern "C" fn binary_fun(_n: c_int, _dst: *mut f32, _a: *const f32, _b: *const f32) {}
+
+    // Runs `fun` and checks the context memory requested for the tensors it
+    // created matches the memory GGML actually used.
+    fn check<T>(ctx: &GContext, fun: impl FnOnce() -> Result<T>) -> Result<T> {
+        let (used, logged) = (ctx.used_mem()?, ctx.allocation_log()?.len());
+        let result = fun()?;
+        let log = ctx.allocation_log()?;
+        assert!(log.len() > logged, "No tensors were created");
+        let estimated = log[logged..].iter().map(|rec| rec.ctx_bytes).sum::<usize>();
+        assert_eq!(ctx.used_mem()? - used, estimated);
+        Ok(result)
+    }
+
+    // Returns the offset GGML will place the next tensor at in the current scratch buffer.
+    fn ggml_scratch_offs(ctx: &GContext) -> Result<usize> {
+        ctx.with_icontext_infallible(|mut ictx| unsafe {
+            let offs = gg::ggml_set_scratch(
+                ictx.gptr(),
+                gg::ggml_scratch {
+                    offs: 0,
+                    size: 0,
+                    data: std::ptr::null_mut(),
+                },
+            );
+            ictx.sync_scratch();
+            offs
+        })
+    }
+
+    fn run_ops(ctx: &GContext) -> Result<()> {
+        let a = check(ctx, || ctx.tensor(GType::F32, [3, 4]))?;
+        let b = check(ctx, || ctx.tensor(GType::F32, [3, 4]))?;
+        let a3 = check(ctx, || ctx.tensor(GType::F32, [4, 3, 2]))?;
+        let rows = check(ctx, || ctx.tensor(GType::I32, [2]))?;
+        let scalar = check(ctx, || ctx.tensor(GType::F32, [1]))?;
+        let small = check(ctx, || ctx.tensor(GType::F32, [2]))?;
+        let flat = check(ctx, || ctx.tensor(GType::F32, [12]))?;
+        let mm = check(ctx, || ctx.tensor(GType::F32, [5, 4]))?;
+        let conv = check(ctx, || ctx.tensor(GType::F32, [3, 10]))?;
+        let mut dst = check(ctx, || ctx.tensor(GType::F32, [3, 4]))?;
+        check(ctx, || ctx.tensor(GType::F16, [3, 5]))?;
+        check(ctx, || ctx.tensor(GType::Q4_0, [3, 64]))?;
+        check(ctx, || ctx.tensor(GType::Q8_0, [2, 96]))?;
+
+        check(ctx, || Ok(a.try_sqr()?))?;
+        check(ctx, || Ok(a.try_sqrt()?))?;
+        check(ctx, || Ok(a.try_abs()?))?;
+        check(ctx, || Ok(a.try_sgn()?))?;
+        check(ctx, || Ok(a.try_neg()?))?;
+        check(ctx, || Ok(a.try_step()?))?;
+        check(ctx, || Ok(a.try_relu()?))?;
+        check(ctx, || Ok(a.try_gelu()?))?;
+        check(ctx, || Ok(a.try_silu()?))?;
+        check(ctx, || Ok(a.try_soft_max()?))?;
+        check(ctx, || Ok(a.try_norm(1e-5)?))?;
+        check(ctx, || Ok(a.try_rms_norm(1e-5)?))?;
+        check(ctx, || Ok(small.try_mean::<1>()?))?;
+        check(ctx, || Ok(a.try_sum::<1>()?))?;
+        let t = check(ctx, || Ok(a.try_transpose()?))?;
+        check(ctx, || Ok(t.try_cont()?))?;
+        check(ctx, || Ok(a.try_reshape([2, 6])?))?;
+        check(ctx, || Ok(a.try_view([2, 2], [1, 1])?))?;
+        check(ctx, || Ok(a.try_diag_mask_inf(0)?))?;
+        check(ctx, || Ok(a3.try_rope(0, 2, 0, 0)?))?;
+        check(ctx, || Ok(a3.try_rope_custom(0, 2, 0, 0, 10000.0, 1.0)?))?;
+        check(ctx, || Ok(a.try_map_unary(unary_fun)?))?;
+
+        check(ctx, || Ok(a.try_add(&b)?))?;
+        check(ctx, || Ok(a.try_sub(&b)?))?;
+        check(ctx, || Ok(a.try_mul(&b)?))?;
+        check(ctx, || Ok(a.try_div(&b)?))?;
+        check(ctx, || Ok(a.try_scale(&scalar)?))?;
+        check(ctx, || Ok(small.try_repeat(&b)?))?;
+        check(ctx, || Ok(a.try_conv_1d::<2, 2, _>(&conv, 1, 0, 1)?))?;
+        check(ctx, || Ok(a.try_permute([1, 0, 2, 3])?))?;
+        check(ctx, || Ok(b.try_reshape_with(&flat)?))?;
+        check(ctx, || Ok(a.try_get_rows::<1, 2, _>(&rows)?))?;
+        check(ctx, || Ok(a.try_mul_mat(&mm)?))?;
+        check(ctx, || Ok(a.try_map_binary(&b, binary_fun)?))?;
+        check(ctx, || Ok(dst.try_copy_from(&a)?))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimates_match_ggml() -> Result<()> {
+        let ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        run_ops(&ctx)?;
+
+        let region = ctx.register_mapped_region(vec![0u8; 1024])?;
+        check(&ctx, || {
+            ctx.mapped_tensor::<2>(GType::F32, [4, 8], region, 0)
+        })?;
+        let other = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let t = other.tensor(GType::F32, [4, 8])?;
+        check(&ctx, || ctx.import_view(&t))?;
+
+        let report = ctx.memory_report(0)?;
+        assert_eq!(report.context_used, report.ggml_used);
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimates_match_ggml_scratch() -> Result<()> {
+        let mut ctx = GContextBuilder::new().mem_size(1024 * 1024).build()?;
+        let bufid = ctx.register_scratch_buffer(ScratchBuffer::new(1024 * 1024))?;
+        ctx.set_scratch_buffer(Some(bufid))?;
+        run_ops(&ctx)?;
+
+        let report = ctx.memory_report(0)?;
+        assert_eq!(report.context_used, report.ggml_used);
+        assert_eq!(report.scratch[0].used, ggml_scratch_offs(&ctx)?);
+        Ok(())
+    }
+}